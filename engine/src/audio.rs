@@ -1,4 +1,18 @@
-use rodio::{OutputStream, Source};
+use std::{cell::{Cell, RefCell}, ops::Range, sync::{Arc, Weak}};
+
+use cgmath::{Point3, Vector3};
+use rodio::{mixer::Mixer, ChannelCount, OutputStream, SampleRate, Source};
+
+use crate::{audio::{bus::{Bus, BusOutput, Counted}, effects::Effects, music::{Playlist, Track}, offline::OfflineOutput, spatial::{Attenuation, Emitter, EmitterShared, Listener, Spatialized}, stream::{ReadSeek, Stream}, synth::{Synth, XorShift}, voice::{PlayParams, Voice, VoiceSource}}, error::{EngineError, EngineResult}};
+
+pub mod bus;
+pub mod effects;
+pub mod music;
+mod offline;
+pub mod spatial;
+pub mod stream;
+pub mod synth;
+pub mod voice;
 
 enum AudioBackend {
     Open(OutputStream),
@@ -7,6 +21,9 @@ enum AudioBackend {
     /// State where the AudioBackend is currently waiting for a gesture from
     /// the user before it will start playing. Mainly relevant on web.
     WaitForGesture,
+
+    /// Mixes into memory, a tick at a time. See audio/offline.rs.
+    Offline(OfflineOutput),
 }
 
 pub struct Audio {
@@ -15,17 +32,51 @@ pub struct Audio {
     // Used to keep music around until we have a proper backend.
     stored_music: Option<Box<dyn Source + Send + 'static>>,
 
-    current_music_sink: Option<rodio::Sink>,
+    current_music: Option<Voice>,
+    playlist: Option<Playlist>,
+    /// How long one track fades into the next, in seconds.
+    crossfade: f32,
+
+    /// The master bus is always first. Every other bus mixes into it.
+    buses: Vec<Bus>,
+
+    /// Follows Engine::main_camera.
+    listener: Listener,
+    pub attenuation: Attenuation,
+    /// Every emitter that has played a sound, as long as the emitter or one of
+    /// its sounds is around.
+    emitters: RefCell<Vec<Weak<EmitterShared>>>,
+
+    /// For pitch variation and noise seeds.
+    rng: RefCell<XorShift>,
 }
 
-/// Represents a single Sound asset that can be played. Used for sounds where
-/// the sample data is loaded entirely ahead-of-time; primarily useful for sound
-/// effects.
+/// Represents a single Sound asset that can be played. Either the sample data
+/// is loaded entirely ahead-of-time, which is primarily useful for sound
+/// effects, or it is streamed from its file while it plays, for long sounds.
 pub struct Sound {
-    buffer: rodio::buffer::SamplesBuffer,
+    data: SoundData,
+
+    /// How many Voices of this Sound may play at once.
+    max_voices: Option<usize>,
+    voices: RefCell<Vec<Voice>>,
+
+    /// Every time the Sound plays, its speed is multiplied by a random number
+    /// in this range.
+    pitch_range: Option<Range<f32>>,
+    plays: Cell<usize>,
+}
+
+enum SoundData {
+    Buffered(rodio::buffer::SamplesBuffer),
+    Streamed(Stream),
 }
 
 impl Sound {
+    fn new(data: SoundData) -> Sound {
+        Sound { data, max_voices: None, voices: RefCell::new(Vec::new()), pitch_range: None, plays: Cell::new(0) }
+    }
+
     pub fn from_data(data: &'static [u8]) -> Sound {
         let cursor = std::io::Cursor::new(data);
         let decoder = rodio::Decoder::try_from(cursor).unwrap();
@@ -35,93 +86,543 @@ impl Sound {
             decoder.collect::<Vec<f32>>()
         );
 
-        Sound { buffer }
+        Sound::new(SoundData::Buffered(buffer))
+    }
+
+    /// Streams the sound from a reader that is opened every time it plays,
+    /// e.g. a file in an asset pack. Only a small part of it is decoded at a
+    /// time.
+    pub fn streaming<R: ReadSeek + 'static>(open: impl Fn() -> std::io::Result<R> + Send + Sync + 'static) -> EngineResult<Sound> {
+        Ok(Sound::new(SoundData::Streamed(Stream::new(open)?)))
+    }
+
+    /// Streams the sound from the bytes of an audio file, e.g. embedded with
+    /// include_bytes!(). The bytes stay compressed in memory.
+    pub fn streaming_from_data(data: impl AsRef<[u8]> + Clone + Send + Sync + 'static) -> EngineResult<Sound> {
+        Self::streaming(move || Ok(std::io::Cursor::new(data.clone())))
+    }
+
+    /// Streams the sound from a file on disk.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> EngineResult<Sound> {
+        let path = path.as_ref().to_path_buf();
+        Self::streaming(move || std::fs::File::open(&path))
+    }
+
+    /// Limits how many times the Sound can play at once. Playing it once more
+    /// fades out the oldest Voice.
+    pub fn with_max_voices(mut self, max_voices: usize) -> Sound {
+        self.max_voices = Some(max_voices.max(1));
+        self
+    }
+
+    /// Varies the pitch every time the Sound plays, so that sounds that play
+    /// often don't get repetitive, e.g. with_pitch_range(0.95..1.05).
+    pub fn with_pitch_range(mut self, range: Range<f32>) -> Sound {
+        self.pitch_range = Some(range);
+        self
+    }
+
+    /// How many times the Sound has started playing. Sounds don't play
+    /// without a backend, so this is mostly useful with Audio::offline().
+    pub fn play_count(&self) -> usize {
+        self.plays.get()
+    }
+
+    /// Makes room for one more Voice, if there is a limit.
+    fn steal_voice(&self) {
+        let Some(max_voices) = self.max_voices else { return; };
+
+        let mut voices = self.voices.borrow_mut();
+        voices.retain(|voice| !voice.is_finished());
+        while voices.len() >= max_voices {
+            // Quick enough to not be heard as a fade, but without a click.
+            voices.remove(0).fade_out(0.02);
+        }
+    }
+
+    fn add_voice(&self, voice: &Voice) {
+        if self.max_voices.is_some() {
+            self.voices.borrow_mut().push(voice.clone());
+        }
     }
 }
 
 impl Audio {
+    pub const MASTER: &'static str = "master";
+    pub const MUSIC: &'static str = "music";
+    pub const SFX: &'static str = "sfx";
+    pub const UI: &'static str = "ui";
+
     pub fn initial() -> Self {
-        if cfg!(target_arch = "wasm32") {
-            return Audio { backend: AudioBackend::WaitForGesture, stored_music: None, current_music_sink: None };
+        let mut audio = Self::with_backend(AudioBackend::WaitForGesture);
+        if !cfg!(target_arch = "wasm32") {
+            audio.open();
+        }
+        audio
+    }
+
+    /// Creates an Audio that renders the mix into memory in stereo at the
+    /// given sample rate, rather than playing it. Every tick renders exactly
+    /// one tick's worth of samples, so the result is the same on every run.
+    /// Used by the headless engine.
+    pub fn offline(sample_rate: SampleRate) -> Self {
+        let mut audio = Self::with_backend(AudioBackend::Offline(OfflineOutput::new(2, sample_rate)));
+        for idx in 0..audio.buses.len() {
+            audio.connect_bus(idx);
+        }
+        audio
+    }
+
+    fn with_backend(backend: AudioBackend) -> Self {
+        Audio {
+            backend,
+            stored_music: None,
+            current_music: None,
+            playlist: None,
+            crossfade: 2.0,
+            buses: [Self::MASTER, Self::MUSIC, Self::SFX, Self::UI].into_iter().map(Bus::new).collect(),
+
+            listener: Listener {
+                position: Point3::new(0.0, 0.0, 0.0),
+                forward: -Vector3::unit_z(),
+                up: Vector3::unit_y(),
+            },
+            attenuation: Attenuation::default(),
+            emitters: RefCell::new(Vec::new()),
+
+            rng: RefCell::new(XorShift::new(0x5eed)),
         }
-        return Self::new(None)
     }
 
     pub fn resume_on_gesture(&mut self) {
         if matches!(self.backend, AudioBackend::WaitForGesture) {
-            *self = Self::new(self.stored_music.take());
+            self.open();
             if let Some(current_music) = self.stored_music.take() {
-                let AudioBackend::Open(handle) = &self.backend else { return };
-
                 log::info!("audio: resuming music on gesture");
+                self.start_music(current_music);
+            }
+        }
+    }
 
-                let sink = rodio::Sink::connect_new(handle.mixer());
-                sink.append(current_music);
-                sink.play();
-                
-                self.current_music_sink = Some(sink);
-                //handle.mixer().add(current_music);
+    fn open(&mut self) {
+        match rodio::OutputStreamBuilder::open_default_stream() {
+            Ok(stream_handle) => {
+                log::info!("audio: using backend: {:?}", stream_handle.config());
+                self.backend = AudioBackend::Open(stream_handle);
+                for idx in 0..self.buses.len() {
+                    self.connect_bus(idx);
+                }
+            }
+            Err(_) => {
+                log::info!("audio: no backend available");
+                self.backend = AudioBackend::None;
             }
         }
     }
 
-    fn new(stored_music: Option<Box<dyn Source + Send + 'static>>) -> Self {
-        if let Ok(stream_handle) = rodio::OutputStreamBuilder::open_default_stream() {
-            log::info!("audio: using backend: {:?}", stream_handle.config());
+    /// The mixer that the master bus plays into, and its format, unless
+    /// there is nothing to play on.
+    fn output(&self) -> Option<(&Mixer, ChannelCount, SampleRate)> {
+        match &self.backend {
+            AudioBackend::Open(handle) =>
+                Some((handle.mixer(), handle.config().channel_count(), handle.config().sample_rate())),
+            AudioBackend::Offline(offline) => Some((&offline.mixer, offline.channels, offline.sample_rate)),
+            AudioBackend::None | AudioBackend::WaitForGesture => None,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.output().is_some()
+    }
+
+    /// Gives the bus a mixer, and mixes that into its parent.
+    fn connect_bus(&mut self, idx: usize) {
+        let Some((output_mixer, channels, sample_rate)) = self.output() else { return };
+
+        let (mixer, output) = rodio::mixer::mixer(channels, sample_rate);
+        let output = BusOutput::new(output, self.buses[idx].shared.clone());
+
+        match self.buses[0].mixer.as_ref().filter(|_| idx != 0) {
+            Some(master) => master.add(output),
+            None => output_mixer.add(output),
+        }
+        self.buses[idx].mixer = Some(mixer);
+    }
+
+    pub fn is_offline(&self) -> bool {
+        matches!(self.backend, AudioBackend::Offline(_))
+    }
+
+    /// Everything the offline backend has rendered so far (or since the last
+    /// take_rendered()), as interleaved stereo samples. None if the backend
+    /// isn't offline.
+    pub fn rendered(&self) -> Option<&[f32]> {
+        match &self.backend {
+            AudioBackend::Offline(offline) => Some(&offline.samples),
+            _ => None,
+        }
+    }
+
+    /// Returns the rendered samples and clears them, e.g. to keep long tests
+    /// from collecting minutes of audio.
+    pub fn take_rendered(&mut self) -> Vec<f32> {
+        match &mut self.backend {
+            AudioBackend::Offline(offline) => std::mem::take(&mut offline.samples),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The channel count and sample rate of the mix, if there is a backend.
+    pub fn output_format(&self) -> Option<(ChannelCount, SampleRate)> {
+        self.output().map(|(_, channels, sample_rate)| (channels, sample_rate))
+    }
+
+    /// Saves the rendered samples as a WAV file, for listening to what a test
+    /// played.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_rendered_wav(&self, path: impl AsRef<std::path::Path>) -> EngineResult<()> {
+        let AudioBackend::Offline(offline) = &self.backend else {
+            return Err(EngineError::new("audio: only the offline backend can be saved"));
+        };
+        offline::save_wav(path.as_ref(), &offline.samples, offline.channels, offline.sample_rate)
+    }
 
-            return Audio {
-                backend: AudioBackend::Open(stream_handle),
-                stored_music,
-                current_music_sink: None,
+    /// Adds a bus that mixes into the master bus, or returns the existing bus
+    /// with that name.
+    pub fn add_bus(&mut self, name: &str) -> &mut Bus {
+        let idx = match self.buses.iter().position(|bus| bus.name() == name) {
+            Some(idx) => idx,
+            None => {
+                self.buses.push(Bus::new(name));
+                self.connect_bus(self.buses.len() - 1);
+                self.buses.len() - 1
             }
+        };
+        &mut self.buses[idx]
+    }
+
+    pub fn bus(&self, name: &str) -> Option<&Bus> {
+        self.buses.iter().find(|bus| bus.name() == name)
+    }
+
+    pub fn bus_mut(&mut self, name: &str) -> Option<&mut Bus> {
+        self.buses.iter_mut().find(|bus| bus.name() == name)
+    }
+
+    pub fn buses(&self) -> impl Iterator<Item = &Bus> {
+        self.buses.iter()
+    }
+
+    /// Updates the ducking and hands the bus settings to the audio thread.
+    /// With the offline backend, also renders the tick's audio. Called once
+    /// per tick.
+    pub(crate) fn tick(&mut self, dt: f32) {
+        // Move on shortly before the track ends, so that it can crossfade, or
+        // once it has ended if its length isn't known.
+        let music_ended = self.current_music.as_ref().is_some_and(Voice::is_finished);
+        if let Some(playlist) = &self.playlist
+            && (music_ended || playlist.should_advance(self.crossfade))
+            && let Some(next) = playlist.next_index() {
+            self.start_track(next);
+        }
+
+        self.emitters.get_mut().retain(|emitter| {
+            let Some(emitter) = emitter.upgrade() else { return false };
+            spatial::update_gains(&emitter, &self.listener, &self.attenuation);
+            true
+        });
+
+        for idx in 0..self.buses.len() {
+            let sidechain_active = self.buses[idx].ducking()
+                .and_then(|ducking| self.bus(&ducking.sidechain))
+                .is_some_and(Bus::is_active);
+
+            let bus = &mut self.buses[idx];
+            bus.update_ducking(sidechain_active, dt);
+            bus.publish();
         }
 
-        log::info!("audio: no backend available");
-        return Audio {
-            backend: AudioBackend::None,
-            stored_music,
-            current_music_sink: None,
+        if let AudioBackend::Offline(offline) = &mut self.backend {
+            offline.render(dt);
         }
     }
 
-    pub fn play(&self, sound: &Sound) {
-        let AudioBackend::Open(handle) = &self.backend else { return };
+    pub fn listener(&self) -> &Listener {
+        &self.listener
+    }
 
-        // Cloning should be fast here because the internal data is reference
-        // counted.
-        handle.mixer().add(sound.buffer.clone());
+    /// Moves the listener. The Engine does this every tick, to follow the
+    /// main camera.
+    pub fn set_listener(&mut self, listener: Listener) {
+        self.listener = listener;
+    }
+
+    /// Writes the volume and mute of every bus in the format described in
+    /// audio/bus.rs, e.g. to save the player's settings.
+    pub fn settings_to_config(&self) -> String {
+        let mut out = String::new();
+        for bus in &self.buses {
+            out.push_str(&format!("{} = {}{}\n", bus.name(), bus.volume(),
+                if bus.is_muted() { " muted" } else { "" }));
+        }
+        out
+    }
+
+    /// Applies saved volume settings. Every bus in the settings must already
+    /// exist.
+    pub fn load_settings(&mut self, text: &str) -> EngineResult<()> {
+        for (name, volume, muted) in bus::parse_settings(text)? {
+            let Some(bus) = self.bus_mut(&name) else {
+                return Err(EngineError::new(format!("audio settings: no bus named {}", name)));
+            };
+            bus.set_volume(volume);
+            bus.set_muted(muted);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_settings_file(&mut self, path: impl AsRef<std::path::Path>) -> EngineResult<()> {
+        let text = std::fs::read_to_string(path.as_ref())
+            .map_err(|err| EngineError::new(format!("failed to read {}: {}", path.as_ref().display(), err)))?;
+        self.load_settings(&text)
     }
 
-    pub fn play_speed(&self, sound: &Sound, speed: f32) {
-        let AudioBackend::Open(handle) = &self.backend else { return };
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_settings_file(&self, path: impl AsRef<std::path::Path>) -> EngineResult<()> {
+        std::fs::write(path.as_ref(), self.settings_to_config())
+            .map_err(|err| EngineError::new(format!("failed to write {}: {}", path.as_ref().display(), err)))
+    }
+
+    /// The web version of load_settings_file(), reading from the browser's
+    /// localStorage. Does nothing if nothing was saved under the key.
+    #[cfg(target_arch = "wasm32")]
+    pub fn load_settings_local_storage(&mut self, key: &str) -> EngineResult<()> {
+        let text = local_storage()?.get_item(key)
+            .map_err(|err| EngineError::new(format!("failed to read {} from localStorage: {:?}", key, err)))?;
+        match text {
+            Some(text) => self.load_settings(&text),
+            None => Ok(()),
+        }
+    }
+
+    /// The web version of save_settings_file(), writing to the browser's
+    /// localStorage.
+    #[cfg(target_arch = "wasm32")]
+    pub fn save_settings_local_storage(&self, key: &str) -> EngineResult<()> {
+        local_storage()?.set_item(key, &self.settings_to_config())
+            .map_err(|err| EngineError::new(format!("failed to write {} to localStorage: {:?}", key, err)))
+    }
+
+    /// Returns false if the bus doesn't exist or there is no backend.
+    fn add_to_bus(&self, bus: &str, source: impl Source + Send + 'static) -> bool {
+        let Some(bus) = self.bus(bus) else {
+            log::warn!("audio: no bus named {}", bus);
+            return false;
+        };
+        let Some(mixer) = &bus.mixer else { return false };
+
+        mixer.add(Counted::new(source, bus.shared.clone()));
+        true
+    }
+
+    /// Plays the sound on the sfx bus.
+    pub fn play(&self, sound: &Sound) -> Voice {
+        self.play_with(sound, PlayParams::default())
+    }
+
+    pub fn play_speed(&self, sound: &Sound, speed: f32) -> Voice {
+        self.play_with(sound, PlayParams { speed, ..Default::default() })
+    }
+
+    pub fn play_on(&self, bus: &str, sound: &Sound) -> Voice {
+        self.play_with(sound, PlayParams { bus, ..Default::default() })
+    }
+
+    /// Plays the sound from the emitter's position in the world, on the sfx
+    /// bus.
+    pub fn play_at(&self, sound: &Sound, emitter: &Emitter) -> Voice {
+        self.play_with(sound, PlayParams { emitter: Some(emitter), ..Default::default() })
+    }
+
+    pub fn play_with(&self, sound: &Sound, mut params: PlayParams) -> Voice {
+        if !self.is_open() {
+            return Voice::finished();
+        }
+
+        sound.steal_voice();
+        if let Some(range) = &sound.pitch_range {
+            let random = self.rng.borrow_mut().next_f32();
+            params.speed *= range.start + (range.end - range.start) * random;
+        }
 
         // Cloning should be fast here because the internal data is reference
         // counted.
-        handle.mixer().add(sound.buffer.clone().speed(speed));
+        let source: EngineResult<Box<dyn Source + Send>> = match (&sound.data, params.looping) {
+            (SoundData::Buffered(buffer), false) => Ok(Box::new(buffer.clone())),
+            (SoundData::Buffered(buffer), true) => Ok(Box::new(buffer.clone().repeat_infinite())),
+            (SoundData::Streamed(stream), false) => stream.decoder().map(|decoder| Box::new(decoder) as _),
+            (SoundData::Streamed(stream), true) => stream.looped_decoder().map(|decoder| Box::new(decoder) as _),
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => {
+                log::error!("audio: failed to play sound: {}", err);
+                return Voice::finished();
+            }
+        };
+        let Some(voice) = self.play_source(source, &params) else { return Voice::finished() };
+
+        sound.plays.set(sound.plays.get() + 1);
+        sound.add_voice(&voice);
+        voice
     }
 
-    pub fn play_music(&mut self, data: &'static [u8], amp: f32) {
-        // Store music even if we don't have a backend, because we might get one
-        // later.
-        let cursor = std::io::Cursor::new(data);
-        let decoder = rodio::decoder::Decoder::new_looped(cursor).unwrap();
-        let music = decoder.amplify(amp);
+    /// Plays a procedurally generated tone. PlayParams::looping is ignored; a
+    /// Synth without a hold time plays until it is stopped.
+    pub fn play_synth(&self, synth: &Synth, params: PlayParams) -> Voice {
+        if !self.is_open() {
+            return Voice::finished();
+        }
 
-        log::info!("audio: playing music: {} {} {:?}", music.sample_rate(), music.channels(), music.total_duration());
+        let seed = self.rng.borrow_mut().next_u32();
+        self.play_source(Box::new(synth.source(seed)), &params).unwrap_or_else(Voice::finished)
+    }
 
-        let AudioBackend::Open(handle) = &self.backend else {
+    /// Runs the source through the effects and the emitter, and puts it on the
+    /// bus. Returns None if the bus doesn't exist.
+    fn play_source(&self, source: Box<dyn Source + Send>, params: &PlayParams) -> Option<Voice> {
+        let source: Box<dyn Source + Send> = match params.effects {
+            [] => source,
+            effects => Box::new(Effects::new(source, effects)),
+        };
+        let source: Box<dyn Source + Send> = match params.emitter {
+            Some(emitter) => {
+                emitter.update(&self.listener, &self.attenuation);
+                self.add_emitter(emitter);
+                Box::new(Spatialized::new(source, emitter))
+            }
+            None => source,
+        };
+
+        let voice = Voice::new(params);
+        self.add_to_bus(params.bus, VoiceSource::new(source, &voice)).then_some(voice)
+    }
+
+    fn add_emitter(&self, emitter: &Emitter) {
+        let weak = emitter.downgrade();
+        let mut emitters = self.emitters.borrow_mut();
+        if !emitters.iter().any(|other| other.ptr_eq(&weak)) {
+            emitters.push(weak);
+        }
+    }
+
+    /// The music started by play_music() or play_playlist(), if any.
+    pub fn current_music(&self) -> Option<&Voice> {
+        self.current_music.as_ref()
+    }
+
+    pub fn crossfade(&self) -> f32 {
+        self.crossfade
+    }
+
+    /// Sets how long one track fades into the next, in seconds. 0 cuts
+    /// straight to the next track.
+    pub fn set_crossfade(&mut self, seconds: f32) {
+        self.crossfade = seconds.max(0.0);
+    }
+
+    /// Plays a single track on the music bus, crossfading from any music that
+    /// is already playing.
+    pub fn play_music(&mut self, track: &Track) -> Voice {
+        self.play_playlist(vec![track.clone()], false)
+    }
+
+    /// Plays the tracks one after another, crossfading between them. A track
+    /// that loops keeps playing until next_track() is called. With `repeat`,
+    /// the first track follows the last one.
+    pub fn play_playlist(&mut self, tracks: Vec<Track>, repeat: bool) -> Voice {
+        if tracks.is_empty() {
+            self.stop_music(self.crossfade);
+            return Voice::finished();
+        }
+
+        self.playlist = Some(Playlist { tracks, current: 0, repeat, position: Arc::default() });
+        self.start_track(0)
+    }
+
+    /// Crossfades into the next track of the playlist. Returns None if this
+    /// was the last one.
+    pub fn next_track(&mut self) -> Option<Voice> {
+        let next = self.playlist.as_ref()?.next_index()?;
+        Some(self.start_track(next))
+    }
+
+    /// Fades out the music over the given number of seconds, and forgets the
+    /// playlist.
+    pub fn stop_music(&mut self, fade_out: f32) {
+        self.playlist = None;
+        self.stored_music = None;
+        if let Some(current) = self.current_music.take() {
+            current.fade_out(fade_out);
+        }
+    }
+
+    /// Starts the track at the given index of the playlist, fading out the
+    /// music that was playing.
+    fn start_track(&mut self, idx: usize) -> Voice {
+        let Some(playlist) = &mut self.playlist else { return Voice::finished() };
+        let track = &playlist.tracks[idx];
+        playlist.current = idx;
+
+        log::info!("audio: playing music: {} {:?}", track.sample_rate(), track.duration());
+        let (source, position) = match track.source() {
+            Ok(source) => source,
+            Err(err) => {
+                log::error!("audio: failed to play music: {}", err);
+                return Voice::finished();
+            }
+        };
+        playlist.position = position;
+
+        // Only fade in if there is something to fade from. Music that waits for
+        // a backend hasn't been heard yet.
+        let is_open = self.is_open();
+        let fade_in = match self.current_music.take() {
+            Some(current) if is_open && !current.is_finished() => {
+                current.fade_out(self.crossfade);
+                self.crossfade
+            }
+            Some(current) => {
+                current.stop();
+                0.0
+            }
+            None => 0.0,
+        };
+
+        let voice = Voice::new(&PlayParams { bus: Self::MUSIC, fade_in, ..Default::default() });
+        let music = Box::new(VoiceSource::new(source, &voice));
+        self.current_music = Some(voice.clone());
+
+        if !is_open {
             // If we don't have a handle *yet*, store the music for later.
-            let music = Box::new(music);
             self.stored_music = Some(music);
-            return;
-        };
+            return voice;
+        }
 
-        log::info!("audio: actually playing music!");
+        self.start_music(music);
+        voice
+    }
 
-        let sink = rodio::Sink::connect_new(handle.mixer());
-        sink.append(music);
-        sink.play();
-        
-        self.current_music_sink = Some(sink);
+    fn start_music(&mut self, music: Box<dyn Source + Send + 'static>) {
+        self.add_to_bus(Self::MUSIC, music);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> EngineResult<web_sys::Storage> {
+    web_sys::window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .ok_or_else(|| EngineError::new("localStorage is not available"))
+}
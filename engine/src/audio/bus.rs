@@ -0,0 +1,339 @@
+// Mixer buses.
+//
+// Every bus is a rodio Mixer of its own. Sounds are added to a bus's mixer,
+// and the bus's output goes through its effects into the master bus, which
+// plays on the output stream. The settings live on the game thread in Bus,
+// and are handed to the audio thread through BusShared by Audio::tick().
+//
+// The volume settings can be saved in the same style as the input bindings:
+//
+// # bus = volume [muted]
+// master = 1
+// music  = 0.6 muted
+
+use std::{sync::{atomic::{AtomicU32, AtomicUsize, Ordering}, Arc}, time::Duration};
+
+use rodio::{mixer::{Mixer, MixerSource}, source::SeekError, ChannelCount, SampleRate, Source};
+
+use crate::error::{EngineError, EngineResult};
+
+pub(crate) struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub(crate) fn new(value: f32) -> Self {
+        AtomicF32(AtomicU32::new(value.to_bits()))
+    }
+
+    pub(crate) fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// The part of a Bus that the audio thread reads.
+pub(crate) struct BusShared {
+    /// The final gain of the bus, with its volume, mute and ducking applied.
+    gain: AtomicF32,
+    /// Cutoff of the low-pass filter in Hz, or 0 when it is off.
+    low_pass: AtomicF32,
+    /// How many sounds are playing on the bus right now.
+    playing: AtomicUsize,
+}
+
+/// Turns a bus's volume down while another bus (the sidechain) is playing
+/// anything, e.g. the music while a voice line plays.
+#[derive(Clone, Debug)]
+pub struct Ducking {
+    pub sidechain: String,
+    /// The gain while ducked, from 0 to 1.
+    pub gain: f32,
+    /// How long it takes to duck, in seconds.
+    pub attack: f32,
+    /// How long it takes to come back up, in seconds.
+    pub release: f32,
+}
+
+pub struct Bus {
+    name: String,
+    volume: f32,
+    muted: bool,
+    low_pass: Option<f32>,
+    ducking: Option<Ducking>,
+    /// The current ducking gain, moving towards 1 or Ducking::gain.
+    duck_gain: f32,
+
+    pub(crate) shared: Arc<BusShared>,
+    /// None until the audio backend is open.
+    pub(crate) mixer: Option<Mixer>,
+}
+
+impl Bus {
+    pub(crate) fn new(name: &str) -> Self {
+        Bus {
+            name: name.to_string(),
+            volume: 1.0,
+            muted: false,
+            low_pass: None,
+            ducking: None,
+            duck_gain: 1.0,
+
+            shared: Arc::new(BusShared {
+                gain: AtomicF32::new(1.0),
+                low_pass: AtomicF32::new(0.0),
+                playing: AtomicUsize::new(0),
+            }),
+            mixer: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    /// Sets the volume, from 0 upwards. 1 leaves the sounds as they are.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.max(0.0);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Silences the bus without forgetting its volume.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn low_pass(&self) -> Option<f32> {
+        self.low_pass
+    }
+
+    /// Filters out everything above the given frequency in Hz, e.g. to muffle
+    /// the sound effects while a menu is open. None turns the filter off.
+    pub fn set_low_pass(&mut self, cutoff: Option<f32>) {
+        self.low_pass = cutoff;
+    }
+
+    pub fn ducking(&self) -> Option<&Ducking> {
+        self.ducking.as_ref()
+    }
+
+    pub fn set_ducking(&mut self, ducking: Option<Ducking>) {
+        self.ducking = ducking;
+    }
+
+    /// Whether any sound is playing on this bus.
+    pub fn is_active(&self) -> bool {
+        self.shared.playing.load(Ordering::Relaxed) > 0
+    }
+
+    /// The volume with mute applied, but not ducking.
+    pub(crate) fn own_gain(&self) -> f32 {
+        if self.muted { 0.0 } else { self.volume }
+    }
+
+    /// Moves the ducking gain towards its target by `dt` seconds.
+    pub(crate) fn update_ducking(&mut self, sidechain_active: bool, dt: f32) {
+        let Some(ducking) = &self.ducking else {
+            self.duck_gain = 1.0;
+            return;
+        };
+
+        let (target, time) = if sidechain_active {
+            (ducking.gain, ducking.attack)
+        }
+        else {
+            (1.0, ducking.release)
+        };
+
+        // Linear in gain, over the full range from 1 to Ducking::gain.
+        let step = if time > 0.0 { (1.0 - ducking.gain).abs() * dt / time } else { 1.0 };
+        self.duck_gain = if self.duck_gain < target {
+            (self.duck_gain + step).min(target)
+        }
+        else {
+            (self.duck_gain - step).max(target)
+        };
+    }
+
+    /// Hands the settings to the audio thread. The master bus's gain is
+    /// applied when the other buses are mixed into it, so it isn't part of
+    /// this.
+    pub(crate) fn publish(&self) {
+        self.shared.gain.store(self.own_gain() * self.duck_gain);
+        self.shared.low_pass.store(self.low_pass.unwrap_or(0.0));
+    }
+}
+
+/// Parses the settings format described at the top of this file, into
+/// (bus, volume, muted).
+pub(crate) fn parse_settings(text: &str) -> EngineResult<Vec<(String, f32, bool)>> {
+    let mut settings = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let error = |message: String| EngineError::Config { line: idx + 1, message };
+
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some((bus, value)) = line.split_once('=') else {
+            return Err(error("expected 'bus = volume'".to_string()));
+        };
+
+        let mut words = value.split_whitespace();
+        let volume = words.next()
+            .and_then(|word| word.parse::<f32>().ok())
+            .ok_or_else(|| error("expected a volume".to_string()))?;
+        let muted = match words.next() {
+            None => false,
+            Some("muted") => true,
+            Some(other) => return Err(error(format!("unexpected '{}'", other))),
+        };
+
+        settings.push((bus.trim().to_string(), volume, muted));
+    }
+    Ok(settings)
+}
+
+/// The output of a bus's mixer, with the bus's effects applied. Never ends,
+/// so that the bus stays connected while nothing is playing.
+pub(crate) struct BusOutput {
+    input: MixerSource,
+    shared: Arc<BusShared>,
+
+    /// Smoothed towards BusShared::gain, so that volume changes don't click.
+    gain: f32,
+    /// The coefficient of the one-pole low-pass filter, 1 when it is off.
+    alpha: f32,
+    filter_state: Vec<f32>,
+    sample_idx: usize,
+}
+
+impl BusOutput {
+    /// How far the gain moves towards its target every frame. About 10ms to
+    /// settle at 48kHz.
+    const SMOOTHING: f32 = 0.002;
+
+    pub(crate) fn new(input: MixerSource, shared: Arc<BusShared>) -> Self {
+        let channels = input.channels() as usize;
+        BusOutput {
+            input,
+            gain: shared.gain.load(),
+            shared,
+            alpha: 1.0,
+            filter_state: vec![0.0; channels],
+            sample_idx: 0,
+        }
+    }
+}
+
+impl Iterator for BusOutput {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next().unwrap_or(0.0);
+
+        let channel = self.sample_idx % self.filter_state.len();
+        self.sample_idx += 1;
+
+        // Only look at the settings once per frame.
+        if channel == 0 {
+            self.gain += (self.shared.gain.load() - self.gain) * Self::SMOOTHING;
+
+            let cutoff = self.shared.low_pass.load();
+            self.alpha = if cutoff > 0.0 {
+                1.0 - f32::exp(-2.0 * std::f32::consts::PI * cutoff / self.input.sample_rate() as f32)
+            }
+            else {
+                1.0
+            };
+        }
+
+        let state = &mut self.filter_state[channel];
+        *state += self.alpha * (sample - *state);
+        Some(*state * self.gain)
+    }
+}
+
+impl Source for BusOutput {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, _pos: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported { underlying_source: std::any::type_name::<Self>() })
+    }
+}
+
+/// Wraps a sound that plays on a bus, so that the bus knows it is active.
+pub(crate) struct Counted<S> {
+    input: S,
+    shared: Arc<BusShared>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(input: S, shared: Arc<BusShared>) -> Self {
+        shared.playing.fetch_add(1, Ordering::Relaxed);
+        Counted { input, shared }
+    }
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        self.shared.playing.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: Source> Iterator for Counted<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.input.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<S: Source> Source for Counted<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)
+    }
+}
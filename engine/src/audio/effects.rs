@@ -0,0 +1,225 @@
+// Per-voice effects.
+//
+// A sound can be played through a chain of Effects, given in
+// PlayParams::effects. They run on the audio thread, in order, before the
+// sound is panned and mixed into its bus. Echo and reverb keep ringing for a
+// while after the sound itself has ended.
+
+use std::time::Duration;
+
+use rodio::{source::SeekError, ChannelCount, SampleRate, Source};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Effect {
+    /// Filters out everything above the cutoff, in Hz.
+    LowPass(f32),
+    /// Filters out everything below the cutoff, in Hz.
+    HighPass(f32),
+    /// Repeats the sound after `delay` seconds, each repeat `feedback` times as
+    /// loud as the last. `mix` is how loud the repeats are, from 0 to 1.
+    Echo { delay: f32, feedback: f32, mix: f32 },
+    /// Makes the sound ring as if it was played in a room. `room_size` goes
+    /// from 0 (a small room) to 1 (a hall), and `mix` is how much of the
+    /// reverb is heard, from 0 to 1.
+    Reverb { room_size: f32, mix: f32 },
+}
+
+/// A delay line over interleaved samples, so that one buffer serves every
+/// channel.
+struct Delay {
+    buffer: Vec<f32>,
+    idx: usize,
+}
+
+impl Delay {
+    fn new(frames: usize, channels: usize) -> Self {
+        Delay { buffer: vec![0.0; frames.max(1) * channels], idx: 0 }
+    }
+
+    /// Returns the sample that went in one delay ago, and puts in a new one.
+    fn exchange(&mut self, f: impl FnOnce(f32) -> f32) -> f32 {
+        let out = self.buffer[self.idx];
+        self.buffer[self.idx] = f(out);
+        self.idx = (self.idx + 1) % self.buffer.len();
+        out
+    }
+}
+
+enum Stage {
+    LowPass { alpha: f32, state: Vec<f32> },
+    HighPass { alpha: f32, last_input: Vec<f32>, last_output: Vec<f32> },
+    Echo { delay: Delay, feedback: f32, mix: f32 },
+    Reverb { combs: Vec<(Delay, Vec<f32>)>, allpasses: Vec<Delay>, feedback: f32, mix: f32 },
+}
+
+impl Stage {
+    /// The delays of the reverb's comb and allpass filters in frames at
+    /// 44.1kHz, from Freeverb.
+    const COMB_DELAYS: [usize; 4] = [1116, 1188, 1277, 1356];
+    const ALLPASS_DELAYS: [usize; 2] = [556, 441];
+    /// How much the comb filters dull the high frequencies.
+    const DAMPING: f32 = 0.2;
+
+    fn new(effect: Effect, channels: usize, sample_rate: SampleRate) -> Self {
+        let rate = sample_rate as f32;
+        let scaled = |frames: usize| (frames as f32 * rate / 44100.0) as usize;
+        match effect {
+            Effect::LowPass(cutoff) => Stage::LowPass {
+                alpha: 1.0 - f32::exp(-std::f32::consts::TAU * cutoff / rate),
+                state: vec![0.0; channels],
+            },
+            Effect::HighPass(cutoff) => {
+                let rc = 1.0 / (std::f32::consts::TAU * cutoff.max(1.0));
+                Stage::HighPass {
+                    alpha: rc / (rc + 1.0 / rate),
+                    last_input: vec![0.0; channels],
+                    last_output: vec![0.0; channels],
+                }
+            }
+            Effect::Echo { delay, feedback, mix } => Stage::Echo {
+                delay: Delay::new((delay * rate) as usize, channels),
+                feedback: feedback.clamp(0.0, 0.99),
+                mix,
+            },
+            Effect::Reverb { room_size, mix } => Stage::Reverb {
+                combs: Self::COMB_DELAYS.iter()
+                    .map(|frames| (Delay::new(scaled(*frames), channels), vec![0.0; channels]))
+                    .collect(),
+                allpasses: Self::ALLPASS_DELAYS.iter()
+                    .map(|frames| Delay::new(scaled(*frames), channels))
+                    .collect(),
+                feedback: 0.7 + 0.28 * room_size.clamp(0.0, 1.0),
+                mix,
+            },
+        }
+    }
+
+    /// How long the effect keeps ringing once its input is silent, in
+    /// seconds.
+    fn tail(effect: &Effect) -> f32 {
+        match *effect {
+            Effect::LowPass(_) | Effect::HighPass(_) => 0.0,
+            // Until the repeats are 60dB down.
+            Effect::Echo { delay, feedback, .. } =>
+                delay * (0.001f32.ln() / feedback.clamp(0.01, 0.99).ln()).ceil(),
+            Effect::Reverb { room_size, .. } => 1.0 + 3.0 * room_size.clamp(0.0, 1.0),
+        }
+    }
+
+    fn process(&mut self, sample: f32, channel: usize) -> f32 {
+        match self {
+            Stage::LowPass { alpha, state } => {
+                let state = &mut state[channel];
+                *state += *alpha * (sample - *state);
+                *state
+            }
+            Stage::HighPass { alpha, last_input, last_output } => {
+                let out = *alpha * (last_output[channel] + sample - last_input[channel]);
+                last_input[channel] = sample;
+                last_output[channel] = out;
+                out
+            }
+            Stage::Echo { delay, feedback, mix } => {
+                let echo = delay.exchange(|echo| sample + echo * *feedback);
+                sample + echo * *mix
+            }
+            Stage::Reverb { combs, allpasses, feedback, mix } => {
+                let mut wet = 0.0;
+                for (delay, filter) in combs.iter_mut() {
+                    let filter = &mut filter[channel];
+                    let out = delay.exchange(|out| {
+                        *filter = out * (1.0 - Self::DAMPING) + *filter * Self::DAMPING;
+                        sample + *filter * *feedback
+                    });
+                    wet += out;
+                }
+                wet /= combs.len() as f32;
+
+                for delay in allpasses.iter_mut() {
+                    let input = wet;
+                    let delayed = delay.exchange(|delayed| input + delayed * 0.5);
+                    wet = delayed - input;
+                }
+                sample * (1.0 - *mix) + wet * *mix
+            }
+        }
+    }
+}
+
+/// Runs a source through a chain of effects.
+pub(crate) struct Effects<S> {
+    input: S,
+    stages: Vec<Stage>,
+    channels: usize,
+    channel: usize,
+    /// How many samples of silence are left to feed through the effects once
+    /// the input has ended.
+    tail: usize,
+    input_ended: bool,
+}
+
+impl<S: Source> Effects<S> {
+    pub(crate) fn new(input: S, effects: &[Effect]) -> Self {
+        let channels = input.channels().max(1) as usize;
+        let sample_rate = input.sample_rate();
+        let tail = effects.iter().map(Stage::tail).sum::<f32>();
+        Effects {
+            stages: effects.iter().map(|effect| Stage::new(*effect, channels, sample_rate)).collect(),
+            input,
+            channels,
+            channel: 0,
+            tail: (tail * sample_rate as f32) as usize * channels,
+            input_ended: false,
+        }
+    }
+}
+
+impl<S: Source> Iterator for Effects<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = if self.input_ended { None } else { self.input.next() };
+        let mut sample = match sample {
+            Some(sample) => sample,
+            None => {
+                // Finish the frame, and then play out the tail.
+                self.input_ended = true;
+                if self.channel == 0 {
+                    if self.tail == 0 {
+                        return None;
+                    }
+                    self.tail = self.tail.saturating_sub(self.channels);
+                }
+                0.0
+            }
+        };
+
+        for stage in &mut self.stages {
+            sample = stage.process(sample, self.channel);
+        }
+        self.channel = (self.channel + 1) % self.channels;
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Effects<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels as ChannelCount
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, _pos: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported { underlying_source: std::any::type_name::<Self>() })
+    }
+}
@@ -0,0 +1,296 @@
+// Music tracks and the playlist.
+//
+// A Track loops on exact sample positions: an intro plays once, and then the
+// loop section repeats. Tracks are either decoded ahead of time, or streamed
+// (see audio/stream.rs), in which case looping seeks in the file. The playlist
+// lives in Audio, which crossfades from one track to the next.
+
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+use rodio::{source::SeekError, ChannelCount, Decoder, SampleRate, Source};
+
+use crate::{audio::stream::{ReadSeek, Stream}, error::{EngineError, EngineResult}};
+
+/// A piece of music. Cloning is cheap, as the samples or the stream are
+/// shared.
+#[derive(Clone)]
+pub struct Track {
+    data: TrackData,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    /// None for streams that don't say how long they are.
+    frames: Option<usize>,
+
+    /// The section that repeats, in frames (samples per channel). None plays
+    /// the track once.
+    loop_section: Option<(usize, usize)>,
+    volume: f32,
+}
+
+#[derive(Clone)]
+enum TrackData {
+    Decoded(Arc<[f32]>),
+    Streamed(Stream),
+}
+
+impl Track {
+    /// Decodes a track from the bytes of an audio file, in any format that
+    /// Sound supports. By default, the whole track loops.
+    pub fn from_data(data: impl AsRef<[u8]> + Send + Sync + 'static) -> EngineResult<Track> {
+        let decoder = rodio::Decoder::try_from(std::io::Cursor::new(data))
+            .map_err(|err| EngineError::AssetParse(format!("music: {}", err)))?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let samples: Arc<[f32]> = decoder.collect();
+
+        let frames = samples.len() / channels.max(1) as usize;
+        Ok(Track {
+            data: TrackData::Decoded(samples),
+            channels,
+            sample_rate,
+            frames: Some(frames),
+            loop_section: Some((0, frames)),
+            volume: 1.0,
+        })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_file(path: impl AsRef<std::path::Path>) -> EngineResult<Track> {
+        let data = std::fs::read(path.as_ref())
+            .map_err(|err| EngineError::new(format!("failed to read {}: {}", path.as_ref().display(), err)))?;
+        Self::from_data(data)
+    }
+
+    /// Streams a track from a reader that is opened every time it plays, so
+    /// that it is never decoded all at once. By default, the whole track
+    /// loops.
+    pub fn streaming<R: ReadSeek + 'static>(open: impl Fn() -> std::io::Result<R> + Send + Sync + 'static) -> EngineResult<Track> {
+        let stream = Stream::new(open)?;
+        let frames = stream.duration
+            .map(|duration| (duration.as_secs_f64() * stream.sample_rate as f64) as usize);
+        Ok(Track {
+            channels: stream.channels,
+            sample_rate: stream.sample_rate,
+            data: TrackData::Streamed(stream),
+            frames,
+            loop_section: Some((0, frames.unwrap_or(usize::MAX))),
+            volume: 1.0,
+        })
+    }
+
+    /// Streams a track from the bytes of an audio file, e.g. embedded with
+    /// include_bytes!(). The bytes stay compressed in memory.
+    pub fn streaming_from_data(data: impl AsRef<[u8]> + Clone + Send + Sync + 'static) -> EngineResult<Track> {
+        Self::streaming(move || Ok(std::io::Cursor::new(data.clone())))
+    }
+
+    /// Plays everything before `start` once as an intro, and then repeats the
+    /// frames from `start` up to `end`. Frames are counted per channel, i.e.
+    /// at the track's sample rate.
+    pub fn with_loop(mut self, start: usize, end: usize) -> Track {
+        let end = self.frames.map_or(end, |frames| end.min(frames));
+        self.loop_section = (start < end).then_some((start, end));
+        self
+    }
+
+    /// Plays the track once, and then ends. A playlist moves on to the next
+    /// track.
+    pub fn without_loop(mut self) -> Track {
+        self.loop_section = None;
+        self
+    }
+
+    /// Scales the track's samples, for tracks that were mastered too quietly
+    /// or loudly. The player's volume is the music bus's.
+    pub fn with_volume(mut self, volume: f32) -> Track {
+        self.volume = volume;
+        self
+    }
+
+    /// How many frames long the track is, if known.
+    pub fn frames(&self) -> Option<usize> {
+        self.frames
+    }
+
+    pub fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.loop_section.is_some()
+    }
+
+    /// How long the track plays before it ends, or None if it loops or its
+    /// length isn't known.
+    pub fn duration(&self) -> Option<Duration> {
+        match self.loop_section {
+            Some(_) => None,
+            None => self.frames.map(|frames| Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)),
+        }
+    }
+
+    pub(crate) fn source(&self) -> EngineResult<(TrackSource, Arc<AtomicUsize>)> {
+        let reader = match &self.data {
+            TrackData::Decoded(samples) => TrackReader::Decoded { samples: samples.clone(), idx: 0 },
+            TrackData::Streamed(stream) => TrackReader::Streamed { decoder: stream.decoder()? },
+        };
+
+        let position = Arc::new(AtomicUsize::new(0));
+        Ok((TrackSource {
+            track: self.clone(),
+            reader,
+            frame: 0,
+            channel: 0,
+            position: position.clone(),
+        }, position))
+    }
+}
+
+enum TrackReader {
+    Decoded { samples: Arc<[f32]>, idx: usize },
+    Streamed { decoder: Decoder<Box<dyn ReadSeek>> },
+}
+
+/// Plays a Track, following its loop section.
+pub(crate) struct TrackSource {
+    track: Track,
+    reader: TrackReader,
+    /// The frame that the next sample belongs to.
+    frame: usize,
+    channel: usize,
+    /// The frame that is playing, for the playlist to know when to move on.
+    position: Arc<AtomicUsize>,
+}
+
+impl TrackSource {
+    fn read(&mut self) -> Option<f32> {
+        match &mut self.reader {
+            TrackReader::Decoded { samples, idx } => {
+                let sample = samples.get(*idx).copied();
+                *idx += 1;
+                sample
+            }
+            TrackReader::Streamed { decoder } => decoder.next(),
+        }
+    }
+
+    /// Jumps to the start of the given frame.
+    fn seek_frame(&mut self, frame: usize) {
+        let channels = self.track.channels.max(1) as usize;
+        match &mut self.reader {
+            TrackReader::Decoded { idx, .. } => *idx = frame * channels,
+            TrackReader::Streamed { decoder } => {
+                let pos = Duration::from_secs_f64(frame as f64 / self.track.sample_rate as f64);
+                if let Err(err) = decoder.try_seek(pos) {
+                    log::warn!("audio: failed to loop music: {}", err);
+                }
+            }
+        }
+        self.frame = frame;
+        self.channel = 0;
+    }
+}
+
+impl Iterator for TrackSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let loop_section = self.track.loop_section;
+        if self.channel == 0
+            && let Some((start, end)) = loop_section
+            && self.frame >= end {
+            self.seek_frame(start);
+        }
+
+        let sample = match self.read() {
+            Some(sample) => sample,
+            // The file ended before the end of the loop section, e.g. because a
+            // stream's length wasn't known. Don't loop on an empty section.
+            None => match loop_section {
+                Some((start, _)) if self.channel == 0 && self.frame > start => {
+                    self.seek_frame(start);
+                    self.read()?
+                }
+                _ => return None,
+            },
+        };
+
+        self.channel += 1;
+        if self.channel == self.track.channels.max(1) as usize {
+            self.channel = 0;
+            self.frame += 1;
+            self.position.store(self.frame, Ordering::Relaxed);
+        }
+        Some(sample * self.track.volume)
+    }
+}
+
+impl Source for TrackSource {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.track.channels
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.track.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.track.duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let frame = (pos.as_secs_f64() * self.track.sample_rate as f64) as usize;
+        self.seek_frame(self.track.frames.map_or(frame, |frames| frame.min(frames)));
+        Ok(())
+    }
+}
+
+/// The playlist state kept in Audio.
+pub(crate) struct Playlist {
+    pub(crate) tracks: Vec<Track>,
+    /// Index into tracks of the one that is playing.
+    pub(crate) current: usize,
+    /// Whether to go back to the first track after the last one.
+    pub(crate) repeat: bool,
+    /// How far the current track has played, in frames.
+    pub(crate) position: Arc<AtomicUsize>,
+}
+
+impl Playlist {
+    /// Whether the current track is close enough to its end to start fading
+    /// into the next one.
+    pub(crate) fn should_advance(&self, crossfade: f32) -> bool {
+        let track = &self.tracks[self.current];
+        if track.is_looping() {
+            return false;
+        }
+
+        // Wait for the track to start, e.g. until the backend opens.
+        let position = self.position.load(Ordering::Relaxed);
+        if position == 0 {
+            return false;
+        }
+
+        // Streams that don't know their length move on once they have ended.
+        let Some(frames) = track.frames() else { return false };
+        let remaining = frames.saturating_sub(position);
+        remaining as f32 <= crossfade * track.sample_rate as f32
+    }
+
+    /// The track after the current one, if there is one.
+    pub(crate) fn next_index(&self) -> Option<usize> {
+        if self.current + 1 < self.tracks.len() {
+            Some(self.current + 1)
+        }
+        else if self.repeat && !self.tracks.is_empty() {
+            Some(0)
+        }
+        else {
+            None
+        }
+    }
+}
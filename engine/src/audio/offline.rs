@@ -0,0 +1,84 @@
+// Offline audio.
+//
+// Instead of an output stream, the offline backend pulls the master mix into
+// a buffer itself, a tick's worth of samples per Audio::tick(). Nothing plays
+// on the speakers, and the result only depends on what the game did in each
+// tick, so tests can check what was played or save the mix as a WAV file.
+
+use rodio::{mixer::{Mixer, MixerSource}, ChannelCount, SampleRate};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::error::{EngineError, EngineResult};
+
+pub(crate) struct OfflineOutput {
+    pub(crate) mixer: Mixer,
+    source: MixerSource,
+    pub(crate) channels: ChannelCount,
+    pub(crate) sample_rate: SampleRate,
+
+    /// Every sample rendered so far, interleaved.
+    pub(crate) samples: Vec<f32>,
+    /// The part of a frame that was due, but not rendered yet, when the tick
+    /// length isn't a whole number of frames.
+    pending: f64,
+}
+
+impl OfflineOutput {
+    pub(crate) fn new(channels: ChannelCount, sample_rate: SampleRate) -> Self {
+        let (mixer, source) = rodio::mixer::mixer(channels, sample_rate);
+        OfflineOutput {
+            mixer,
+            source,
+            channels,
+            sample_rate,
+            samples: Vec::new(),
+            pending: 0.0,
+        }
+    }
+
+    /// Renders `dt` seconds of the mix.
+    pub(crate) fn render(&mut self, dt: f32) {
+        self.pending += dt as f64 * self.sample_rate as f64;
+        let frames = self.pending.floor();
+        self.pending -= frames;
+
+        let count = frames as usize * self.channels as usize;
+        self.samples.reserve(count);
+        for _ in 0..count {
+            // The mixer ends whenever nothing is playing.
+            self.samples.push(self.source.next().unwrap_or(0.0));
+        }
+    }
+}
+
+/// Writes interleaved samples as a 16-bit WAV file.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn save_wav(path: &std::path::Path, samples: &[f32], channels: ChannelCount, sample_rate: SampleRate) -> EngineResult<()> {
+    let data_len = samples.len() as u32 * 2;
+    let block_align = channels * 2;
+
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    std::fs::write(path, out)
+        .map_err(|err| EngineError::new(format!("failed to write {}: {}", path.display(), err)))
+}
@@ -0,0 +1,235 @@
+// Positional audio.
+//
+// Sounds can be played from an Emitter, which has a position in the world.
+// Once per tick, Audio::tick() works out how loud every emitter is in the
+// left and right ear of the Listener (normally the main camera), and hands
+// that to the audio thread, where Spatialized mixes the sound down to mono and
+// pans it.
+
+use std::{sync::{Arc, Weak}, time::Duration};
+
+use cgmath::{InnerSpace, Point3, Vector3};
+use rodio::{source::SeekError, ChannelCount, SampleRate, Source};
+
+use crate::{audio::bus::AtomicF32, video::camera::Camera};
+
+/// Where the sounds are heard from.
+#[derive(Clone, Copy, Debug)]
+pub struct Listener {
+    pub position: Point3<f32>,
+    /// The direction the listener faces.
+    pub forward: Vector3<f32>,
+    pub up: Vector3<f32>,
+}
+
+impl Listener {
+    /// Hears from the camera's position, with the right ear towards the right
+    /// of the screen.
+    pub fn from_camera(camera: &Camera) -> Self {
+        let position = camera.position.get();
+        Listener {
+            position,
+            forward: camera.target.get() - position,
+            up: camera.up,
+        }
+    }
+
+    /// The unit vector pointing out of the right ear.
+    fn right(&self) -> Vector3<f32> {
+        let right = self.forward.cross(self.up);
+        if right.magnitude2() > f32::EPSILON {
+            right.normalize()
+        }
+        else {
+            // Looking straight along up; any ear will do.
+            Vector3::unit_x()
+        }
+    }
+
+    /// The gains of the left and right channel for a sound at the given
+    /// position. Uses an equal-power pan, scaled so that a sound straight
+    /// ahead plays at its full volume in both ears.
+    pub(crate) fn gains(&self, position: Point3<f32>, attenuation: &Attenuation) -> (f32, f32) {
+        let offset = position - self.position;
+        let distance = offset.magnitude();
+        let pan = if distance > f32::EPSILON {
+            (offset.dot(self.right()) / distance).clamp(-1.0, 1.0)
+        }
+        else {
+            0.0
+        };
+
+        let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        let gain = attenuation.gain(distance) * std::f32::consts::SQRT_2;
+        (angle.cos() * gain, angle.sin() * gain)
+    }
+}
+
+/// How sounds get quieter with distance. Uses the inverse distance model: at
+/// reference_distance and closer, sounds play at full volume, and beyond it
+/// they fall off until max_distance, after which they stay the same.
+#[derive(Clone, Copy, Debug)]
+pub struct Attenuation {
+    pub reference_distance: f32,
+    pub max_distance: f32,
+    /// How quickly the volume falls off. 0 turns attenuation off.
+    pub rolloff: f32,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Attenuation {
+            reference_distance: 10.0,
+            max_distance: 100.0,
+            rolloff: 1.0,
+        }
+    }
+}
+
+impl Attenuation {
+    pub fn gain(&self, distance: f32) -> f32 {
+        let reference = self.reference_distance.max(f32::EPSILON);
+        let distance = distance.clamp(reference, self.max_distance.max(reference));
+        reference / (reference + self.rolloff * (distance - reference))
+    }
+}
+
+pub(crate) struct EmitterShared {
+    x: AtomicF32,
+    y: AtomicF32,
+    z: AtomicF32,
+
+    /// Computed by Audio::tick() from the position and the listener.
+    left: AtomicF32,
+    right: AtomicF32,
+}
+
+/// A position in the world that sounds can be played from, e.g. with
+/// Audio::play_at(). Clones refer to the same emitter, so moving it moves
+/// every sound playing from it.
+#[derive(Clone)]
+pub struct Emitter {
+    shared: Arc<EmitterShared>,
+}
+
+impl std::fmt::Debug for Emitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Emitter").field("position", &self.position()).finish()
+    }
+}
+
+impl Emitter {
+    pub fn new(position: Point3<f32>) -> Self {
+        Emitter {
+            shared: Arc::new(EmitterShared {
+                x: AtomicF32::new(position.x),
+                y: AtomicF32::new(position.y),
+                z: AtomicF32::new(position.z),
+
+                left: AtomicF32::new(1.0),
+                right: AtomicF32::new(1.0),
+            }),
+        }
+    }
+
+    pub fn position(&self) -> Point3<f32> {
+        Point3::new(self.shared.x.load(), self.shared.y.load(), self.shared.z.load())
+    }
+
+    /// Moves the emitter. Takes effect on the next tick.
+    pub fn set_position(&self, position: Point3<f32>) {
+        self.shared.x.store(position.x);
+        self.shared.y.store(position.y);
+        self.shared.z.store(position.z);
+    }
+
+    pub(crate) fn update(&self, listener: &Listener, attenuation: &Attenuation) {
+        update_gains(&self.shared, listener, attenuation);
+    }
+
+    pub(crate) fn downgrade(&self) -> Weak<EmitterShared> {
+        Arc::downgrade(&self.shared)
+    }
+}
+
+pub(crate) fn update_gains(shared: &EmitterShared, listener: &Listener, attenuation: &Attenuation) {
+    let position = Point3::new(shared.x.load(), shared.y.load(), shared.z.load());
+    let (left, right) = listener.gains(position, attenuation);
+    shared.left.store(left);
+    shared.right.store(right);
+}
+
+/// Mixes a source down to mono, and plays it in stereo with the gains of an
+/// Emitter.
+pub(crate) struct Spatialized<S> {
+    input: S,
+    shared: Arc<EmitterShared>,
+    input_channels: usize,
+
+    /// Smoothed towards the emitter's gains, so that moving doesn't click.
+    left: f32,
+    right: f32,
+    /// The right sample of the frame, once the left one has been returned.
+    pending: Option<f32>,
+}
+
+impl<S: Source> Spatialized<S> {
+    /// How far the gains move towards their targets every frame.
+    const SMOOTHING: f32 = 0.002;
+
+    pub(crate) fn new(input: S, emitter: &Emitter) -> Self {
+        let input_channels = input.channels().max(1) as usize;
+        let shared = emitter.shared.clone();
+        Spatialized {
+            input,
+            input_channels,
+            left: shared.left.load(),
+            right: shared.right.load(),
+            shared,
+            pending: None,
+        }
+    }
+}
+
+impl<S: Source> Iterator for Spatialized<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.pending.take() {
+            return Some(right);
+        }
+
+        let mut mono = self.input.next()?;
+        for _ in 1..self.input_channels {
+            mono += self.input.next().unwrap_or(0.0);
+        }
+        mono /= self.input_channels as f32;
+
+        self.left += (self.shared.left.load() - self.left) * Self::SMOOTHING;
+        self.right += (self.shared.right.load() - self.right) * Self::SMOOTHING;
+        self.pending = Some(mono * self.right);
+        Some(mono * self.left)
+    }
+}
+
+impl<S: Source> Source for Spatialized<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        2
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, _pos: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported { underlying_source: std::any::type_name::<Self>() })
+    }
+}
@@ -0,0 +1,73 @@
+// Streaming audio.
+//
+// Instead of decoding a whole file ahead of time, a streamed Sound or Track
+// keeps a way to open the file, and decodes it bit by bit on the audio thread
+// while it plays. Only the (compressed) file and the decoder's buffers are in
+// memory, which matters for long music on the web.
+
+use std::{io::{Read, Seek, SeekFrom}, sync::Arc, time::Duration};
+
+use rodio::{decoder::{DecoderBuilder, LoopedDecoder}, ChannelCount, Decoder, SampleRate, Source};
+
+use crate::error::{EngineError, EngineResult};
+
+/// Anything a stream can be decoded from: a file, embedded bytes in a Cursor,
+/// or a reader into an asset pack.
+pub trait ReadSeek: Read + Seek + Send + Sync {}
+
+impl<T: Read + Seek + Send + Sync> ReadSeek for T {}
+
+type Open = dyn Fn() -> std::io::Result<Box<dyn ReadSeek>> + Send + Sync;
+
+/// Opens a new reader over the same file every time the audio plays, so that
+/// several voices can stream it at once.
+#[derive(Clone)]
+pub(crate) struct Stream {
+    open: Arc<Open>,
+
+    pub(crate) channels: ChannelCount,
+    pub(crate) sample_rate: SampleRate,
+    /// None if the format doesn't say how long it is.
+    pub(crate) duration: Option<Duration>,
+}
+
+impl Stream {
+    /// Opens the stream once to check that it can be decoded, and to find its
+    /// format.
+    pub(crate) fn new<R: ReadSeek + 'static>(open: impl Fn() -> std::io::Result<R> + Send + Sync + 'static) -> EngineResult<Self> {
+        let mut stream = Stream {
+            open: Arc::new(move || Ok(Box::new(open()?) as Box<dyn ReadSeek>)),
+            channels: 0,
+            sample_rate: 0,
+            duration: None,
+        };
+
+        let decoder = stream.decoder()?;
+        stream.channels = decoder.channels();
+        stream.sample_rate = decoder.sample_rate();
+        stream.duration = decoder.total_duration();
+        Ok(stream)
+    }
+
+    fn builder(&self) -> EngineResult<DecoderBuilder<Box<dyn ReadSeek>>> {
+        let mut reader = (self.open)()
+            .map_err(|err| EngineError::new(format!("failed to open audio stream: {}", err)))?;
+
+        // The decoder needs the length to seek reliably.
+        let byte_len = reader.seek(SeekFrom::End(0))
+            .and_then(|len| reader.rewind().map(|()| len))
+            .map_err(|err| EngineError::new(format!("failed to seek audio stream: {}", err)))?;
+
+        Ok(DecoderBuilder::new().with_data(reader).with_byte_len(byte_len))
+    }
+
+    pub(crate) fn decoder(&self) -> EngineResult<Decoder<Box<dyn ReadSeek>>> {
+        self.builder()?.build()
+            .map_err(|err| EngineError::AssetParse(format!("audio stream: {}", err)))
+    }
+
+    pub(crate) fn looped_decoder(&self) -> EngineResult<LoopedDecoder<Box<dyn ReadSeek>>> {
+        self.builder()?.build_looped()
+            .map_err(|err| EngineError::AssetParse(format!("audio stream: {}", err)))
+    }
+}
@@ -0,0 +1,191 @@
+// Procedural sounds.
+//
+// A Synth describes a tone: a waveform at a frequency, shaped by an ADSR
+// envelope. It is generated on the audio thread as it plays, so it costs no
+// memory, and can be played through Audio::play_synth() like a Sound. The
+// pitch can be changed while it plays with Voice::set_speed().
+
+use std::time::Duration;
+
+use rodio::{source::SeekError, ChannelCount, SampleRate, Source};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Square,
+    /// White noise. Ignores the frequency.
+    Noise,
+}
+
+/// Shapes the volume of a Synth over time. All times are in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Envelope {
+    /// How long it takes to rise from silence to full volume.
+    pub attack: f32,
+    /// How long it takes to fall from full volume to the sustain level.
+    pub decay: f32,
+    /// The volume while the note is held, from 0 to 1.
+    pub sustain: f32,
+    /// How long it takes to fall to silence once the note is let go.
+    pub release: f32,
+}
+
+impl Default for Envelope {
+    /// Just long enough to not click.
+    fn default() -> Self {
+        Envelope { attack: 0.005, decay: 0.0, sustain: 1.0, release: 0.005 }
+    }
+}
+
+impl Envelope {
+    /// The gain while the note is held, `t` seconds after it started.
+    fn held(&self, t: f32) -> f32 {
+        if t < self.attack {
+            t / self.attack
+        }
+        else if t < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+        }
+        else {
+            self.sustain
+        }
+    }
+
+    /// The gain `t` seconds after the note started, if it is let go after
+    /// `hold` seconds. None once the release is over.
+    fn gain(&self, t: f32, hold: Option<f32>) -> Option<f32> {
+        let Some(hold) = hold.filter(|hold| t >= *hold) else {
+            return Some(self.held(t));
+        };
+
+        let since_release = t - hold;
+        if since_release >= self.release {
+            return None;
+        }
+        Some(self.held(hold) * (1.0 - since_release / self.release))
+    }
+}
+
+/// A procedurally generated tone, e.g.
+///
+/// let blip = Synth::new(Waveform::Square, 880.0)
+///     .with_envelope(Envelope { attack: 0.0, decay: 0.08, sustain: 0.0, release: 0.0 })
+///     .with_hold(0.08);
+#[derive(Clone, Copy, Debug)]
+pub struct Synth {
+    pub waveform: Waveform,
+    /// In Hz.
+    pub frequency: f32,
+    pub volume: f32,
+    pub envelope: Envelope,
+    /// How long the note is held before it is let go, in seconds. None holds
+    /// it until the Voice is stopped or faded out.
+    pub hold: Option<f32>,
+}
+
+impl Synth {
+    pub fn new(waveform: Waveform, frequency: f32) -> Self {
+        Synth {
+            waveform,
+            frequency,
+            volume: 1.0,
+            envelope: Envelope::default(),
+            hold: None,
+        }
+    }
+
+    pub fn with_envelope(mut self, envelope: Envelope) -> Self {
+        self.envelope = envelope;
+        self
+    }
+
+    pub fn with_hold(mut self, seconds: f32) -> Self {
+        self.hold = Some(seconds);
+        self
+    }
+
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    pub(crate) fn source(&self, seed: u32) -> SynthSource {
+        SynthSource { synth: *self, phase: 0.0, frame: 0, noise: XorShift::new(seed) }
+    }
+}
+
+/// A small random number generator, for noise and pitch variation. Not good
+/// enough for anything else.
+pub(crate) struct XorShift(u32);
+
+impl XorShift {
+    pub(crate) fn new(seed: u32) -> Self {
+        // Zero is the one state that xorshift never leaves.
+        XorShift(seed.max(1))
+    }
+
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// Returns a number from 0 up to 1.
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+pub(crate) struct SynthSource {
+    synth: Synth,
+    /// How far through the current period the waveform is, from 0 to 1.
+    phase: f32,
+    frame: u64,
+    noise: XorShift,
+}
+
+impl SynthSource {
+    const SAMPLE_RATE: SampleRate = 44100;
+}
+
+impl Iterator for SynthSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let t = self.frame as f32 / Self::SAMPLE_RATE as f32;
+        let gain = self.synth.envelope.gain(t, self.synth.hold)?;
+        self.frame += 1;
+
+        let value = match self.synth.waveform {
+            Waveform::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Noise => self.noise.next_f32() * 2.0 - 1.0,
+        };
+        self.phase = (self.phase + self.synth.frequency / Self::SAMPLE_RATE as f32).fract();
+
+        Some(value * gain * self.synth.volume)
+    }
+}
+
+impl Source for SynthSource {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        1
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        Self::SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.synth.hold.map(|hold| Duration::from_secs_f32(hold + self.synth.envelope.release))
+    }
+
+    fn try_seek(&mut self, _pos: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported { underlying_source: std::any::type_name::<Self>() })
+    }
+}
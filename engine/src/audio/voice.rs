@@ -0,0 +1,327 @@
+// Voices: handles to sounds that are playing.
+//
+// Every sound that is played is wrapped in a VoiceSource, which the audio
+// thread pulls samples from. The Voice handle on the game thread controls it
+// through VoiceShared, the same way Bus controls a BusOutput.
+
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+
+use rodio::{source::SeekError, ChannelCount, SampleRate, Source};
+
+use crate::audio::{bus::AtomicF32, effects::Effect, spatial::Emitter};
+
+pub(crate) struct VoiceShared {
+    volume: AtomicF32,
+    speed: AtomicF32,
+    paused: AtomicBool,
+    stopped: AtomicBool,
+    finished: AtomicBool,
+
+    /// Where the fade gain is heading, and how fast, per second.
+    fade_target: AtomicF32,
+    fade_rate: AtomicF32,
+    /// Set by fade_in(), and cleared by the audio thread once it has dropped
+    /// the fade gain to 0.
+    fade_from_silence: AtomicBool,
+    /// Whether the voice stops once the fade reaches silence.
+    stop_after_fade: AtomicBool,
+}
+
+/// A handle to a playing sound. Clones refer to the same sound. Dropping the
+/// handle does not stop the sound.
+#[derive(Clone)]
+pub struct Voice {
+    shared: Arc<VoiceShared>,
+}
+
+impl Voice {
+    pub(crate) fn new(params: &PlayParams) -> Self {
+        Voice {
+            shared: Arc::new(VoiceShared {
+                volume: AtomicF32::new(params.volume),
+                speed: AtomicF32::new(params.speed),
+                paused: AtomicBool::new(false),
+                stopped: AtomicBool::new(false),
+                finished: AtomicBool::new(false),
+
+                fade_target: AtomicF32::new(1.0),
+                fade_rate: AtomicF32::new(if params.fade_in > 0.0 { 1.0 / params.fade_in } else { 0.0 }),
+                fade_from_silence: AtomicBool::new(params.fade_in > 0.0),
+                stop_after_fade: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// A Voice for a sound that never played, e.g. because there is no audio
+    /// backend.
+    pub(crate) fn finished() -> Self {
+        let voice = Voice::new(&PlayParams::default());
+        voice.shared.finished.store(true, Ordering::Relaxed);
+        voice
+    }
+
+    /// Stops the sound right away. It can't be started again.
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.shared.volume.load()
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.shared.volume.store(volume.max(0.0));
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.shared.speed.load()
+    }
+
+    /// Changes the playback speed, which also changes the pitch.
+    pub fn set_speed(&self, speed: f32) {
+        self.shared.speed.store(speed.max(0.0));
+    }
+
+    /// Drops the sound to silence, and brings it back up over the given number
+    /// of seconds.
+    pub fn fade_in(&self, seconds: f32) {
+        self.shared.stop_after_fade.store(false, Ordering::Relaxed);
+        self.shared.fade_target.store(1.0);
+        self.shared.fade_rate.store(if seconds > 0.0 { 1.0 / seconds } else { 0.0 });
+        self.shared.fade_from_silence.store(seconds > 0.0, Ordering::Relaxed);
+    }
+
+    /// Fades the sound to silence over the given number of seconds, and then
+    /// stops it.
+    pub fn fade_out(&self, seconds: f32) {
+        if seconds <= 0.0 {
+            self.stop();
+            return;
+        }
+        self.shared.fade_target.store(0.0);
+        self.shared.fade_rate.store(1.0 / seconds);
+        self.shared.stop_after_fade.store(true, Ordering::Relaxed);
+    }
+
+    /// True once the sound has played to its end or was stopped.
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Relaxed)
+    }
+}
+
+/// How to play a Sound. Use with Audio::play_with(), e.g.
+///
+/// engine.audio.play_with(&sound, PlayParams { speed: 1.2, ..Default::default() });
+#[derive(Clone, Copy, Debug)]
+pub struct PlayParams<'a> {
+    pub bus: &'a str,
+    pub volume: f32,
+    pub speed: f32,
+    /// Plays the sound over and over until it is stopped.
+    pub looping: bool,
+    /// Fades in from silence over this many seconds. 0 starts at full volume.
+    pub fade_in: f32,
+    /// Plays the sound from the emitter's position, panned and attenuated
+    /// for the listener. None plays it in the middle.
+    pub emitter: Option<&'a Emitter>,
+    /// Effects to run the sound through, in order.
+    pub effects: &'a [Effect],
+}
+
+impl Default for PlayParams<'static> {
+    fn default() -> Self {
+        PlayParams {
+            bus: super::Audio::SFX,
+            volume: 1.0,
+            speed: 1.0,
+            looping: false,
+            fade_in: 0.0,
+            emitter: None,
+            effects: &[],
+        }
+    }
+}
+
+/// Plays a source under the control of a Voice. Resamples by linear
+/// interpolation, so that the speed can change while playing.
+pub(crate) struct VoiceSource<S> {
+    input: S,
+    shared: Arc<VoiceShared>,
+    channels: usize,
+    sample_rate: SampleRate,
+
+    /// The two input frames that the output is between, `position` of the
+    /// way from `current` to `next`.
+    current: Vec<f32>,
+    next: Vec<f32>,
+    position: f32,
+    /// Whether `next` holds a frame of the input. None until the first
+    /// frames are read.
+    has_next: Option<bool>,
+
+    output: Vec<f32>,
+    channel: usize,
+
+    /// Smoothed towards the volume, so that changes don't click.
+    gain: f32,
+    fade_gain: f32,
+}
+
+impl<S: Source> VoiceSource<S> {
+    /// How far the gain moves towards the volume every frame.
+    const SMOOTHING: f32 = 0.002;
+
+    pub(crate) fn new(input: S, voice: &Voice) -> Self {
+        let channels = input.channels() as usize;
+        let sample_rate = input.sample_rate();
+        VoiceSource {
+            input,
+            shared: voice.shared.clone(),
+            channels,
+            sample_rate,
+
+            current: vec![0.0; channels],
+            next: vec![0.0; channels],
+            position: 0.0,
+            has_next: None,
+
+            output: vec![0.0; channels],
+            channel: 0,
+
+            gain: voice.volume(),
+            fade_gain: 1.0,
+        }
+    }
+
+    /// Reads a frame into `next`. Returns false if the input has ended.
+    fn read_frame(&mut self) -> bool {
+        for (idx, sample) in self.next.iter_mut().enumerate() {
+            match self.input.next() {
+                Some(value) => *sample = value,
+                None if idx == 0 => return false,
+                // The input ended in the middle of a frame.
+                None => *sample = 0.0,
+            }
+        }
+        true
+    }
+
+    /// Computes the next output frame. Returns false once the voice is done.
+    fn next_frame(&mut self) -> bool {
+        if self.shared.stopped.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        if self.has_next.is_none() {
+            if !self.read_frame() {
+                return false;
+            }
+            std::mem::swap(&mut self.current, &mut self.next);
+            let has_next = self.read_frame();
+            self.has_next = Some(has_next);
+        }
+
+        let shared = &self.shared;
+        if shared.paused.load(Ordering::Relaxed) {
+            self.output.fill(0.0);
+            return true;
+        }
+
+        if shared.fade_from_silence.swap(false, Ordering::Relaxed) {
+            self.fade_gain = 0.0;
+        }
+        let fade_step = shared.fade_rate.load() / self.sample_rate as f32;
+        let fade_target = shared.fade_target.load();
+        self.fade_gain = if self.fade_gain < fade_target {
+            (self.fade_gain + fade_step).min(fade_target)
+        }
+        else {
+            (self.fade_gain - fade_step).max(fade_target)
+        };
+        if self.fade_gain <= 0.0 && shared.stop_after_fade.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        self.gain += (shared.volume.load() - self.gain) * Self::SMOOTHING;
+        let gain = self.gain * self.fade_gain;
+        for (idx, out) in self.output.iter_mut().enumerate() {
+            let (a, b) = (self.current[idx], self.next[idx]);
+            *out = (a + (b - a) * self.position) * gain;
+        }
+
+        self.position += self.shared.speed.load();
+        while self.position >= 1.0 {
+            // The last frame was already played.
+            if self.has_next != Some(true) {
+                return false;
+            }
+            self.position -= 1.0;
+            std::mem::swap(&mut self.current, &mut self.next);
+            let has_next = self.read_frame();
+            if !has_next {
+                self.next.copy_from_slice(&self.current);
+            }
+            self.has_next = Some(has_next);
+        }
+        true
+    }
+}
+
+impl<S> Drop for VoiceSource<S> {
+    fn drop(&mut self) {
+        self.shared.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<S: Source> Iterator for VoiceSource<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.shared.finished.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        if self.channel == 0 && !self.next_frame() {
+            self.shared.finished.store(true, Ordering::Relaxed);
+            return None;
+        }
+
+        let sample = self.output[self.channel];
+        self.channel = (self.channel + 1) % self.channels;
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for VoiceSource<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels as ChannelCount
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, _pos: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported { underlying_source: std::any::type_name::<Self>() })
+    }
+}
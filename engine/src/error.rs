@@ -1,21 +1,94 @@
-pub struct EngineError {
-    // TODO????
+#[derive(Debug)]
+pub enum EngineError {
+    Image(image::ImageError),
+    BufferAsync(wgpu::BufferAsyncError),
+    Poll(wgpu::PollError),
 
-    // For now, we'll just use a message string. We might swap this out
-    // for more structured things later.
-    message: String,
+    /// An asset file could not be parsed at all.
+    AssetParse(String),
+    /// A mesh is missing a vertex attribute that can't be generated, e.g. its
+    /// positions.
+    MissingAttribute {
+        mesh: String,
+        attribute: &'static str,
+    },
+    /// A face with fewer than three indices, i.e. a point or line. Faces with
+    /// more are triangulated instead.
+    NonTriangleFace {
+        mesh: String,
+        index_count: usize,
+    },
+    /// A mesh that was asked for by name doesn't exist in the asset.
+    MeshNotFound(String),
+
+    /// A line of a config file (e.g. an input binding file) that couldn't be
+    /// parsed. Lines are numbered from 1.
+    Config {
+        line: usize,
+        message: String,
+    },
+
+    /// Anything else, with a message.
+    Other(String),
+}
+
+impl EngineError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self::Other(message.into())
+    }
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Image(err) => write!(f, "image error: {err}"),
+            EngineError::BufferAsync(err) => write!(f, "buffer mapping failed: {err}"),
+            EngineError::Poll(err) => write!(f, "device poll failed: {err}"),
+            EngineError::AssetParse(message) => write!(f, "failed to parse asset: {message}"),
+            EngineError::MissingAttribute { mesh, attribute } =>
+                write!(f, "mesh '{mesh}' is missing its {attribute}"),
+            EngineError::NonTriangleFace { mesh, index_count } =>
+                write!(f, "mesh '{mesh}' has a face with only {index_count} indices"),
+            EngineError::MeshNotFound(name) => write!(f, "mesh '{name}' not found"),
+            EngineError::Config { line, message } => write!(f, "config error on line {line}: {message}"),
+            EngineError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::Image(err) => Some(err),
+            EngineError::BufferAsync(err) => Some(err),
+            EngineError::Poll(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<wgpu::BufferAsyncError> for EngineError {
+    fn from(value: wgpu::BufferAsyncError) -> Self {
+        Self::BufferAsync(value)
+    }
+}
+
+impl From<wgpu::PollError> for EngineError {
+    fn from(value: wgpu::PollError) -> Self {
+        Self::Poll(value)
+    }
 }
 
 impl From<image::ImageError> for EngineError {
     fn from(value: image::ImageError) -> Self {
-        Self { message: format!("{value}") }
+        Self::Image(value)
     }
 }
 
-pub type EngineResult<T> = Result<T, EngineError>;
-
-impl std::fmt::Debug for EngineError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.message)
+impl From<gltf::Error> for EngineError {
+    fn from(value: gltf::Error) -> Self {
+        Self::AssetParse(format!("{value}"))
     }
-}
\ No newline at end of file
+}
+
+pub type EngineResult<T> = Result<T, EngineError>;
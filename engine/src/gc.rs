@@ -1,26 +1,254 @@
 // Integration with the PonieScript GC.
+//
+// All allocations made through Gp::new are owned by a thread-local Heap. The
+// collector is a simple non-moving mark & sweep: it only runs when collect()
+// is called explicitly, marks everything reachable from the registered roots
+// (plus whatever extra roots are passed in), and then drops everything else.
+//
+// Because Gp's may live anywhere (on the stack, inside plain Rust structs,
+// etc.), the collector cannot find them on its own. Anything that is still in
+// use when collect() is called MUST be reachable from the roots, or it will be
+// freed out from under us.
 
-use std::{ops::Deref, ptr, sync::atomic::{AtomicPtr, AtomicU64, Ordering}};
+use std::{cell::RefCell, collections::HashMap, ops::Deref, ptr, sync::atomic::{AtomicPtr, AtomicU64, Ordering}};
 
-pub trait Gc {
+/// Implemented by anything that may contain Gp's. Implementations must call
+/// trace() on every field that (transitively) holds a Gp or GpMaybe.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+pub trait Gc: Trace {
     const TYPE_ID: u64;
 }
 
+/// Implements Trace for the given type by tracing each of the listed fields.
+#[macro_export]
+macro_rules! gc_trace {
+    ($typ:ty, [$($field:ident),* $(,)?]) => {
+        impl $crate::gc::Trace for $typ {
+            #[allow(unused_variables)]
+            fn trace(&self, tracer: &mut $crate::gc::Tracer) {
+                $( $crate::gc::Trace::trace(&self.$field, tracer); )*
+            }
+        }
+    };
+}
+
+/// Registers the given type with the GC. The optional list of fields is the
+/// set of fields that may contain Gp's, and will be traced by the collector.
 #[macro_export]
 macro_rules! gc {
     ($typ:ty, $typ_id:expr) => {
-        impl crate::gc::Gc for $typ {
+        $crate::gc!($typ, $typ_id, []);
+    };
+    ($typ:ty, $typ_id:expr, [$($field:ident),* $(,)?]) => {
+        impl $crate::gc::Gc for $typ {
             const TYPE_ID: u64 = $typ_id;
         }
+
+        $crate::gc_trace!($typ, [$($field),*]);
     };
 }
 
+/// The top bit of the header is used as the mark bit. The rest of the header
+/// is the TYPE_ID of the value.
+const MARK_BIT: u64 = 1 << 63;
+const TYPE_ID_MASK: u64 = !MARK_BIT;
+
 #[repr(C)]
 pub struct GcValue<T> {
     header: AtomicU64,
     inner: T,
 }
 
+/// Type-erased operations for a single TYPE_ID. The collector looks these up
+/// based on the header of each GcValue.
+#[derive(Clone, Copy)]
+struct GcVTable {
+    type_name: &'static str,
+    trace: unsafe fn(*const AtomicU64, &mut Tracer),
+    drop: unsafe fn(*mut AtomicU64),
+}
+
+unsafe fn trace_erased<T: Gc>(header: *const AtomicU64, tracer: &mut Tracer) {
+    // SAFETY: GcValue is repr(C) with the header as its first field, and we
+    // were looked up by T::TYPE_ID, so the header belongs to a GcValue<T>.
+    let value = unsafe { &*(header as *const GcValue<T>) };
+    value.inner.trace(tracer);
+}
+
+unsafe fn drop_erased<T: Gc>(header: *mut AtomicU64) {
+    // SAFETY: See trace_erased. Every GcValue is allocated with Box::new.
+    drop(unsafe { Box::from_raw(header as *mut GcValue<T>) });
+}
+
+struct Heap {
+    vtables: HashMap<u64, GcVTable>,
+    /// Every live allocation, as a pointer to its header.
+    objects: Vec<*mut AtomicU64>,
+    /// Long-lived roots, along with how many times they have been registered.
+    roots: HashMap<*const AtomicU64, usize>,
+}
+
+impl Heap {
+    fn new() -> Self {
+        Heap {
+            vtables: HashMap::new(),
+            objects: Vec::new(),
+            roots: HashMap::new(),
+        }
+    }
+
+    fn register<T: Gc>(&mut self, header: *mut AtomicU64) {
+        let vtable = self.vtables.entry(T::TYPE_ID).or_insert(GcVTable {
+            type_name: std::any::type_name::<T>(),
+            trace: trace_erased::<T>,
+            drop: drop_erased::<T>,
+        });
+
+        // Two different types sharing a TYPE_ID would make the collector
+        // trace and drop values as the wrong type.
+        assert!(vtable.type_name == std::any::type_name::<T>(),
+            "gc: TYPE_ID {:#x} is used by both {} and {}",
+            T::TYPE_ID, vtable.type_name, std::any::type_name::<T>());
+
+        self.objects.push(header);
+    }
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::new());
+}
+
+/// Passed to Trace::trace. Keeps track of the objects that have been marked,
+/// but not yet traced.
+pub struct Tracer {
+    gray: Vec<*const AtomicU64>,
+    /// Set when something couldn't be traced, e.g. a RefCell that is mutably
+    /// borrowed. Anything it holds may be live, so nothing can be freed.
+    incomplete: bool,
+}
+
+impl Tracer {
+    fn mark_header(&mut self, header: *const AtomicU64) {
+        if header.is_null() { return; }
+
+        // SAFETY: Non-null Gp's always point to a live GcValue.
+        let prev = unsafe { &*header }.fetch_or(MARK_BIT, Ordering::Relaxed);
+        if prev & MARK_BIT == 0 {
+            self.gray.push(header);
+        }
+    }
+
+    pub fn mark<T: Gc>(&mut self, gp: &Gp<T>) {
+        self.mark_header(gp.ptr.load(Ordering::Relaxed) as *const AtomicU64);
+    }
+
+    pub fn mark_maybe<T: Gc>(&mut self, gp: &GpMaybe<T>) {
+        self.mark_header(gp.ptr.load(Ordering::Relaxed) as *const AtomicU64);
+    }
+}
+
+/// Registers the given object as a root. Roots are never collected. Each call
+/// must be balanced by a call to remove_root.
+pub fn add_root<T: Gc>(gp: &Gp<T>) {
+    let header = gp.ptr.load(Ordering::Relaxed) as *const AtomicU64;
+    HEAP.with_borrow_mut(|heap| *heap.roots.entry(header).or_insert(0) += 1);
+}
+
+pub fn remove_root<T: Gc>(gp: &Gp<T>) {
+    let header = gp.ptr.load(Ordering::Relaxed) as *const AtomicU64;
+    HEAP.with_borrow_mut(|heap| {
+        if let Some(count) = heap.roots.get_mut(&header) {
+            *count -= 1;
+            if *count == 0 {
+                heap.roots.remove(&header);
+            }
+        }
+    });
+}
+
+/// Returns the number of objects currently owned by the heap.
+pub fn allocated_count() -> usize {
+    HEAP.with_borrow(|heap| heap.objects.len())
+}
+
+/// Frees every object that is not reachable from either the registered roots
+/// or the given extra roots. Returns the number of objects that were freed.
+///
+/// Any Gp that is not reachable from a root is dangling after this call.
+///
+/// If a traced RefCell is mutably borrowed, e.g. because collect() was called
+/// from inside a borrow_mut(), its contents can't be traced. The collection is
+/// then skipped, and 0 is returned.
+pub fn collect(extra_roots: &[&dyn Trace]) -> usize {
+    let mut tracer = Tracer { gray: Vec::new(), incomplete: false };
+
+    // Copy out the vtables so that we aren't borrowing the heap while calling
+    // into arbitrary trace() and drop() code.
+    let vtables = HEAP.with_borrow(|heap| {
+        for root in heap.roots.keys() {
+            tracer.mark_header(*root);
+        }
+        heap.vtables.clone()
+    });
+
+    for root in extra_roots {
+        root.trace(&mut tracer);
+    }
+
+    let lookup = |header: *const AtomicU64| -> GcVTable {
+        // SAFETY: Everything on the gray stack / in the heap is live.
+        let type_id = unsafe { &*header }.load(Ordering::Relaxed) & TYPE_ID_MASK;
+        *vtables.get(&type_id).expect("gc: value with unregistered TYPE_ID")
+    };
+
+    while let Some(header) = tracer.gray.pop() {
+        let vtable = lookup(header);
+        unsafe { (vtable.trace)(header, &mut tracer) };
+    }
+
+    if tracer.incomplete {
+        log::warn!("gc: a traced RefCell is mutably borrowed, skipping the collection");
+        HEAP.with_borrow(|heap| {
+            for &header in &heap.objects {
+                // SAFETY: Everything in the heap is live.
+                unsafe { &*header }.fetch_and(TYPE_ID_MASK, Ordering::Relaxed);
+            }
+        });
+        return 0;
+    }
+
+    let garbage = HEAP.with_borrow_mut(|heap| {
+        let mut garbage = Vec::new();
+        heap.objects.retain(|&header| {
+            let header_ref = unsafe { &*header };
+            let value = header_ref.load(Ordering::Relaxed);
+            if value & MARK_BIT != 0 {
+                // Clear the mark for the next collection.
+                header_ref.store(value & TYPE_ID_MASK, Ordering::Relaxed);
+                true
+            }
+            else {
+                garbage.push(header);
+                false
+            }
+        });
+        garbage
+    });
+
+    let freed = garbage.len();
+    for header in garbage {
+        let vtable = lookup(header);
+        unsafe { (vtable.drop)(header) };
+    }
+
+    log::info!("gc: freed {} objects, {} remaining", freed, allocated_count());
+
+    freed
+}
+
 // Garbage-collected pointer.
 pub struct Gp<T: Gc> {
     // TODO: Is there any way to make this a NonNull?
@@ -33,17 +261,18 @@ pub struct GpMaybe<T: Gc> {
 
 impl<T: Gc> Gp<T> {
     pub fn new(t: T) -> Gp<T> {
-        // TODO: We want to actually do the allocation through the GC. For now,
-        // let's just leak a box.
+        debug_assert!(T::TYPE_ID & MARK_BIT == 0);
+
         let value = Box::new(GcValue {
             header: AtomicU64::new(T::TYPE_ID),
             inner: t
         });
 
-        let as_static_ref = Box::leak(value);
+        let ptr = Box::into_raw(value);
+        HEAP.with_borrow_mut(|heap| heap.register::<T>(ptr as *mut AtomicU64));
 
         Gp {
-            ptr: AtomicPtr::new(as_static_ref as *mut GcValue<T>)
+            ptr: AtomicPtr::new(ptr)
         }
     }
 
@@ -79,7 +308,7 @@ impl<T: Gc> GpMaybe<T> {
         match other {
             Some(other) => self.ptr.store(other.ptr.load(Ordering::Relaxed), Ordering::Relaxed),
             None => self.ptr.store(ptr::null_mut::<GcValue<T>>(), Ordering::Relaxed)
-        }   
+        }
     }
 
     #[inline(always)]
@@ -118,4 +347,191 @@ impl<T: Gc> Clone for GpMaybe<T> {
     fn clone(&self) -> Self {
         Self { ptr: AtomicPtr::new(self.ptr.load(Ordering::Relaxed)) }
     }
-}
\ No newline at end of file
+}
+
+impl<T: Gc> Trace for Gp<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self);
+    }
+}
+
+impl<T: Gc> Trace for GpMaybe<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark_maybe(self);
+    }
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(inner) = self { inner.trace(tracer); }
+    }
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for item in self { item.trace(tracer); }
+    }
+}
+
+impl<T: Trace, const N: usize> Trace for [T; N] {
+    fn trace(&self, tracer: &mut Tracer) {
+        for item in self { item.trace(tracer); }
+    }
+}
+
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.try_borrow() {
+            Ok(inner) => inner.trace(tracer),
+            Err(_) => tracer.incomplete = true,
+        }
+    }
+}
+
+impl<K, V: Trace> Trace for HashMap<K, V> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values() { value.trace(tracer); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::{Cell, RefCell}, rc::Rc};
+
+    use super::{add_root, allocated_count, collect, remove_root, Gp};
+
+    // The heap is thread-local and every test runs on its own thread, so the
+    // tests can't see each other's objects.
+
+    /// Counts how many times it has been dropped.
+    struct Leaf {
+        drops: Rc<Cell<usize>>,
+    }
+
+    impl Drop for Leaf {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    struct Holder {
+        leaf: Gp<Leaf>,
+    }
+
+    struct Node {
+        option: Option<Gp<Leaf>>,
+        vec: Vec<Gp<Leaf>>,
+        cell: RefCell<Option<Gp<Node>>>,
+    }
+
+    crate::gc!(Leaf, 0x7E57_0001_u64);
+    crate::gc!(Holder, 0x7E57_0002_u64, [leaf]);
+    crate::gc!(Node, 0x7E57_0003_u64, [option, vec, cell]);
+
+    fn leaf(drops: &Rc<Cell<usize>>) -> Gp<Leaf> {
+        Gp::new(Leaf { drops: drops.clone() })
+    }
+
+    fn node(option: Option<Gp<Leaf>>, vec: Vec<Gp<Leaf>>) -> Gp<Node> {
+        Gp::new(Node { option, vec, cell: RefCell::new(None) })
+    }
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let drops = Rc::new(Cell::new(0));
+        for _ in 0..3 {
+            leaf(&drops);
+        }
+        assert_eq!(allocated_count(), 3);
+
+        assert_eq!(collect(&[]), 3);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(allocated_count(), 0);
+
+        // Nothing is left to free.
+        assert_eq!(collect(&[]), 0);
+    }
+
+    #[test]
+    fn traced_fields_keep_objects() {
+        let drops = Rc::new(Cell::new(0));
+
+        let holder = Gp::new(Holder { leaf: leaf(&drops) });
+        let root = node(Some(leaf(&drops)), vec![leaf(&drops), leaf(&drops)]);
+        let inner = node(None, vec![leaf(&drops)]);
+        *root.cell.borrow_mut() = Some(inner);
+        assert_eq!(allocated_count(), 8);
+
+        assert_eq!(collect(&[&holder, &root]), 0);
+        assert_eq!(drops.get(), 0);
+
+        // Every object survives any number of collections, with the mark bits
+        // cleared in between.
+        assert_eq!(collect(&[&holder, &root]), 0);
+        assert_eq!(root.vec.len(), 2);
+        assert_eq!(root.cell.borrow().as_ref().unwrap().vec.len(), 1);
+
+        // Without the node, only the holder and its leaf are reachable.
+        assert_eq!(collect(&[&holder]), 6);
+        assert_eq!(drops.get(), 4);
+        assert_eq!(allocated_count(), 2);
+        assert_eq!(holder.leaf.drops.get(), 4);
+    }
+
+    #[test]
+    fn roots_are_respected() {
+        let drops = Rc::new(Cell::new(0));
+        let rooted = leaf(&drops);
+
+        add_root(&rooted);
+        add_root(&rooted);
+        assert_eq!(collect(&[]), 0);
+
+        // Still registered once.
+        remove_root(&rooted);
+        assert_eq!(collect(&[]), 0);
+        assert_eq!(drops.get(), 0);
+
+        remove_root(&rooted);
+        assert_eq!(collect(&[]), 1);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn cycles_are_collected() {
+        let drops = Rc::new(Cell::new(0));
+
+        let a = node(Some(leaf(&drops)), Vec::new());
+        let b = node(Some(leaf(&drops)), Vec::new());
+        *a.cell.borrow_mut() = Some(b.clone());
+        *b.cell.borrow_mut() = Some(a.clone());
+
+        // Reachable through either end of the cycle.
+        assert_eq!(collect(&[&b]), 0);
+
+        assert_eq!(collect(&[]), 4);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(allocated_count(), 0);
+    }
+
+    #[test]
+    fn borrowed_refcells_skip_the_collection() {
+        let drops = Rc::new(Cell::new(0));
+
+        let root = node(None, Vec::new());
+        *root.cell.borrow_mut() = Some(node(Some(leaf(&drops)), Vec::new()));
+        leaf(&drops);
+
+        {
+            // The inner node can't be traced, so it might still be in use.
+            let _borrow = root.cell.borrow_mut();
+            assert_eq!(collect(&[&root]), 0);
+            assert_eq!(allocated_count(), 4);
+        }
+
+        // Once the borrow is released, the collection goes ahead as normal.
+        assert_eq!(collect(&[&root]), 1);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(allocated_count(), 3);
+    }
+}
@@ -1,14 +1,24 @@
-use crate::gc;
+use crate::{gc, gc_trace};
 
-gc!(crate::video::world::World,    0xF0000000_u64);
-gc!(crate::video::world::Viewport, 0xF0000001_u64);
+gc!(crate::video::world::World,    0xF0000000_u64, [envmap, sun, lights, meshes]);
+gc!(crate::video::world::Viewport, 0xF0000001_u64, [world, camera, last_envmap, queue, shadow_batches]);
 gc!(crate::video::camera::Camera,  0xF0000002_u64);
+gc!(crate::video::world::Light3D, 0xF0000009_u64);
 gc!(crate::video::texture::Texture, 0xF0000006_u64);
 
 gc!(crate::video::mesh_render_pipeline::Mesh, 0xF0000003_u64);
-gc!(crate::video::mesh_render_pipeline::MeshInstance, 0xF0000004_u64);
+gc!(crate::video::mesh_render_pipeline::MeshInstance, 0xF0000004_u64, [mesh, material, skin]);
 // gc!(crate::video::mesh_render_pipeline::Material, 0xF0000005_u64);
-gc!(crate::video::PBRMaterial, 0xF0000005_u64);
+gc_trace!(crate::video::mesh_render_pipeline::InstanceBatch, [mesh, material, skin]);
+gc_trace!(crate::video::render_queue::RenderQueue, [opaque, transparent]);
+
+gc_trace!(crate::video::asset_import::ScenePrimitive, [mesh, material]);
+gc_trace!(crate::video::asset_import::SceneMesh, [primitives]);
+gc_trace!(crate::video::asset_import::Scene, [meshes, materials]);
+gc!(crate::video::PBRMaterial, 0xF0000005_u64, [cached_bind_group, shader]);
 gc!(crate::video::PBRShader,   0xF0000008_u64);
 
+gc!(crate::video::animation::Skin, 0xF000000A_u64);
+gc_trace!(crate::video::animation::Animator, [skins]);
+
 gc!(wgpu::BindGroup, 0xE0000000_u64);
\ No newline at end of file
@@ -0,0 +1,130 @@
+// Support for running a Gameplay without any window, display or GPU, e.g. for
+// integration tests on CI machines.
+//
+// Instead of being driven by winit, the Headless runner is stepped manually,
+// one fixed-size tick at a time.
+
+use cgmath::Vector2;
+
+use crate::{audio::Audio, error::EngineResult, input::{gamepad::FakeGamepadBackend, replay::InputLog}, video::{HeadlessTarget, Video}, Engine, Gameplay};
+
+impl Engine {
+    pub const HEADLESS_SAMPLE_RATE: u32 = 48000;
+
+    /// Creates an Engine with no windows. The main world and camera render
+    /// into an offscreen Viewport with the given dimensions. Fails if there is
+    /// no graphics adapter, not even a software one.
+    pub fn new_headless<G: Gameplay>(dimensions: (u32, u32)) -> EngineResult<Engine> {
+        let video = Video::new_headless::<G>(dimensions)?;
+
+        let viewport = video.main_viewport();
+        let world = viewport.world.clone();
+        let camera = viewport.camera.clone();
+
+        // Audio is rendered into memory, in lock-step with the ticks, so tests
+        // can check what was played.
+        let audio = Audio::offline(Self::HEADLESS_SAMPLE_RATE);
+
+        let mut engine = Engine::from_parts(video, audio, world, camera);
+        // Real gamepads would make tests depend on what is plugged in. Tests
+        // that need one can set a FakeGamepadBackend.
+        engine.input.set_gamepad_backend(None);
+        Ok(engine)
+    }
+}
+
+pub struct Headless<G: Gameplay> {
+    pub engine: Engine,
+    pub gameplay: G,
+}
+
+impl<G: Gameplay> Headless<G> {
+    pub fn new(dimensions: (u32, u32)) -> EngineResult<Self> {
+        let mut engine = Engine::new_headless::<G>(dimensions)?;
+        let gameplay = G::new(&mut engine);
+
+        Ok(Headless { engine, gameplay })
+    }
+
+    /// Runs a single fixed-size tick.
+    pub fn step(&mut self) {
+        self.engine.step(&mut self.gameplay);
+    }
+
+    pub fn step_n(&mut self, count: usize) {
+        for _ in 0..count {
+            self.step();
+        }
+    }
+
+    /// Runs ticks until the given amount of simulated time has passed.
+    pub fn step_for(&mut self, duration: web_time::Duration) {
+        let ticks = duration.as_nanos() * Engine::TICKS_PER_SECOND as u128 / 1_000_000_000;
+        self.step_n(ticks as usize);
+    }
+
+    /// Moves the fake cursor, as returned by Engine::get_cursor_position.
+    pub fn set_cursor_position(&mut self, position: Vector2<f32>) {
+        if let Some(headless) = self.engine.video.headless.as_mut() {
+            headless.cursor_position = position;
+        }
+    }
+
+    /// Plays the whole log back, one step per recorded tick, and then goes back
+    /// to live input. Used for automated playthroughs. If the log names the
+    /// state it started from, Gameplay::replay_start() gets there first.
+    pub fn replay(&mut self, log: InputLog) {
+        if let Some(start) = log.start() {
+            self.gameplay.replay_start(&mut self.engine, start);
+        }
+        self.engine.input.start_replay(log);
+        while !self.engine.input.is_replay_finished() {
+            self.step();
+        }
+        self.engine.input.stop_replay();
+    }
+
+    /// Feeds a touch event in, as if it came from the window. The gestures
+    /// are recognized at the start of the next step().
+    pub fn touch(&mut self, id: u64, phase: winit::event::TouchPhase, position: Vector2<f32>) {
+        self.engine.input.update_touch(id, phase, position);
+    }
+
+    /// Replaces the gamepad backend with a fake one, and returns a handle for
+    /// pushing events into it.
+    pub fn fake_gamepads(&mut self) -> FakeGamepadBackend {
+        let backend = FakeGamepadBackend::new();
+        self.engine.input.set_gamepad_backend(Some(Box::new(backend.clone())));
+        backend
+    }
+
+    /// Renders the current state of the main world and reads back the
+    /// tonemapped result. Note that egui is not included.
+    pub fn render(&self) -> EngineResult<image::RgbaImage> {
+        let viewport = self.engine.get_viewport();
+        viewport.render_offscreen(&self.engine.video.renderer);
+        self.engine.render_ctx().read_viewport(viewport)
+    }
+
+    /// Returns an egui RawInput with no events, covering the whole viewport.
+    pub fn raw_input(&self) -> egui::RawInput {
+        let viewport = self.engine.get_viewport();
+        egui::RawInput {
+            screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO,
+                egui::vec2(viewport.width as f32, viewport.height as f32))),
+            ..Default::default()
+        }
+    }
+
+    /// Runs Gameplay::ui once with the given input, returning egui's output.
+    /// The ui gets HeadlessTarget::WINDOW_ID as its window.
+    pub fn run_ui(&mut self, raw_input: egui::RawInput) -> egui::FullOutput {
+        // Clone the context for borrow checker :)
+        let context = self.engine.video.headless.as_ref()
+            .expect("Headless engine has no headless target")
+            .egui.egui_ctx.clone();
+        context.run(raw_input, |ctx| {
+            self.gameplay.ui(&mut self.engine, HeadlessTarget::WINDOW_ID, ctx);
+        })
+    }
+}
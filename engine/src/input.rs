@@ -1,8 +1,16 @@
 use std::collections::{BTreeMap, HashMap};
 
-pub use winit::keyboard::KeyCode;
+use cgmath::{vec2, Vector2};
+pub use winit::keyboard::{KeyCode, ModifiersState};
 pub use winit::event::MouseButton;
 
+use crate::input::{actions::{ActionMap, Binding}, gamepad::{GamepadAxis, GamepadBackend, GamepadButton, GamepadEvent, GamepadId, GamepadState}, replay::{InputEvent, InputLog, Replay}, touch::{Drag, GestureKind, TouchPoint, TouchSettings, TouchTracker, TwoFinger}};
+
+pub mod actions;
+pub mod gamepad;
+pub mod replay;
+pub mod touch;
+
 struct ButtonStateTracker<T: Clone + Ord + PartialEq> {
     // TODO: Consider using Vec instead of BTreeMap
     current: BTreeMap<T, bool>,
@@ -39,6 +47,13 @@ impl<T: Clone + Ord + PartialEq> ButtonStateTracker<T> {
         }
     }
 
+    /// Every button that went down this tick.
+    pub fn just_pressed(&self) -> impl Iterator<Item = &T> {
+        self.current.iter()
+            .filter(|(k, v)| **v && !self.was_pressed(k))
+            .map(|(k, _)| k)
+    }
+
     pub fn update(&mut self, key: T, value: bool) {
         self.current.insert(key, value);
     }
@@ -48,15 +63,97 @@ impl<T: Clone + Ord + PartialEq> ButtonStateTracker<T> {
 pub(crate) enum AnyButton {
     PhysicalKey(winit::keyboard::PhysicalKey),
     Mouse(MouseButton),
+    /// Pressed while any finger is on the screen.
+    Touch,
+    Gamepad(GamepadId, GamepadButton),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+/// Lets a gamepad stick move the cursor returned by
+/// Engine::get_cursor_position, for menus and such that expect a mouse.
+#[derive(Clone, Copy, Debug)]
+pub struct CursorEmulation {
+    pub stick: Stick,
+    /// How fast the cursor moves with the stick fully tilted, in pixels per
+    /// second.
+    pub speed: f32,
 }
 
 pub struct Input {
-    tracker: ButtonStateTracker<AnyButton>
+    tracker: ButtonStateTracker<AnyButton>,
+    touch: TouchTracker,
+
+    pub actions: ActionMap,
+
+    gamepad_backend: Option<Box<dyn GamepadBackend>>,
+    gamepads: BTreeMap<GamepadId, GamepadState>,
+    /// Connects and disconnects since the last tick.
+    connection_events: Vec<GamepadEvent>,
+
+    /// Stick and trigger values below this are treated as 0.
+    pub dead_zone: f32,
+    pub cursor_emulation: Option<CursorEmulation>,
+
+    /// The cursor position at the start of this tick, and how far it moved
+    /// since the last one.
+    cursor_position: Vector2<f32>,
+    cursor_delta: Vector2<f32>,
+    cursor_inside: bool,
+    was_cursor_inside: bool,
+    /// Scrolling since the last tick, by the lines of a wheel with notches
+    /// and by the pixels of a touchpad.
+    scroll_lines: Vector2<f32>,
+    scroll_pixels: Vector2<f32>,
+    modifiers: ModifiersState,
+    /// Text typed since the last tick, including IME commits.
+    text: String,
+    ime_preedit: Option<String>,
+
+    /// Counts up once per tick. Reset when recording or replaying starts.
+    tick: u64,
+    recording: Option<InputLog>,
+    last_recorded_cursor: Option<Vector2<f32>>,
+    replay: Option<Replay>,
+    /// Whether any input from the window or gamepads was dropped this tick
+    /// because of a replay.
+    live_input_ignored: bool,
 }
 
 impl Input {
     pub fn new() -> Self {
-        Input { tracker: ButtonStateTracker::new() }
+        Input {
+            tracker: ButtonStateTracker::new(),
+            touch: TouchTracker::new(),
+            actions: ActionMap::new(),
+
+            gamepad_backend: gamepad::default_backend(),
+            gamepads: BTreeMap::new(),
+            connection_events: Vec::new(),
+
+            dead_zone: 0.15,
+            cursor_emulation: None,
+
+            cursor_position: vec2(0.0, 0.0),
+            cursor_delta: vec2(0.0, 0.0),
+            cursor_inside: false,
+            was_cursor_inside: false,
+            scroll_lines: vec2(0.0, 0.0),
+            scroll_pixels: vec2(0.0, 0.0),
+            modifiers: ModifiersState::empty(),
+            text: String::new(),
+            ime_preedit: None,
+
+            tick: 0,
+            recording: None,
+            last_recorded_cursor: None,
+            replay: None,
+            live_input_ignored: false,
+        }
     }
 
     pub fn is_physical_key_pressed(&self, code: KeyCode) -> bool {
@@ -91,11 +188,491 @@ impl Input {
         self.tracker.is_just_released(&AnyButton::Mouse(button))
     }
 
+    /// Whether the binding is held now, or at the end of the last tick if
+    /// `last` is true.
+    fn is_binding_pressed(&self, binding: Binding, last: bool) -> bool {
+        let check = |button: AnyButton| if last {
+            self.tracker.was_pressed(&button)
+        }
+        else {
+            self.tracker.is_pressed(&button)
+        };
+
+        match binding {
+            Binding::Key(code) => check(AnyButton::PhysicalKey(winit::keyboard::PhysicalKey::Code(code))),
+            Binding::Mouse(button) => check(AnyButton::Mouse(button)),
+            Binding::Touch => check(AnyButton::Touch),
+            // Active at the end of the last tick if it still is and didn't
+            // just start, or if it just ended.
+            Binding::Gesture(gesture) if last => (self.touch.is_active(gesture) && !self.touch.is_just_started(gesture))
+                || self.touch.is_just_ended(gesture),
+            Binding::Gesture(gesture) => self.touch.is_active(gesture),
+            Binding::Gamepad(button) => self.gamepads.keys().any(|id| check(AnyButton::Gamepad(*id, button))),
+        }
+    }
+
+    /// True while any of the action's bindings are held.
+    pub fn is_action_pressed(&self, action: &str) -> bool {
+        self.actions.bindings(action).iter()
+            .any(|binding| self.is_binding_pressed(*binding, false))
+    }
+
+    /// Whether the action was held at the end of the last tick.
+    pub fn was_action_pressed(&self, action: &str) -> bool {
+        self.actions.bindings(action).iter()
+            .any(|binding| self.is_binding_pressed(*binding, true))
+    }
+
+    /// True on the tick the action starts being held. Pressing a second binding
+    /// of an action that is already held does not count.
+    pub fn is_action_just_pressed(&self, action: &str) -> bool {
+        self.is_action_pressed(action) && !self.was_action_pressed(action)
+    }
+
+    pub fn is_action_just_released(&self, action: &str) -> bool {
+        self.was_action_pressed(action) && !self.is_action_pressed(action)
+    }
+
+    /// Some binding that went down this tick, if any. Useful for a "press a
+    /// key to rebind" menu.
+    pub fn just_pressed_binding(&self) -> Option<Binding> {
+        self.tracker.just_pressed().find_map(Binding::from_button)
+    }
+
+    /// Replaces the gamepad backend, e.g. with a FakeGamepadBackend for tests.
+    /// None disables gamepads entirely.
+    pub fn set_gamepad_backend(&mut self, backend: Option<Box<dyn GamepadBackend>>) {
+        self.gamepad_backend = backend;
+    }
+
+    pub fn connected_gamepads(&self) -> impl Iterator<Item = GamepadId> + '_ {
+        self.gamepads.keys().copied()
+    }
+
+    pub fn is_gamepad_connected(&self, id: GamepadId) -> bool {
+        self.gamepads.contains_key(&id)
+    }
+
+    /// The Connected and Disconnected events since the last tick.
+    pub fn gamepad_connection_events(&self) -> &[GamepadEvent] {
+        &self.connection_events
+    }
+
+    pub fn is_gamepad_button_pressed(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.tracker.is_pressed(&AnyButton::Gamepad(id, button))
+    }
+
+    pub fn was_gamepad_button_pressed(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.tracker.was_pressed(&AnyButton::Gamepad(id, button))
+    }
+
+    pub fn is_gamepad_button_just_pressed(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.tracker.is_just_pressed(&AnyButton::Gamepad(id, button))
+    }
+
+    pub fn is_gamepad_button_just_released(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.tracker.is_just_released(&AnyButton::Gamepad(id, button))
+    }
+
+    /// The value of the axis with the dead zone applied. Sticks use a round
+    /// dead zone, so prefer gamepad_stick() when reading both axes.
+    pub fn gamepad_axis(&self, id: GamepadId, axis: GamepadAxis) -> f32 {
+        match axis {
+            GamepadAxis::LeftStickX => self.gamepad_stick(id, Stick::Left).x,
+            GamepadAxis::LeftStickY => self.gamepad_stick(id, Stick::Left).y,
+            GamepadAxis::RightStickX => self.gamepad_stick(id, Stick::Right).x,
+            GamepadAxis::RightStickY => self.gamepad_stick(id, Stick::Right).y,
+            GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => {
+                let value = self.gamepads.get(&id).map_or(0.0, |pad| pad.axes[axis.index()]);
+                gamepad::apply_dead_zone(value, self.dead_zone)
+            }
+        }
+    }
+
+    /// Both axes of a stick, with the dead zone applied. Y is up.
+    pub fn gamepad_stick(&self, id: GamepadId, stick: Stick) -> Vector2<f32> {
+        let Some(pad) = self.gamepads.get(&id) else { return vec2(0.0, 0.0); };
+        let (x, y) = match stick {
+            Stick::Left => (GamepadAxis::LeftStickX, GamepadAxis::LeftStickY),
+            Stick::Right => (GamepadAxis::RightStickX, GamepadAxis::RightStickY),
+        };
+        let (x, y) = gamepad::apply_stick_dead_zone(pad.axes[x.index()], pad.axes[y.index()], self.dead_zone);
+        vec2(x, y)
+    }
+
+    /// How far the emulated cursor should move over `dt` seconds, in screen
+    /// space. All connected pads can move it.
+    pub(crate) fn emulated_cursor_delta(&self, dt: f32) -> Vector2<f32> {
+        let Some(emulation) = self.cursor_emulation else { return vec2(0.0, 0.0); };
+
+        let mut delta = vec2(0.0, 0.0);
+        for id in self.gamepads.keys() {
+            let stick = self.gamepad_stick(*id, emulation.stick);
+            // Screen space Y goes down.
+            delta += vec2(stick.x, -stick.y) * emulation.speed * dt;
+        }
+        delta
+    }
+
+    /// Every finger on the screen, including the ones lifted since the last
+    /// tick.
+    pub fn touches(&self) -> impl Iterator<Item = &TouchPoint> {
+        self.touch.points()
+    }
+
+    pub fn touch(&self, id: u64) -> Option<&TouchPoint> {
+        self.touch.point(id)
+    }
+
+    pub fn touch_settings(&self) -> TouchSettings {
+        self.touch.settings
+    }
+
+    pub fn set_touch_settings(&mut self, settings: TouchSettings) {
+        self.touch.settings = settings;
+    }
+
+    pub fn is_gesture_active(&self, gesture: GestureKind) -> bool {
+        self.touch.is_active(gesture)
+    }
+
+    pub fn is_gesture_just_started(&self, gesture: GestureKind) -> bool {
+        self.touch.is_just_started(gesture)
+    }
+
+    pub fn is_gesture_just_ended(&self, gesture: GestureKind) -> bool {
+        self.touch.is_just_ended(gesture)
+    }
+
+    /// Where a tap happened this tick, if one did.
+    pub fn tap(&self) -> Option<Vector2<f32>> {
+        self.touch.tap()
+    }
+
+    /// The finger that is being long-pressed, if any.
+    pub fn long_press(&self) -> Option<&TouchPoint> {
+        self.touch.long_press()
+    }
+
+    pub fn drag(&self) -> Option<Drag> {
+        self.touch.drag()
+    }
+
+    pub fn pinch(&self) -> Option<TwoFinger> {
+        self.touch.two_finger().filter(|_| self.is_gesture_active(GestureKind::Pinch))
+    }
+
+    pub fn pan(&self) -> Option<TwoFinger> {
+        self.touch.two_finger().filter(|_| self.is_gesture_active(GestureKind::Pan))
+    }
+
+    /// The same as Engine::get_cursor_position(), as of the start of this
+    /// tick.
+    pub fn cursor_position(&self) -> Vector2<f32> {
+        self.cursor_position
+    }
+
+    /// How far the cursor moved since the last tick, in physical pixels.
+    pub fn cursor_delta(&self) -> Vector2<f32> {
+        self.cursor_delta
+    }
+
+    /// Whether the cursor is over the window.
+    pub fn is_cursor_inside(&self) -> bool {
+        self.cursor_inside
+    }
+
+    pub fn did_cursor_enter(&self) -> bool {
+        self.cursor_inside && !self.was_cursor_inside
+    }
+
+    pub fn did_cursor_leave(&self) -> bool {
+        !self.cursor_inside && self.was_cursor_inside
+    }
+
+    /// Scrolling since the last tick from a wheel with notches, in lines.
+    /// Positive Y scrolls up, away from the player.
+    pub fn scroll_lines(&self) -> Vector2<f32> {
+        self.scroll_lines
+    }
+
+    /// Scrolling since the last tick from a touchpad, in physical pixels.
+    pub fn scroll_pixels(&self) -> Vector2<f32> {
+        self.scroll_pixels
+    }
+
+    /// The shift, control, alt and super keys that are held.
+    pub fn modifiers(&self) -> ModifiersState {
+        self.modifiers
+    }
+
+    /// The text that was typed since the last tick, with IME input already
+    /// composed. Empty if nothing was typed.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The text the IME is composing, before it is committed. See
+    /// Engine::set_ime_allowed().
+    pub fn ime_preedit(&self) -> Option<&str> {
+        self.ime_preedit.as_deref()
+    }
+
+    /// Starts recording every input event, from the next tick on. Any
+    /// recording in progress is thrown away.
+    ///
+    /// For the recording to replay correctly, start it from a state that the
+    /// Gameplay can get back to, e.g. right after opening a level, and name
+    /// that state in `start`. It is saved with the log and handed to
+    /// Gameplay::replay_start() by Headless::replay().
+    pub fn start_recording(&mut self, start: Option<&str>) {
+        self.tick = 0;
+        self.recording = Some(InputLog::new(start));
+        self.last_recorded_cursor = None;
+    }
+
+    pub fn stop_recording(&mut self) -> Option<InputLog> {
+        let mut log = self.recording.take()?;
+        log.finish(self.tick);
+        Some(log)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Starts feeding the given log back in, one tick at a time, from the next
+    /// tick on. Until stop_replay() is called, input from the window and the
+    /// gamepads is ignored, and the cursor comes from the log.
+    pub fn start_replay(&mut self, log: InputLog) {
+        self.recording = None;
+        self.reset_state();
+        self.tick = 0;
+        self.replay = Some(Replay::new(log));
+    }
+
+    /// Goes back to live input. Anything the replay was holding down is
+    /// released.
+    pub fn stop_replay(&mut self) {
+        if self.replay.take().is_some() {
+            self.reset_state();
+        }
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// True once every tick of the replay has been played.
+    pub fn is_replay_finished(&self) -> bool {
+        self.replay.as_ref().is_some_and(|replay| self.tick >= replay.log.ticks())
+    }
+
+    /// Whether the player tried to do anything this tick while a replay was
+    /// running, e.g. to end an attract mode demo.
+    pub fn had_live_input(&self) -> bool {
+        self.live_input_ignored
+    }
+
+    /// The cursor position from the replay, which overrides the real one.
+    pub(crate) fn replay_cursor(&self) -> Option<Vector2<f32>> {
+        self.replay.as_ref().map(|replay| replay.cursor)
+    }
+
+    /// Forgets every button, touch and gamepad.
+    fn reset_state(&mut self) {
+        self.tracker = ButtonStateTracker::new();
+        self.modifiers = ModifiersState::empty();
+        self.scroll_lines = vec2(0.0, 0.0);
+        self.scroll_pixels = vec2(0.0, 0.0);
+        let settings = self.touch.settings;
+        self.touch = TouchTracker::new();
+        self.touch.settings = settings;
+        self.gamepads.clear();
+        self.connection_events.clear();
+    }
+
+    /// Applies an event that came from the window or a gamepad, unless a replay
+    /// is running.
+    fn live_event(&mut self, event: InputEvent) {
+        if self.replay.is_some() {
+            self.live_input_ignored = true;
+            return;
+        }
+
+        if let Some(recording) = self.recording.as_mut() {
+            recording.push(self.tick, event);
+        }
+        self.apply_event(event);
+    }
+
+    fn apply_event(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Key { code, pressed } =>
+                self.tracker.update(AnyButton::PhysicalKey(winit::keyboard::PhysicalKey::Code(code)), pressed),
+            InputEvent::Mouse { button, pressed } => self.tracker.update(AnyButton::Mouse(button), pressed),
+            InputEvent::Cursor(position) => {
+                if let Some(replay) = self.replay.as_mut() {
+                    replay.cursor = position;
+                }
+            }
+            InputEvent::Touch { id, phase, position } => {
+                self.touch.update(id, phase, position);
+                self.tracker.update(AnyButton::Touch, self.touch.any_down());
+            }
+            InputEvent::Gamepad(event) => self.handle_gamepad_event(event),
+            InputEvent::ScrollLines(delta) => self.scroll_lines += delta,
+            InputEvent::ScrollPixels(delta) => self.scroll_pixels += delta,
+            InputEvent::CursorEntered => self.cursor_inside = true,
+            InputEvent::CursorLeft => self.cursor_inside = false,
+            InputEvent::Modifiers(modifiers) => self.modifiers = modifiers,
+        }
+    }
+
+    /// Updates the cursor position for this tick, and records it if it moved.
+    pub(crate) fn update_cursor(&mut self, position: Vector2<f32>) {
+        // Don't count the jump from where the cursor left to where it came
+        // back in.
+        self.cursor_delta = if !self.did_cursor_enter() {
+            position - self.cursor_position
+        }
+        else {
+            vec2(0.0, 0.0)
+        };
+        self.cursor_position = position;
+
+        let Some(recording) = self.recording.as_mut() else { return; };
+        if self.last_recorded_cursor != Some(position) {
+            recording.push(self.tick, InputEvent::Cursor(position));
+            self.last_recorded_cursor = Some(position);
+        }
+    }
+
+    /// Polls the gamepads, or applies this tick's events from the replay, and
+    /// recognizes touch gestures. Called at the start of each tick.
+    pub(crate) fn tick_start(&mut self) {
+        self.poll_gamepads();
+
+        if let Some(replay) = self.replay.as_mut() {
+            for event in replay.take_events(self.tick) {
+                self.apply_event(event);
+            }
+        }
+
+        self.touch.tick_start();
+    }
+
+    /// Reads every pending event from the gamepad backend.
+    fn poll_gamepads(&mut self) {
+        let Some(backend) = self.gamepad_backend.as_mut() else { return; };
+
+        let mut events = Vec::new();
+        backend.poll(&mut events);
+
+        for event in events {
+            self.live_event(InputEvent::Gamepad(event));
+        }
+    }
+
+    fn handle_gamepad_event(&mut self, event: GamepadEvent) {
+        match event {
+            GamepadEvent::Connected(id) => {
+                log::info!("gamepad {:?} connected", id);
+                self.gamepads.entry(id).or_default();
+                self.connection_events.push(event);
+            }
+            GamepadEvent::Disconnected(id) => {
+                log::info!("gamepad {:?} disconnected", id);
+                self.gamepads.remove(&id);
+                // Release everything it was holding, so that nothing stays
+                // stuck down.
+                let held: Vec<AnyButton> = self.tracker.current.iter()
+                    .filter(|(button, pressed)| **pressed && matches!(button, AnyButton::Gamepad(pad, _) if *pad == id))
+                    .map(|(button, _)| button.clone())
+                    .collect();
+                for button in held {
+                    self.tracker.update(button, false);
+                }
+                self.connection_events.push(event);
+            }
+            GamepadEvent::Button { id, button, pressed } => {
+                // Some backends don't report pads that were already plugged in
+                // at startup.
+                self.gamepads.entry(id).or_default();
+                self.tracker.update(AnyButton::Gamepad(id, button), pressed);
+            }
+            GamepadEvent::Axis { id, axis, value } => {
+                self.gamepads.entry(id).or_default().axes[axis.index()] = value;
+            }
+        }
+    }
+
     pub fn tick_end(&mut self) {
         self.tracker.tick_end();
+        self.touch.tick_end();
+        self.connection_events.clear();
+        self.live_input_ignored = false;
+        self.tick += 1;
+
+        self.was_cursor_inside = self.cursor_inside;
+        self.scroll_lines = vec2(0.0, 0.0);
+        self.scroll_pixels = vec2(0.0, 0.0);
+        self.text.clear();
     }
 
     pub(crate) fn update_button(&mut self, button: AnyButton, pressed: bool) {
-        self.tracker.update(button, pressed);
+        let event = match button {
+            AnyButton::PhysicalKey(winit::keyboard::PhysicalKey::Code(code)) => InputEvent::Key { code, pressed },
+            AnyButton::Mouse(button) => InputEvent::Mouse { button, pressed },
+            // Unidentified keys have no KeyCode to record, so they aren't
+            // replayed either.
+            _ => {
+                if self.replay.is_none() {
+                    self.tracker.update(button, pressed);
+                }
+                return;
+            }
+        };
+        self.live_event(event);
+    }
+
+    pub(crate) fn update_scroll(&mut self, delta: winit::event::MouseScrollDelta) {
+        match delta {
+            winit::event::MouseScrollDelta::LineDelta(x, y) => self.live_event(InputEvent::ScrollLines(vec2(x, y))),
+            winit::event::MouseScrollDelta::PixelDelta(position) =>
+                self.live_event(InputEvent::ScrollPixels(vec2(position.x as f32, position.y as f32))),
+        }
+    }
+
+    pub(crate) fn update_cursor_inside(&mut self, inside: bool) {
+        self.live_event(if inside { InputEvent::CursorEntered } else { InputEvent::CursorLeft });
+    }
+
+    pub(crate) fn update_modifiers(&mut self, modifiers: ModifiersState) {
+        self.live_event(InputEvent::Modifiers(modifiers));
+    }
+
+    /// Text isn't part of InputEvent, so it isn't recorded or replayed.
+    pub(crate) fn update_text(&mut self, text: &str) {
+        if self.replay.is_some() {
+            self.live_input_ignored = true;
+            return;
+        }
+        self.text.push_str(text);
+    }
+
+    pub(crate) fn update_ime(&mut self, ime: &winit::event::Ime) {
+        match ime {
+            winit::event::Ime::Preedit(text, _) if !text.is_empty() => self.ime_preedit = Some(text.clone()),
+            winit::event::Ime::Preedit(..) | winit::event::Ime::Enabled | winit::event::Ime::Disabled => self.ime_preedit = None,
+            winit::event::Ime::Commit(text) => {
+                self.ime_preedit = None;
+                self.update_text(text);
+            }
+        }
+    }
+
+    pub(crate) fn update_touch(&mut self, id: u64, phase: winit::event::TouchPhase, position: Vector2<f32>) {
+        self.live_event(InputEvent::Touch { id, phase, position });
     }
 }
\ No newline at end of file
@@ -0,0 +1,310 @@
+// Named actions, e.g. "pick_up" or "menu", each bound to any number of
+// physical inputs. The Gameplay asks Input about actions instead of specific
+// keys, so the controls can be remapped from a config file or at runtime.
+//
+// The config format is one action per line, with a comma-separated list of
+// bindings:
+//
+// # Comments start with a '#'
+// pick_up = mouse:left, key:Space
+// menu    = key:Escape
+// undo    = key:KeyZ, key:Backspace
+// drag    = touch
+// zoom    = touch:pinch
+// confirm = pad:South
+//
+// Key names are the same as the winit KeyCode variants, and gamepad button
+// names the same as the GamepadButton variants. Gestures are tap, long_press,
+// drag, pinch and pan.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{error::{EngineError, EngineResult}, input::{gamepad::GamepadButton, touch::GestureKind, AnyButton, KeyCode, MouseButton}};
+
+/// A single physical input that can trigger an action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// Any finger touching the screen.
+    Touch,
+    /// Held while the gesture is active.
+    Gesture(GestureKind),
+    /// The button on any connected gamepad.
+    Gamepad(GamepadButton),
+}
+
+impl Binding {
+    pub(crate) fn from_button(button: &AnyButton) -> Option<Binding> {
+        match button {
+            AnyButton::PhysicalKey(winit::keyboard::PhysicalKey::Code(code)) => Some(Binding::Key(*code)),
+            AnyButton::PhysicalKey(_) => None,
+            AnyButton::Mouse(button) => Some(Binding::Mouse(*button)),
+            AnyButton::Touch => Some(Binding::Touch),
+            AnyButton::Gamepad(_, button) => Some(Binding::Gamepad(*button)),
+        }
+    }
+
+    /// Parses a single binding in the config format, e.g. "key:Space".
+    pub fn parse(text: &str) -> Option<Binding> {
+        let text = text.trim();
+        if text == "touch" {
+            return Some(Binding::Touch);
+        }
+
+        let (kind, name) = text.split_once(':')?;
+        match kind.trim() {
+            "key" => key_code_from_name(name.trim()).map(Binding::Key),
+            "mouse" => mouse_button_from_name(name.trim()).map(Binding::Mouse),
+            "touch" => gesture_from_name(name.trim()).map(Binding::Gesture),
+            "pad" => gamepad_button_from_name(name.trim()).map(Binding::Gamepad),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Key(code) => write!(f, "key:{:?}", code),
+            Binding::Mouse(MouseButton::Left) => write!(f, "mouse:left"),
+            Binding::Mouse(MouseButton::Right) => write!(f, "mouse:right"),
+            Binding::Mouse(MouseButton::Middle) => write!(f, "mouse:middle"),
+            Binding::Mouse(MouseButton::Back) => write!(f, "mouse:back"),
+            Binding::Mouse(MouseButton::Forward) => write!(f, "mouse:forward"),
+            Binding::Mouse(MouseButton::Other(idx)) => write!(f, "mouse:{}", idx),
+            Binding::Touch => write!(f, "touch"),
+            Binding::Gesture(gesture) => write!(f, "touch:{}", gesture_name(*gesture)),
+            Binding::Gamepad(button) => write!(f, "pad:{:?}", button),
+        }
+    }
+}
+
+pub(crate) fn mouse_button_from_name(name: &str) -> Option<MouseButton> {
+    match name {
+        "left" => Some(MouseButton::Left),
+        "right" => Some(MouseButton::Right),
+        "middle" => Some(MouseButton::Middle),
+        "back" => Some(MouseButton::Back),
+        "forward" => Some(MouseButton::Forward),
+        other => other.parse().ok().map(MouseButton::Other),
+    }
+}
+
+const GESTURE_NAMES: [(GestureKind, &str); 5] = [
+    (GestureKind::Tap, "tap"),
+    (GestureKind::LongPress, "long_press"),
+    (GestureKind::Drag, "drag"),
+    (GestureKind::Pinch, "pinch"),
+    (GestureKind::Pan, "pan"),
+];
+
+fn gesture_name(gesture: GestureKind) -> &'static str {
+    GESTURE_NAMES.iter().find(|(kind, _)| *kind == gesture).map(|(_, name)| *name).unwrap()
+}
+
+fn gesture_from_name(name: &str) -> Option<GestureKind> {
+    GESTURE_NAMES.iter().find(|(_, other)| *other == name).map(|(kind, _)| *kind)
+}
+
+macro_rules! variant_names {
+    ($fn_name:ident, $all:ident, $typ:ident, [$($name:ident),* $(,)?]) => {
+        pub(crate) fn $fn_name(name: &str) -> Option<$typ> {
+            match name {
+                $(stringify!($name) => Some($typ::$name),)*
+                _ => None,
+            }
+        }
+
+        #[cfg(test)]
+        const $all: &[$typ] = &[$($typ::$name),*];
+    };
+}
+
+// Every KeyCode, so that any key the player rebinds to can be saved and
+// loaded again.
+variant_names!(key_code_from_name, ALL_KEY_CODES, KeyCode, [
+    Backquote, Backslash, BracketLeft, BracketRight, Comma, Equal, Minus, Period, Quote, Semicolon, Slash,
+    IntlBackslash, IntlRo, IntlYen,
+    Digit0, Digit1, Digit2, Digit3, Digit4, Digit5, Digit6, Digit7, Digit8, Digit9,
+    KeyA, KeyB, KeyC, KeyD, KeyE, KeyF, KeyG, KeyH, KeyI, KeyJ, KeyK, KeyL, KeyM,
+    KeyN, KeyO, KeyP, KeyQ, KeyR, KeyS, KeyT, KeyU, KeyV, KeyW, KeyX, KeyY, KeyZ,
+    AltLeft, AltRight, ControlLeft, ControlRight, ShiftLeft, ShiftRight, SuperLeft, SuperRight,
+    Backspace, CapsLock, ContextMenu, Enter, Space, Tab,
+    Convert, KanaMode, Lang1, Lang2, Lang3, Lang4, Lang5, NonConvert, Hiragana, Katakana,
+    Delete, End, Help, Home, Insert, PageDown, PageUp,
+    ArrowDown, ArrowLeft, ArrowRight, ArrowUp,
+    NumLock, Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    NumpadAdd, NumpadBackspace, NumpadClear, NumpadClearEntry, NumpadComma, NumpadDecimal,
+    NumpadDivide, NumpadEnter, NumpadEqual, NumpadHash, NumpadMemoryAdd, NumpadMemoryClear,
+    NumpadMemoryRecall, NumpadMemoryStore, NumpadMemorySubtract, NumpadMultiply,
+    NumpadParenLeft, NumpadParenRight, NumpadStar, NumpadSubtract,
+    Escape, Fn, FnLock, PrintScreen, ScrollLock, Pause,
+    BrowserBack, BrowserFavorites, BrowserForward, BrowserHome, BrowserRefresh, BrowserSearch, BrowserStop,
+    Eject, LaunchApp1, LaunchApp2, LaunchMail,
+    MediaPlayPause, MediaSelect, MediaStop, MediaTrackNext, MediaTrackPrevious,
+    Power, Sleep, WakeUp, AudioVolumeDown, AudioVolumeMute, AudioVolumeUp,
+    Meta, Hyper, Turbo, Abort, Resume, Suspend, Again, Copy, Cut, Find, Open, Paste, Props, Select, Undo,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15, F16, F17, F18,
+    F19, F20, F21, F22, F23, F24, F25, F26, F27, F28, F29, F30, F31, F32, F33, F34, F35,
+]);
+
+variant_names!(gamepad_button_from_name, ALL_GAMEPAD_BUTTONS, GamepadButton, [
+    South, East, North, West, LeftBumper, RightBumper, LeftTrigger, RightTrigger,
+    Select, Start, Mode, LeftStick, RightStick, DPadUp, DPadDown, DPadLeft, DPadRight,
+]);
+
+/// Maps action names to their bindings.
+#[derive(Clone, Debug, Default)]
+pub struct ActionMap {
+    actions: BTreeMap<String, Vec<Binding>>,
+}
+
+impl ActionMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses an ActionMap from the config format described at the top of
+    /// this file.
+    pub fn from_config(text: &str) -> EngineResult<Self> {
+        let mut map = ActionMap::new();
+        map.load_config(text)?;
+        Ok(map)
+    }
+
+    /// Adds the bindings from the given config. Actions in the config replace
+    /// any existing bindings for the same action; other actions are kept.
+    pub fn load_config(&mut self, text: &str) -> EngineResult<()> {
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((action, bindings)) = line.split_once('=') else {
+                return Err(EngineError::Config { line: idx + 1, message: "expected 'action = bindings'".to_string() });
+            };
+
+            let action = action.trim();
+            if action.is_empty() {
+                return Err(EngineError::Config { line: idx + 1, message: "missing action name".to_string() });
+            }
+
+            let mut parsed = Vec::new();
+            for binding in bindings.split(',').filter(|binding| !binding.trim().is_empty()) {
+                let Some(binding) = Binding::parse(binding) else {
+                    return Err(EngineError::Config { line: idx + 1, message: format!("unknown binding '{}'", binding.trim()) });
+                };
+                parsed.push(binding);
+            }
+
+            self.set_bindings(action, parsed);
+        }
+        Ok(())
+    }
+
+    /// Reads and parses a config file. Not available on the web, where the
+    /// config should be embedded with include_str!() instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_config_file(&mut self, path: impl AsRef<std::path::Path>) -> EngineResult<()> {
+        let text = std::fs::read_to_string(path.as_ref())
+            .map_err(|err| EngineError::new(format!("failed to read {}: {}", path.as_ref().display(), err)))?;
+        self.load_config(&text)
+    }
+
+    /// Writes the map back out in the config format, e.g. to save the
+    /// player's rebinds.
+    pub fn to_config(&self) -> String {
+        let mut out = String::new();
+        for (action, bindings) in &self.actions {
+            let bindings: Vec<String> = bindings.iter().map(Binding::to_string).collect();
+            out.push_str(&format!("{} = {}\n", action, bindings.join(", ")));
+        }
+        out
+    }
+
+    /// Adds a binding to the action, creating the action if needed.
+    pub fn bind(&mut self, action: &str, binding: Binding) {
+        let bindings = self.actions.entry(action.to_string()).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    pub fn unbind(&mut self, action: &str, binding: Binding) {
+        if let Some(bindings) = self.actions.get_mut(action) {
+            bindings.retain(|other| *other != binding);
+        }
+    }
+
+    /// Replaces all of the action's bindings.
+    pub fn set_bindings(&mut self, action: &str, bindings: Vec<Binding>) {
+        self.actions.insert(action.to_string(), bindings);
+    }
+
+    /// Removes every binding for the action. The action itself still exists.
+    pub fn clear(&mut self, action: &str) {
+        if let Some(bindings) = self.actions.get_mut(action) {
+            bindings.clear();
+        }
+    }
+
+    pub fn bindings(&self, action: &str) -> &[Binding] {
+        self.actions.get(action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &str> {
+        self.actions.keys().map(String::as_str)
+    }
+
+    /// The actions that the given binding triggers.
+    pub fn actions_for(&self, binding: Binding) -> impl Iterator<Item = &str> {
+        self.actions.iter()
+            .filter(move |(_, bindings)| bindings.contains(&binding))
+            .map(|(action, _)| action.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_binding() -> Vec<Binding> {
+        let mut bindings: Vec<Binding> = ALL_KEY_CODES.iter().copied().map(Binding::Key).collect();
+        bindings.extend(ALL_GAMEPAD_BUTTONS.iter().copied().map(Binding::Gamepad));
+        bindings.extend(GESTURE_NAMES.iter().map(|(gesture, _)| Binding::Gesture(*gesture)));
+        bindings.extend([
+            MouseButton::Left, MouseButton::Right, MouseButton::Middle,
+            MouseButton::Back, MouseButton::Forward, MouseButton::Other(7),
+        ].map(Binding::Mouse));
+        bindings.push(Binding::Touch);
+        bindings
+    }
+
+    #[test]
+    fn every_binding_round_trips() {
+        let mut map = ActionMap::new();
+        for binding in every_binding() {
+            map.bind("action", binding);
+        }
+
+        let loaded = ActionMap::from_config(&map.to_config()).unwrap();
+        assert_eq!(loaded.bindings("action"), map.bindings("action"));
+    }
+
+    #[test]
+    fn each_binding_parses_its_own_name() {
+        for binding in every_binding() {
+            assert_eq!(Binding::parse(&binding.to_string()), Some(binding), "{}", binding);
+        }
+    }
+
+    #[test]
+    fn gesture_syntax() {
+        assert_eq!(Binding::parse("touch:tap"), Some(Binding::Gesture(GestureKind::Tap)));
+        assert_eq!(Binding::parse(" touch : long_press "), Some(Binding::Gesture(GestureKind::LongPress)));
+        assert_eq!(Binding::parse("touch"), Some(Binding::Touch));
+        assert_eq!(Binding::parse("touch:swipe"), None);
+    }
+}
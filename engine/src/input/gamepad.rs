@@ -0,0 +1,236 @@
+// Gamepad support.
+//
+// Gamepads are read through a GamepadBackend, which is polled once per tick
+// and turns whatever the platform gives us into GamepadEvents. The default
+// backend uses gilrs; tests can use FakeGamepadBackend to inject a pad.
+
+use std::{cell::RefCell, rc::Rc};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GamepadId(pub usize);
+
+/// Buttons are named by their position, e.g. South is A on an Xbox pad and
+/// Cross on a PlayStation pad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    /// The triggers also report how far they are pulled, as an axis.
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    Mode,
+    LeftStick,
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GamepadAxis {
+    /// -1 is left, 1 is right.
+    LeftStickX,
+    /// -1 is down, 1 is up.
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    /// 0 is released, 1 is fully pulled.
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl GamepadAxis {
+    pub const COUNT: usize = 6;
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The inverse of the Debug name, e.g. "LeftStickX".
+    pub fn from_name(name: &str) -> Option<GamepadAxis> {
+        match name {
+            "LeftStickX" => Some(GamepadAxis::LeftStickX),
+            "LeftStickY" => Some(GamepadAxis::LeftStickY),
+            "RightStickX" => Some(GamepadAxis::RightStickX),
+            "RightStickY" => Some(GamepadAxis::RightStickY),
+            "LeftTrigger" => Some(GamepadAxis::LeftTrigger),
+            "RightTrigger" => Some(GamepadAxis::RightTrigger),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GamepadEvent {
+    Connected(GamepadId),
+    Disconnected(GamepadId),
+    Button { id: GamepadId, button: GamepadButton, pressed: bool },
+    /// The raw value, before any dead zone is applied.
+    Axis { id: GamepadId, axis: GamepadAxis, value: f32 },
+}
+
+pub trait GamepadBackend {
+    /// Appends every event that happened since the last poll.
+    fn poll(&mut self, events: &mut Vec<GamepadEvent>);
+}
+
+/// The analog state of a connected gamepad. Button state is kept in Input's
+/// button tracker.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct GamepadState {
+    pub(crate) axes: [f32; GamepadAxis::COUNT],
+}
+
+/// A backend that only reports the events that are pushed into it. Clones
+/// share the same queue, so a test can keep one and hand the other to
+/// Input::set_gamepad_backend().
+#[derive(Clone, Default)]
+pub struct FakeGamepadBackend {
+    queue: Rc<RefCell<Vec<GamepadEvent>>>,
+}
+
+impl FakeGamepadBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, event: GamepadEvent) {
+        self.queue.borrow_mut().push(event);
+    }
+}
+
+impl GamepadBackend for FakeGamepadBackend {
+    fn poll(&mut self, events: &mut Vec<GamepadEvent>) {
+        events.append(&mut self.queue.borrow_mut());
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+pub struct GilrsBackend {
+    gilrs: gilrs::Gilrs,
+}
+
+#[cfg(not(target_os = "android"))]
+impl GilrsBackend {
+    /// Returns None if gamepads aren't supported on this system.
+    pub fn new() -> Option<Self> {
+        match gilrs::Gilrs::new() {
+            Ok(gilrs) => Some(GilrsBackend { gilrs }),
+            Err(err) => {
+                log::warn!("gamepads unavailable: {}", err);
+                None
+            }
+        }
+    }
+
+    fn convert_button(button: gilrs::Button) -> Option<GamepadButton> {
+        use gilrs::Button;
+
+        Some(match button {
+            Button::South => GamepadButton::South,
+            Button::East => GamepadButton::East,
+            Button::North => GamepadButton::North,
+            Button::West => GamepadButton::West,
+            // gilrs calls the bumpers "triggers", and the triggers "triggers 2".
+            Button::LeftTrigger => GamepadButton::LeftBumper,
+            Button::RightTrigger => GamepadButton::RightBumper,
+            Button::LeftTrigger2 => GamepadButton::LeftTrigger,
+            Button::RightTrigger2 => GamepadButton::RightTrigger,
+            Button::Select => GamepadButton::Select,
+            Button::Start => GamepadButton::Start,
+            Button::Mode => GamepadButton::Mode,
+            Button::LeftThumb => GamepadButton::LeftStick,
+            Button::RightThumb => GamepadButton::RightStick,
+            Button::DPadUp => GamepadButton::DPadUp,
+            Button::DPadDown => GamepadButton::DPadDown,
+            Button::DPadLeft => GamepadButton::DPadLeft,
+            Button::DPadRight => GamepadButton::DPadRight,
+            _ => return None,
+        })
+    }
+
+    fn convert_axis(axis: gilrs::Axis) -> Option<GamepadAxis> {
+        use gilrs::Axis;
+
+        Some(match axis {
+            Axis::LeftStickX => GamepadAxis::LeftStickX,
+            Axis::LeftStickY => GamepadAxis::LeftStickY,
+            Axis::RightStickX => GamepadAxis::RightStickX,
+            Axis::RightStickY => GamepadAxis::RightStickY,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+impl GamepadBackend for GilrsBackend {
+    fn poll(&mut self, events: &mut Vec<GamepadEvent>) {
+        use gilrs::EventType;
+
+        while let Some(event) = self.gilrs.next_event() {
+            let id = GamepadId(usize::from(event.id));
+            match event.event {
+                EventType::Connected => events.push(GamepadEvent::Connected(id)),
+                EventType::Disconnected => events.push(GamepadEvent::Disconnected(id)),
+                EventType::ButtonPressed(button, _) | EventType::ButtonReleased(button, _) => {
+                    let Some(button) = Self::convert_button(button) else { continue; };
+                    let pressed = matches!(event.event, EventType::ButtonPressed(..));
+                    events.push(GamepadEvent::Button { id, button, pressed });
+                }
+                EventType::ButtonChanged(button, value, _) => {
+                    // Analog triggers are reported as buttons with a value.
+                    let axis = match Self::convert_button(button) {
+                        Some(GamepadButton::LeftTrigger) => GamepadAxis::LeftTrigger,
+                        Some(GamepadButton::RightTrigger) => GamepadAxis::RightTrigger,
+                        _ => continue,
+                    };
+                    events.push(GamepadEvent::Axis { id, axis, value });
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    let Some(axis) = Self::convert_axis(axis) else { continue; };
+                    events.push(GamepadEvent::Axis { id, axis, value });
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Creates the backend for the current platform, if there is one.
+pub(crate) fn default_backend() -> Option<Box<dyn GamepadBackend>> {
+    #[cfg(not(target_os = "android"))]
+    {
+        GilrsBackend::new().map(|backend| Box::new(backend) as Box<dyn GamepadBackend>)
+    }
+    #[cfg(target_os = "android")]
+    {
+        None
+    }
+}
+
+/// Applies a dead zone to a stick, and rescales the rest of the range so that
+/// it still goes smoothly from 0 to 1.
+pub(crate) fn apply_stick_dead_zone(x: f32, y: f32, dead_zone: f32) -> (f32, f32) {
+    let magnitude = (x * x + y * y).sqrt();
+    if magnitude <= dead_zone {
+        return (0.0, 0.0);
+    }
+
+    let scaled = ((magnitude - dead_zone) / (1.0 - dead_zone)).min(1.0);
+    (x / magnitude * scaled, y / magnitude * scaled)
+}
+
+/// Same as apply_stick_dead_zone(), for a single axis such as a trigger.
+pub(crate) fn apply_dead_zone(value: f32, dead_zone: f32) -> f32 {
+    if value.abs() <= dead_zone {
+        return 0.0;
+    }
+    value.signum() * ((value.abs() - dead_zone) / (1.0 - dead_zone)).min(1.0)
+}
@@ -0,0 +1,270 @@
+// Recording and replaying input.
+//
+// While recording, every raw input event that reaches Input is stored along
+// with the tick it was applied in. Since ticks have a fixed size, feeding the
+// same events back in at the same ticks reproduces the same session, as long
+// as the Gameplay itself is deterministic and starts from the same state.
+//
+// An InputLog is saved as text, one event per line:
+//
+// ticks 3600
+// start level 3
+// 0 cursor 412 300
+// 12 key:Space down
+// 15 mouse:left up
+// 20 touch 3 started 100.5 200
+// 31 pad 0 connected
+// 31 pad 0 button South down
+// 32 pad 0 axis LeftStickX -0.75
+// 40 scroll lines 0 -1
+// 41 modifiers shift control
+// 42 cursor entered
+//
+// Only the events that reach Input are recorded. Typed text, IME input and
+// the input egui gets for its own widgets are not, so a replay can't type
+// into a text field or click through an egui menu, e.g. to pick the next
+// level. Instead, the recording can name the state it started from, e.g.
+// "level 3", and Headless::replay hands that to Gameplay::replay_start to get
+// back there before playing the events.
+
+use cgmath::Vector2;
+use winit::event::TouchPhase;
+
+use crate::{error::{EngineError, EngineResult}, input::{actions::{gamepad_button_from_name, Binding}, gamepad::{GamepadAxis, GamepadEvent, GamepadId}, KeyCode, ModifiersState, MouseButton}};
+
+/// A single raw input, as it arrived from the window or a gamepad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Key { code: KeyCode, pressed: bool },
+    Mouse { button: MouseButton, pressed: bool },
+    Cursor(Vector2<f32>),
+    Touch { id: u64, phase: TouchPhase, position: Vector2<f32> },
+    Gamepad(GamepadEvent),
+    ScrollLines(Vector2<f32>),
+    ScrollPixels(Vector2<f32>),
+    CursorEntered,
+    CursorLeft,
+    Modifiers(ModifiersState),
+}
+
+const MODIFIER_NAMES: [(&str, ModifiersState); 4] = [
+    ("shift", ModifiersState::SHIFT),
+    ("control", ModifiersState::CONTROL),
+    ("alt", ModifiersState::ALT),
+    ("super", ModifiersState::SUPER),
+];
+
+impl InputEvent {
+    fn to_text(self) -> String {
+        let up_down = |pressed: bool| if pressed { "down" } else { "up" };
+        match self {
+            InputEvent::Key { code, pressed } => format!("{} {}", Binding::Key(code), up_down(pressed)),
+            InputEvent::Mouse { button, pressed } => format!("{} {}", Binding::Mouse(button), up_down(pressed)),
+            InputEvent::Cursor(position) => format!("cursor {} {}", position.x, position.y),
+            InputEvent::Touch { id, phase, position } => {
+                let phase = match phase {
+                    TouchPhase::Started => "started",
+                    TouchPhase::Moved => "moved",
+                    TouchPhase::Ended => "ended",
+                    TouchPhase::Cancelled => "cancelled",
+                };
+                format!("touch {} {} {} {}", id, phase, position.x, position.y)
+            }
+            InputEvent::Gamepad(event) => match event {
+                GamepadEvent::Connected(id) => format!("pad {} connected", id.0),
+                GamepadEvent::Disconnected(id) => format!("pad {} disconnected", id.0),
+                GamepadEvent::Button { id, button, pressed } =>
+                    format!("pad {} button {:?} {}", id.0, button, up_down(pressed)),
+                GamepadEvent::Axis { id, axis, value } =>
+                    format!("pad {} axis {:?} {}", id.0, axis, value),
+            },
+            InputEvent::ScrollLines(delta) => format!("scroll lines {} {}", delta.x, delta.y),
+            InputEvent::ScrollPixels(delta) => format!("scroll pixels {} {}", delta.x, delta.y),
+            InputEvent::CursorEntered => "cursor entered".to_string(),
+            InputEvent::CursorLeft => "cursor left".to_string(),
+            InputEvent::Modifiers(modifiers) => {
+                let mut out = "modifiers".to_string();
+                for (name, modifier) in MODIFIER_NAMES {
+                    if modifiers.contains(modifier) {
+                        out.push(' ');
+                        out.push_str(name);
+                    }
+                }
+                out
+            }
+        }
+    }
+
+    fn parse(words: &[&str]) -> Option<InputEvent> {
+        let pressed = |word: &str| match word {
+            "down" => Some(true),
+            "up" => Some(false),
+            _ => None,
+        };
+        let vec = |x: &str, y: &str| Some(Vector2::new(x.parse().ok()?, y.parse().ok()?));
+
+        Some(match words {
+            ["cursor", "entered"] => InputEvent::CursorEntered,
+            ["cursor", "left"] => InputEvent::CursorLeft,
+            ["cursor", x, y] => InputEvent::Cursor(vec(x, y)?),
+            ["scroll", "lines", x, y] => InputEvent::ScrollLines(vec(x, y)?),
+            ["scroll", "pixels", x, y] => InputEvent::ScrollPixels(vec(x, y)?),
+            ["modifiers", names @ ..] => {
+                let mut modifiers = ModifiersState::empty();
+                for name in names {
+                    let (_, modifier) = MODIFIER_NAMES.iter().find(|(other, _)| other == name)?;
+                    modifiers |= *modifier;
+                }
+                InputEvent::Modifiers(modifiers)
+            }
+            ["touch", id, phase, x, y] => InputEvent::Touch {
+                id: id.parse().ok()?,
+                phase: match *phase {
+                    "started" => TouchPhase::Started,
+                    "moved" => TouchPhase::Moved,
+                    "ended" => TouchPhase::Ended,
+                    "cancelled" => TouchPhase::Cancelled,
+                    _ => return None,
+                },
+                position: vec(x, y)?,
+            },
+            ["pad", id, rest @ ..] => {
+                let id = GamepadId(id.parse().ok()?);
+                InputEvent::Gamepad(match rest {
+                    ["connected"] => GamepadEvent::Connected(id),
+                    ["disconnected"] => GamepadEvent::Disconnected(id),
+                    ["button", button, state] => GamepadEvent::Button {
+                        id,
+                        button: gamepad_button_from_name(button)?,
+                        pressed: pressed(state)?,
+                    },
+                    ["axis", axis, value] => GamepadEvent::Axis {
+                        id,
+                        axis: GamepadAxis::from_name(axis)?,
+                        value: value.parse().ok()?,
+                    },
+                    _ => return None,
+                })
+            }
+            [binding, state] => match Binding::parse(binding)? {
+                Binding::Key(code) => InputEvent::Key { code, pressed: pressed(state)? },
+                Binding::Mouse(button) => InputEvent::Mouse { button, pressed: pressed(state)? },
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// A recorded session: the input events of every tick.
+#[derive(Clone, Debug, Default)]
+pub struct InputLog {
+    /// Sorted by tick.
+    events: Vec<(u64, InputEvent)>,
+    /// How many ticks were recorded, including quiet ones at the end.
+    ticks: u64,
+    /// The state the recording started from, as named by the Gameplay.
+    start: Option<String>,
+}
+
+impl InputLog {
+    pub(crate) fn new(start: Option<&str>) -> Self {
+        InputLog { start: start.map(str::to_string), ..Default::default() }
+    }
+
+    pub fn events(&self) -> &[(u64, InputEvent)] {
+        &self.events
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn start(&self) -> Option<&str> {
+        self.start.as_deref()
+    }
+
+    pub(crate) fn push(&mut self, tick: u64, event: InputEvent) {
+        self.events.push((tick, event));
+        self.ticks = self.ticks.max(tick + 1);
+    }
+
+    pub(crate) fn finish(&mut self, ticks: u64) {
+        self.ticks = self.ticks.max(ticks);
+    }
+
+    /// Writes the log in the text format described at the top of this file.
+    pub fn to_text(&self) -> String {
+        let mut out = format!("ticks {}\n", self.ticks);
+        if let Some(start) = &self.start {
+            out.push_str(&format!("start {}\n", start));
+        }
+        for (tick, event) in &self.events {
+            out.push_str(&format!("{} {}\n", tick, event.to_text()));
+        }
+        out
+    }
+
+    pub fn from_text(text: &str) -> EngineResult<Self> {
+        let mut log = InputLog::default();
+        for (idx, line) in text.lines().enumerate() {
+            let error = |message: &str| EngineError::Config { line: idx + 1, message: message.to_string() };
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => continue,
+                ["ticks", ticks] => {
+                    let ticks = ticks.parse().map_err(|_| error("invalid tick count"))?;
+                    log.finish(ticks);
+                }
+                ["start", start @ ..] => log.start = Some(start.join(" ")),
+                [tick, rest @ ..] => {
+                    let tick: u64 = tick.parse().map_err(|_| error("invalid tick"))?;
+                    if log.events.last().is_some_and(|(last, _)| *last > tick) {
+                        return Err(error("ticks are out of order"));
+                    }
+                    let event = InputEvent::parse(rest).ok_or_else(|| error("invalid event"))?;
+                    log.push(tick, event);
+                }
+            }
+        }
+        Ok(log)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_file(path: impl AsRef<std::path::Path>) -> EngineResult<Self> {
+        let text = std::fs::read_to_string(path.as_ref())
+            .map_err(|err| EngineError::new(format!("failed to read {}: {}", path.as_ref().display(), err)))?;
+        Self::from_text(&text)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_file(&self, path: impl AsRef<std::path::Path>) -> EngineResult<()> {
+        std::fs::write(path.as_ref(), self.to_text())
+            .map_err(|err| EngineError::new(format!("failed to write {}: {}", path.as_ref().display(), err)))
+    }
+}
+
+/// An InputLog being played back.
+pub(crate) struct Replay {
+    pub(crate) log: InputLog,
+    /// The index of the next event to apply.
+    pub(crate) next: usize,
+    /// The last cursor position in the log so far.
+    pub(crate) cursor: Vector2<f32>,
+}
+
+impl Replay {
+    pub(crate) fn new(log: InputLog) -> Self {
+        Replay { log, next: 0, cursor: Vector2::new(0.0, 0.0) }
+    }
+
+    /// Takes every event for the given tick, and any before it.
+    pub(crate) fn take_events(&mut self, tick: u64) -> Vec<InputEvent> {
+        let start = self.next;
+        while self.log.events.get(self.next).is_some_and(|(event_tick, _)| *event_tick <= tick) {
+            self.next += 1;
+        }
+        self.log.events[start..self.next].iter().map(|(_, event)| *event).collect()
+    }
+}
@@ -0,0 +1,322 @@
+// Touch points and gestures.
+//
+// TouchTracker keeps every finger that is on the screen, from the event that
+// started it until the end of the tick in which it was lifted, so that even a
+// touch that starts and ends between two ticks is seen by the Gameplay.
+// Gestures are recognized once at the start of each tick, and tracked like
+// buttons, so they have the same just-started/just-ended semantics.
+
+use std::collections::BTreeMap;
+
+use cgmath::{InnerSpace, Vector2};
+
+use crate::input::ButtonStateTracker;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TouchPhase {
+    /// The finger went down since the last tick.
+    Started,
+    /// The finger is still down.
+    Held,
+    /// The finger was lifted since the last tick. The point is removed at the
+    /// end of this tick.
+    Ended,
+    /// The system took the touch away, e.g. for a system gesture. Never
+    /// counts as a tap.
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TouchPoint {
+    pub id: u64,
+    pub phase: TouchPhase,
+    /// Where the finger went down, in physical pixels.
+    pub start: Vector2<f32>,
+    pub position: Vector2<f32>,
+    /// The position at the end of the last tick.
+    pub last_position: Vector2<f32>,
+    /// How many ticks the finger has been down for.
+    pub ticks: u32,
+
+    /// Whether the finger ever moved further than TouchSettings::slop from
+    /// its start.
+    moved: bool,
+    /// Whether another finger was down at some point during this touch.
+    multi: bool,
+}
+
+impl TouchPoint {
+    pub fn is_down(&self) -> bool {
+        matches!(self.phase, TouchPhase::Started | TouchPhase::Held)
+    }
+
+    /// How far the finger moved since the last tick.
+    pub fn delta(&self) -> Vector2<f32> {
+        self.position - self.last_position
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GestureKind {
+    /// A single finger went down and up quickly, without moving. Active for
+    /// exactly one tick.
+    Tap,
+    /// A single finger is held down without moving.
+    LongPress,
+    /// A single finger is moving.
+    Drag,
+    /// Two fingers are moving apart or together.
+    Pinch,
+    /// Two fingers are moving in the same direction.
+    Pan,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Drag {
+    pub id: u64,
+    pub start: Vector2<f32>,
+    pub position: Vector2<f32>,
+    /// Movement since the last tick.
+    pub delta: Vector2<f32>,
+}
+
+/// A two finger gesture. Both Pinch and Pan report the same data, as they
+/// often happen at the same time.
+#[derive(Clone, Copy, Debug)]
+pub struct TwoFinger {
+    /// The point between the two fingers.
+    pub center: Vector2<f32>,
+    /// How far the center moved since the last tick.
+    pub delta: Vector2<f32>,
+    /// The current distance between the fingers, divided by the distance when
+    /// the second finger went down.
+    pub scale: f32,
+    /// The same, relative to the last tick.
+    pub scale_delta: f32,
+}
+
+/// The thresholds used to tell gestures apart.
+#[derive(Clone, Copy, Debug)]
+pub struct TouchSettings {
+    /// How far, in pixels, a finger may move before it counts as moving.
+    pub slop: f32,
+    /// The longest a tap can be held, in ticks.
+    pub tap_ticks: u32,
+    /// How long a finger must be held before it is a long press, in ticks.
+    pub long_press_ticks: u32,
+}
+
+impl Default for TouchSettings {
+    fn default() -> Self {
+        TouchSettings {
+            slop: 12.0,
+            tap_ticks: 15,
+            long_press_ticks: 30,
+        }
+    }
+}
+
+/// The fingers of a two finger gesture, and where they started.
+struct TwoFingerStart {
+    ids: (u64, u64),
+    center: Vector2<f32>,
+    distance: f32,
+}
+
+pub(crate) struct TouchTracker {
+    points: BTreeMap<u64, TouchPoint>,
+    gestures: ButtonStateTracker<GestureKind>,
+    pub(crate) settings: TouchSettings,
+
+    tap: Option<Vector2<f32>>,
+    long_press: Option<u64>,
+    drag: Option<u64>,
+    two_finger_start: Option<TwoFingerStart>,
+    two_finger: Option<TwoFinger>,
+}
+
+impl TouchTracker {
+    pub fn new() -> Self {
+        TouchTracker {
+            points: BTreeMap::new(),
+            gestures: ButtonStateTracker::new(),
+            settings: TouchSettings::default(),
+
+            tap: None,
+            long_press: None,
+            drag: None,
+            two_finger_start: None,
+            two_finger: None,
+        }
+    }
+
+    pub fn update(&mut self, id: u64, phase: winit::event::TouchPhase, position: Vector2<f32>) {
+        use winit::event::TouchPhase as Winit;
+
+        match phase {
+            Winit::Started => {
+                self.points.insert(id, TouchPoint {
+                    id,
+                    phase: TouchPhase::Started,
+                    start: position,
+                    position,
+                    last_position: position,
+                    ticks: 0,
+                    moved: false,
+                    multi: false,
+                });
+            }
+            Winit::Moved | Winit::Ended | Winit::Cancelled => {
+                let Some(point) = self.points.get_mut(&id) else { return; };
+                if !point.is_down() {
+                    return;
+                }
+
+                point.position = position;
+                if (position - point.start).magnitude() > self.settings.slop {
+                    point.moved = true;
+                }
+                match phase {
+                    Winit::Ended => point.phase = TouchPhase::Ended,
+                    Winit::Cancelled => point.phase = TouchPhase::Cancelled,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Whether any finger is down.
+    pub fn any_down(&self) -> bool {
+        self.points.values().any(TouchPoint::is_down)
+    }
+
+    pub fn points(&self) -> impl Iterator<Item = &TouchPoint> {
+        self.points.values()
+    }
+
+    pub fn point(&self, id: u64) -> Option<&TouchPoint> {
+        self.points.get(&id)
+    }
+
+    /// Recognizes the gestures for this tick.
+    pub fn tick_start(&mut self) {
+        let down: Vec<u64> = self.points.values()
+            .filter(|point| point.is_down())
+            .map(|point| point.id)
+            .collect();
+
+        if down.len() > 1 {
+            for id in &down {
+                self.points.get_mut(id).unwrap().multi = true;
+            }
+        }
+
+        // A lifted finger that never moved, and was alone the whole time.
+        self.tap = self.points.values()
+            .find(|point| point.phase == TouchPhase::Ended
+                && !point.moved
+                && !point.multi
+                && point.ticks <= self.settings.tap_ticks)
+            .map(|point| point.position);
+
+        let single = match down.as_slice() {
+            [id] => self.points.get(id).filter(|point| !point.multi),
+            _ => None,
+        };
+        self.drag = single.filter(|point| point.moved).map(|point| point.id);
+        self.long_press = single
+            .filter(|point| !point.moved && point.ticks >= self.settings.long_press_ticks)
+            .map(|point| point.id);
+
+        self.update_two_finger(&down);
+
+        let slop = self.settings.slop;
+        let (pinching, panning) = match (&self.two_finger_start, &self.two_finger) {
+            (Some(start), Some(current)) => (
+                (current.scale - 1.0).abs() * start.distance > slop,
+                (current.center - start.center).magnitude() > slop,
+            ),
+            _ => (false, false),
+        };
+
+        self.gestures.update(GestureKind::Tap, self.tap.is_some());
+        self.gestures.update(GestureKind::LongPress, self.long_press.is_some());
+        self.gestures.update(GestureKind::Drag, self.drag.is_some());
+        self.gestures.update(GestureKind::Pinch, pinching);
+        self.gestures.update(GestureKind::Pan, panning);
+    }
+
+    fn update_two_finger(&mut self, down: &[u64]) {
+        let [a, b] = down else {
+            self.two_finger_start = None;
+            self.two_finger = None;
+            return;
+        };
+        let (a, b) = (self.points[a], self.points[b]);
+
+        let center = (a.position + b.position) * 0.5;
+        let distance = (a.position - b.position).magnitude();
+        let last_center = (a.last_position + b.last_position) * 0.5;
+        let last_distance = (a.last_position - b.last_position).magnitude();
+
+        // Start over whenever the pair of fingers changes.
+        if self.two_finger_start.as_ref().map(|start| start.ids) != Some((a.id, b.id)) {
+            self.two_finger_start = Some(TwoFingerStart { ids: (a.id, b.id), center, distance });
+            self.two_finger = None;
+        }
+        let start = self.two_finger_start.as_ref().unwrap();
+
+        // A fresh pair has nothing to compare to yet.
+        let fresh = self.two_finger.is_none();
+        self.two_finger = Some(TwoFinger {
+            center,
+            delta: if fresh { Vector2::new(0.0, 0.0) } else { center - last_center },
+            scale: if start.distance > 0.0 { distance / start.distance } else { 1.0 },
+            scale_delta: if fresh || last_distance <= 0.0 { 1.0 } else { distance / last_distance },
+        });
+    }
+
+    pub fn tick_end(&mut self) {
+        self.points.retain(|_, point| point.is_down());
+        for point in self.points.values_mut() {
+            point.phase = TouchPhase::Held;
+            point.last_position = point.position;
+            point.ticks += 1;
+        }
+        self.gestures.tick_end();
+    }
+
+    pub fn is_active(&self, gesture: GestureKind) -> bool {
+        self.gestures.is_pressed(&gesture)
+    }
+
+    pub fn is_just_started(&self, gesture: GestureKind) -> bool {
+        self.gestures.is_just_pressed(&gesture)
+    }
+
+    pub fn is_just_ended(&self, gesture: GestureKind) -> bool {
+        self.gestures.is_just_released(&gesture)
+    }
+
+    pub fn tap(&self) -> Option<Vector2<f32>> {
+        self.tap
+    }
+
+    pub fn long_press(&self) -> Option<&TouchPoint> {
+        self.long_press.and_then(|id| self.points.get(&id))
+    }
+
+    pub fn drag(&self) -> Option<Drag> {
+        let point = self.points.get(&self.drag?)?;
+        Some(Drag {
+            id: point.id,
+            start: point.start,
+            position: point.position,
+            delta: point.delta(),
+        })
+    }
+
+    pub fn two_finger(&self) -> Option<TwoFinger> {
+        self.two_finger
+    }
+}
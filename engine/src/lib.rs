@@ -2,9 +2,9 @@ use cgmath::Vector2;
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
-use winit::{application::ApplicationHandler, event::WindowEvent, event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopProxy}};
+use winit::{application::ApplicationHandler, event::WindowEvent, event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopProxy}, window::WindowId};
 
-use crate::{gc::Gp, video::{camera::Camera, hdr_tonemap::Tonemap, world::{Viewport, World}, RenderCtx, Video, Window}};
+use crate::{gc::{Gp, Trace}, video::{camera::Camera, hdr_tonemap::Tonemap, world::{Viewport, ViewportRect, World}, HeadlessTarget, RenderCtx, Video, Window, WindowOptions, WindowRequest}};
 
 pub use winit;
 
@@ -15,6 +15,9 @@ pub mod gc;
 pub mod gc_types;
 pub mod ui;
 pub mod input;
+pub mod tween;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod headless;
 
 /// Our custom user event for winit. Used in part for asynchronously initializing
 /// the app in browser.
@@ -26,13 +29,12 @@ pub struct Engine {
     pub video: crate::Video,
     pub audio: crate::audio::Audio,
     pub input: crate::input::Input,
+    pub tweens: crate::tween::Tweens,
 
     // TODO: We really need to be able to access the Window, Viewport, etc...
     pub main_world: Gp<World>,
     pub main_camera: Gp<Camera>,
 
-    pub egui: ui::Egui,
-
     last_tick: web_time::Instant,
     accumulator: web_time::Duration,
 }
@@ -41,6 +43,10 @@ pub trait Gameplay {
     const GAME_TITLE: &'static str;
     const DEFAULT_TONEMAP: Tonemap;
 
+    /// The maximum number of lights a World can render at once. Any lights
+    /// past this are ignored.
+    const MAX_LIGHTS: usize = 16;
+
     fn new(engine: &mut Engine) -> Self;
 
     /// The framerate-independent tick function. Similar to _physics_process
@@ -49,13 +55,32 @@ pub trait Gameplay {
         let _ = engine;
     }
 
-    /// Callback for the Gameplay to draw a GUI. This currently applies only
-    /// to the main window. Hopefully in the future it will be more flexible.
-    fn ui(&mut self, engine: &mut Engine, ctx: &egui::Context) {
+    /// Callback for the Gameplay to draw a GUI. Called for every window when
+    /// it redraws, each with its own egui context. Compare the window with
+    /// Engine::main_window_id() to tell the game window apart from any
+    /// opened with Engine::open_window().
+    fn ui(&mut self, engine: &mut Engine, window: WindowId, ctx: &egui::Context) {
         let _ = engine;
+        let _ = window;
         let _ = ctx;
     }
 
+    /// Called once a window asked for with Engine::open_window() has been
+    /// created.
+    fn window_opened(&mut self, engine: &mut Engine, request: WindowRequest, window: WindowId) {
+        let _ = engine;
+        let _ = request;
+        let _ = window;
+    }
+
+    /// Called by Headless::replay() before playing a log that names the state
+    /// its recording started from, see Input::start_recording(). Should put
+    /// the Gameplay back into that state, e.g. by opening the level.
+    fn replay_start(&mut self, engine: &mut Engine, start: &str) {
+        let _ = engine;
+        let _ = start;
+    }
+
     /// Callback for the Gameplay to handle winit WindowEvents with custom 
     /// logic.
     fn event(&mut self, engine: &mut Engine, event: &winit::event::WindowEvent) {
@@ -72,38 +97,194 @@ struct EngineApp<G: Gameplay> {
 }
 
 impl Engine {
+    /// The number of times per second that Gameplay::tick is called.
+    pub const TICKS_PER_SECOND: u64 = 60;
+
+    pub(crate) fn from_parts(video: Video, audio: audio::Audio, main_world: Gp<World>, main_camera: Gp<Camera>) -> Self {
+        // The main world and camera stay alive for as long as the engine does.
+        gc::add_root(&main_world);
+        gc::add_root(&main_camera);
+
+        Engine {
+            video,
+            audio,
+            input: crate::input::Input::new(),
+            tweens: crate::tween::Tweens::default(),
+            main_world,
+            main_camera,
+
+            accumulator: web_time::Duration::from_micros(0),
+            last_tick: web_time::Instant::now(),
+        }
+    }
+
     pub fn get_cursor_position(&self) -> Vector2<f32> {
-        // TODO: Don't use unwrap...
-        self.video.id_map.iter().next().unwrap().1.cursor_position
+        if let Some(position) = self.input.replay_cursor() {
+            return position;
+        }
+
+        match self.get_main_window() {
+            Some(window) => window.cursor_position,
+            None => self.video.headless.as_ref().map(|headless| headless.cursor_position)
+                .unwrap_or(Vector2::new(0.0, 0.0)),
+        }
     }
 
-    pub fn get_main_window(&self) -> &Window {
-        self.video.id_map.iter().next().unwrap().1
+    /// Returns the main window, or None if we are running headless.
+    pub fn get_main_window(&self) -> Option<&Window> {
+        self.get_window(self.video.main_window?)
+    }
+    pub fn get_main_window_mut(&mut self) -> Option<&mut Window> {
+        self.get_window_mut(self.video.main_window?)
+    }
+
+    /// The id of the main window, or HeadlessTarget::WINDOW_ID if we are
+    /// running headless.
+    pub fn main_window_id(&self) -> WindowId {
+        self.video.main_window.unwrap_or(HeadlessTarget::WINDOW_ID)
+    }
+
+    /// Returns the given window, or None if it has been closed.
+    pub fn get_window(&self, id: WindowId) -> Option<&Window> {
+        self.video.id_map.get(&id)
+    }
+    pub fn get_window_mut(&mut self, id: WindowId) -> Option<&mut Window> {
+        self.video.id_map.get_mut(&id)
+    }
+
+    /// Opens another window, with its own Viewport, camera and egui context,
+    /// e.g. for a level editor next to the game. The window is created
+    /// between events, after which Gameplay::window_opened() is called. Input
+    /// in it only reaches its egui context, not Engine::input.
+    ///
+    /// Not supported on the web or when running headless, where the request
+    /// is dropped.
+    pub fn open_window(&mut self, options: WindowOptions) -> WindowRequest {
+        self.video.request_window(options)
+    }
+
+    /// Closes a window opened with open_window(). The main window can't be
+    /// closed this way.
+    pub fn close_window(&mut self, id: WindowId) {
+        if self.video.main_window != Some(id) {
+            self.video.id_map.remove(&id);
+        }
+    }
+
+    /// Creates the windows asked for with open_window().
+    fn open_requested_windows<G: Gameplay>(&mut self, gameplay: &mut G, event_loop: &ActiveEventLoop) {
+        for (request, options) in std::mem::take(&mut self.video.window_requests) {
+            if cfg!(target_arch = "wasm32") {
+                log::warn!("cannot open window '{}': extra windows are not supported on the web", options.title);
+                continue;
+            }
+
+            let world = options.world.unwrap_or_else(|| self.main_world.clone());
+            let camera = options.camera.unwrap_or_else(|| Gp::new(Camera::demo()));
+            match self.video.create_window(event_loop, &options.title, options.size, world, camera, G::DEFAULT_TONEMAP) {
+                Ok(window) => gameplay.window_opened(self, request, window),
+                Err(err) => log::warn!("cannot open window '{}': {}", options.title, err),
+            }
+        }
     }
-    pub fn get_main_window_mut(&mut self) -> &mut Window {
-        self.video.id_map.iter_mut().next().unwrap().1
+
+    /// Lets the main window receive composed text from an input method, e.g.
+    /// for typing in a level editor. See Input::text().
+    pub fn set_ime_allowed(&self, allowed: bool) {
+        if let Some(window) = self.get_main_window() {
+            window.sdl.set_ime_allowed(allowed);
+        }
     }
 
     pub fn get_viewport(&self) -> &Viewport {
-        &self.video.id_map.iter().next().unwrap().1.renderer.viewport
+        self.video.main_viewport()
+    }
+
+    /// Moves a window's main Viewport to part of the window, e.g. the left
+    /// half for split-screen.
+    pub fn set_viewport_rect(&mut self, window: WindowId, rect: ViewportRect) {
+        if let Some(window) = self.video.id_map.get_mut(&window) {
+            window.renderer.viewport.set_rect(&self.video.renderer.ctx, rect);
+        }
+    }
+
+    /// Adds a Viewport on top of a window's others, e.g. a minimap or the
+    /// other half of a split-screen. A world of None shows the main world.
+    /// Returns None if the window doesn't exist.
+    pub fn add_overlay(&mut self, window: WindowId, rect: ViewportRect, camera: Gp<Camera>, world: Option<Gp<World>>, tonemap: Tonemap) -> Option<&mut Viewport> {
+        let world = world.unwrap_or_else(|| self.main_world.clone());
+        let window = self.video.id_map.get_mut(&window)?;
+        Some(window.renderer.add_overlay(&self.video.renderer.ctx, world, camera, rect, tonemap))
     }
 
     pub fn render_ctx(&self) -> &RenderCtx {
         &self.video.renderer.ctx
     }
 
+    /// Frees every GC object that is not reachable from the engine (the main
+    /// world and camera, the window viewports, the built-in shaders, and
+    /// running tweens) or from the given extra roots. Returns how many objects
+    /// were freed.
+    ///
+    /// The Gameplay must pass in everything it is still holding on to, as any
+    /// Gp that is not reachable afterwards is dangling. This is best called
+    /// at a quiet point, e.g. between levels. If any traced RefCell is
+    /// mutably borrowed at the time, nothing is freed.
+    pub fn collect_garbage(&self, extra_roots: &dyn Trace) -> usize {
+        gc::collect(&[&self.video, &self.tweens, extra_roots])
+    }
+
+    /// Runs exactly one fixed-size tick, regardless of how much time has
+    /// passed.
+    pub fn step<G: Gameplay>(&mut self, gameplay: &mut G) {
+        self.input.tick_start();
+        if !self.input.is_replaying() {
+            self.emulate_cursor();
+        }
+        let cursor = self.get_cursor_position();
+        self.input.update_cursor(cursor);
+
+        gameplay.tick(self);
+        tween::Tweens::advance(self, 1.0 / Self::TICKS_PER_SECOND as f32);
+        self.audio.set_listener(audio::spatial::Listener::from_camera(&self.main_camera));
+        self.audio.tick(1.0 / Self::TICKS_PER_SECOND as f32);
+        // Update input at the end of the tick.
+        self.input.tick_end();
+    }
+
+    /// Moves the cursor with the gamepad stick, if Input::cursor_emulation is
+    /// set.
+    fn emulate_cursor(&mut self) {
+        let delta = self.input.emulated_cursor_delta(1.0 / Self::TICKS_PER_SECOND as f32);
+        if delta == Vector2::new(0.0, 0.0) {
+            return;
+        }
+
+        // The cursor can go anywhere in the window, not just the main Viewport.
+        let (width, height) = self.get_viewport().target_size();
+        let (width, height) = (width as f32, height as f32);
+        let clamp = |position: Vector2<f32>| Vector2::new(position.x.clamp(0.0, width), position.y.clamp(0.0, height));
+
+        if let Some(window) = self.get_main_window_mut() {
+            window.cursor_position = clamp(window.cursor_position + delta);
+        }
+        else if let Some(headless) = self.video.headless.as_mut() {
+            headless.cursor_position = clamp(headless.cursor_position + delta);
+        }
+    }
+
     pub fn maybe_tick<G: Gameplay>(&mut self, gameplay: &mut G) {
         let now = web_time::Instant::now();
 
         let elapsed = now - self.last_tick;
         let mut total = elapsed + self.accumulator;
 
-        let step_size = web_time::Duration::from_nanos(1_000_000_000 / 60);
+        let step_size = web_time::Duration::from_nanos(1_000_000_000 / Self::TICKS_PER_SECOND);
 
         // If we are really far behind, just tick once (?) and then update the
         // instant to now.  The idea being that this happens during loading and such.
         if total >= step_size * 16 {
-            gameplay.tick(self);
+            self.step(gameplay);
             self.accumulator = web_time::Duration::from_micros(0);
             self.last_tick = now;
             return;
@@ -114,9 +295,7 @@ impl Engine {
             while total >= step_size {
                 total -= step_size;
                
-                gameplay.tick(self);
-                // Update input at the end of the tick.
-                self.input.tick_end();
+                self.step(gameplay);
 
                 max_loops -= 1;
                 if max_loops <= 0 { break; }
@@ -136,12 +315,16 @@ impl<G: Gameplay> ApplicationHandler<EngineAppEvent> for EngineApp<G> {
 
     fn window_event(
         &mut self,
-        _event_loop: &winit::event_loop::ActiveEventLoop,
+        event_loop: &winit::event_loop::ActiveEventLoop,
         window_id: winit::window::WindowId,
         event: winit::event::WindowEvent,
     ) {
         let Some((engine, gameplay)) = self.inner.as_mut() else { return; };
 
+        // The mouse and keyboard only drive the game in the main window.
+        // Other windows only see them through their egui context.
+        let is_main = engine.video.main_window == Some(window_id);
+
         #[cfg(target_arch = "wasm32")]
         match &event {
             winit::event::WindowEvent::MouseInput { .. } | winit::event::WindowEvent::Touch(_) => {
@@ -151,13 +334,35 @@ impl<G: Gameplay> ApplicationHandler<EngineAppEvent> for EngineApp<G> {
         }
 
         match &event {
-            WindowEvent::KeyboardInput { device_id: _, event, is_synthetic: _ } => {
+            WindowEvent::KeyboardInput { device_id: _, event, is_synthetic: _ } if is_main => {
                 engine.input.update_button(input::AnyButton::PhysicalKey(event.physical_key),
                     event.state.is_pressed());
+                if event.state.is_pressed() && let Some(text) = &event.text {
+                    engine.input.update_text(text);
+                }
+            }
+            WindowEvent::Ime(ime) if is_main => {
+                engine.input.update_ime(ime);
+            }
+            WindowEvent::ModifiersChanged(modifiers) if is_main => {
+                engine.input.update_modifiers(modifiers.state());
+            }
+            WindowEvent::MouseWheel { device_id: _, delta, phase: _ } if is_main => {
+                engine.input.update_scroll(*delta);
             }
-            WindowEvent::MouseInput { device_id: _, state, button } => {
+            WindowEvent::CursorEntered { .. } if is_main => {
+                engine.input.update_cursor_inside(true);
+            }
+            WindowEvent::CursorLeft { .. } if is_main => {
+                engine.input.update_cursor_inside(false);
+            }
+            WindowEvent::MouseInput { device_id: _, state, button } if is_main => {
                 engine.input.update_button(input::AnyButton::Mouse(*button), state.is_pressed());
             }
+            WindowEvent::Touch(touch) if is_main => {
+                engine.input.update_touch(touch.id, touch.phase,
+                    Vector2::new(touch.location.x as f32, touch.location.y as f32));
+            }
             _ => {}
         }
 
@@ -174,7 +379,7 @@ impl<G: Gameplay> ApplicationHandler<EngineAppEvent> for EngineApp<G> {
             {
                 // This will blow up on WASM, but we shouldn't be exiting anyway,
                 // so, it's OK...
-                _event_loop.exit();
+                event_loop.exit();
             }
         }
 
@@ -188,16 +393,18 @@ impl<G: Gameplay> ApplicationHandler<EngineAppEvent> for EngineApp<G> {
         // }
 
         engine.maybe_tick(gameplay);
+        engine.open_requested_windows(gameplay, event_loop);
 
         //engine.video.render();
     }
 
-    fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: EngineAppEvent) {
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, event: EngineAppEvent) {
         match event {
             EngineAppEvent::Initialize(mut engine) => {
                 engine.video.update_all_window_sizes();
 
-                let gameplay = G::new(&mut engine);
+                let mut gameplay = G::new(&mut engine);
+                engine.open_requested_windows(&mut gameplay, event_loop);
 
                 // Remove the loading screen once the initial loading is done.
                 Video::wasm_remove_loading_screen();
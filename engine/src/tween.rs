@@ -0,0 +1,386 @@
+// Tweens animate a Cell on some Gp object from its current value to a target
+// value over time. They are owned by Engine::tweens and advanced once per
+// tick, right after Gameplay::tick, so a running tween always wins over a
+// value that the Gameplay set during the same tick.
+//
+// Usage:
+//
+// engine.tweens.add(Tween::new(&instance, |i| &i.transform, target, 0.12)
+//     .ease(Easing::CubicOut)
+//     .call(|engine| { engine.audio.play(&click); }));
+
+use std::{cell::Cell, collections::VecDeque};
+
+use cgmath::{Matrix4, Point3, Vector3, Vector4};
+
+use crate::{gc::{Gc, Gp, Trace, Tracer}, video::camera::CameraProjection, Engine};
+
+/// A value that can be interpolated by a Tween.
+pub trait Lerp: Copy + 'static {
+    fn lerp(from: Self, to: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        from + (to - from) * t
+    }
+}
+
+impl Lerp for Point3<f32> {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        from + (to - from) * t
+    }
+}
+
+impl Lerp for Vector3<f32> {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        from + (to - from) * t
+    }
+}
+
+impl Lerp for Vector4<f32> {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        from + (to - from) * t
+    }
+}
+
+/// Interpolates each element separately. This is exact for translations and
+/// scales, but a rotation will shrink a bit halfway through.
+impl Lerp for Matrix4<f32> {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        from + (to - from) * t
+    }
+}
+
+/// Switching between perspective and orthographic can't be interpolated, so
+/// that switches right away.
+impl Lerp for CameraProjection {
+    fn lerp(from: Self, to: Self, t: f32) -> Self {
+        match (from, to) {
+            (CameraProjection::Orthographic { zoom: a }, CameraProjection::Orthographic { zoom: b }) =>
+                CameraProjection::Orthographic { zoom: f32::lerp(a, b, t) },
+            (CameraProjection::Perspective { fovy: fa, znear: na, zfar: fa2 },
+             CameraProjection::Perspective { fovy: fb, znear: nb, zfar: fb2 }) =>
+                CameraProjection::Perspective {
+                    fovy: f32::lerp(fa, fb, t),
+                    znear: f32::lerp(na, nb, t),
+                    zfar: f32::lerp(fa2, fb2, t),
+                },
+            _ => to,
+        }
+    }
+}
+
+/// Maps the linear progress of a tween (0 to 1) to the progress of the value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Easing {
+    #[default]
+    Linear,
+    QuadIn,
+    QuadOut,
+    QuadInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    SineIn,
+    SineOut,
+    SineInOut,
+    /// Overshoots the target a little before settling.
+    BackOut,
+}
+
+impl Easing {
+    pub fn apply(self, t: f32) -> f32 {
+        use std::f32::consts::PI;
+
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::QuadIn => t * t,
+            Easing::QuadOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::QuadInOut => if t < 0.5 {
+                2.0 * t * t
+            }
+            else {
+                1.0 - (-2.0 * t + 2.0).powi(2) / 2.0
+            },
+            Easing::CubicIn => t * t * t,
+            Easing::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Easing::CubicInOut => if t < 0.5 {
+                4.0 * t * t * t
+            }
+            else {
+                1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+            },
+            Easing::SineIn => 1.0 - f32::cos(t * PI / 2.0),
+            Easing::SineOut => f32::sin(t * PI / 2.0),
+            Easing::SineInOut => -(f32::cos(PI * t) - 1.0) / 2.0,
+            Easing::BackOut => {
+                const C1: f32 = 1.70158;
+                const C3: f32 = C1 + 1.0;
+                1.0 + C3 * (t - 1.0).powi(3) + C1 * (t - 1.0).powi(2)
+            }
+        }
+    }
+}
+
+/// One step of a Tween's sequence.
+trait TweenStep: Trace {
+    /// Advances the step by `dt` seconds. Returns the time that was left over
+    /// if the step finished.
+    fn advance(&mut self, engine: &mut Engine, dt: f32) -> Option<f32>;
+
+    fn set_easing(&mut self, easing: Easing) {
+        let _ = easing;
+    }
+
+    /// The address of the Cell this step animates, if any.
+    fn property(&self) -> Option<usize> {
+        None
+    }
+}
+
+struct PropertyStep<T: Gc, V: Lerp> {
+    object: Gp<T>,
+    property: fn(&T) -> &Cell<V>,
+    /// Captured when the step starts, so that a sequence continues from
+    /// wherever the previous step left off.
+    from: Option<V>,
+    to: V,
+    duration: f32,
+    elapsed: f32,
+    easing: Easing,
+}
+
+impl<T: Gc, V: Lerp> Trace for PropertyStep<T, V> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.object.trace(tracer);
+    }
+}
+
+impl<T: Gc, V: Lerp> TweenStep for PropertyStep<T, V> {
+    fn advance(&mut self, _engine: &mut Engine, dt: f32) -> Option<f32> {
+        let cell = (self.property)(&self.object);
+        let from = *self.from.get_or_insert_with(|| cell.get());
+
+        self.elapsed += dt;
+        if self.elapsed >= self.duration {
+            cell.set(self.to);
+            return Some(self.elapsed - self.duration);
+        }
+
+        let t = self.easing.apply(self.elapsed / self.duration);
+        cell.set(V::lerp(from, self.to, t));
+        None
+    }
+
+    fn set_easing(&mut self, easing: Easing) {
+        self.easing = easing;
+    }
+
+    fn property(&self) -> Option<usize> {
+        Some((self.property)(&self.object) as *const Cell<V> as usize)
+    }
+}
+
+struct WaitStep {
+    remaining: f32,
+}
+
+impl Trace for WaitStep {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+impl TweenStep for WaitStep {
+    fn advance(&mut self, _engine: &mut Engine, dt: f32) -> Option<f32> {
+        self.remaining -= dt;
+        if self.remaining <= 0.0 {
+            return Some(-self.remaining);
+        }
+        None
+    }
+}
+
+type TweenCallback = Box<dyn FnOnce(&mut Engine)>;
+
+struct CallStep {
+    callback: Option<TweenCallback>,
+}
+
+impl Trace for CallStep {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+impl TweenStep for CallStep {
+    fn advance(&mut self, engine: &mut Engine, dt: f32) -> Option<f32> {
+        if let Some(callback) = self.callback.take() {
+            callback(engine);
+        }
+        Some(dt)
+    }
+}
+
+/// A sequence of steps: animating properties, waiting, and calling back into
+/// the Gameplay. Build one with Tween::new() and hand it to Tweens::add().
+#[must_use = "a Tween does nothing until it is added to Engine::tweens"]
+pub struct Tween {
+    steps: VecDeque<Box<dyn TweenStep>>,
+}
+
+impl Tween {
+    /// Animates the given property of `object` from its value at the time the
+    /// step starts to `to`, over `seconds`. Linear unless ease() is called.
+    pub fn new<T: Gc + 'static, V: Lerp>(object: &Gp<T>, property: fn(&T) -> &Cell<V>, to: V, seconds: f32) -> Self {
+        Tween::empty().then(Tween::step(PropertyStep {
+            object: object.clone(),
+            property,
+            from: None,
+            to,
+            duration: seconds,
+            elapsed: 0.0,
+            easing: Easing::Linear,
+        }))
+    }
+
+    /// A Tween that does nothing for the given time. Useful for delaying a
+    /// call().
+    pub fn delay(seconds: f32) -> Self {
+        Tween::empty().wait(seconds)
+    }
+
+    fn empty() -> Self {
+        Tween { steps: VecDeque::new() }
+    }
+
+    fn step(step: impl TweenStep + 'static) -> Self {
+        let mut tween = Tween::empty();
+        tween.steps.push_back(Box::new(step));
+        tween
+    }
+
+    /// Sets the easing of the last property step.
+    pub fn ease(mut self, easing: Easing) -> Self {
+        if let Some(step) = self.steps.back_mut() {
+            step.set_easing(easing);
+        }
+        self
+    }
+
+    /// Runs `next` once everything so far has finished.
+    pub fn then(mut self, next: Tween) -> Self {
+        self.steps.extend(next.steps);
+        self
+    }
+
+    pub fn wait(self, seconds: f32) -> Self {
+        self.then(Tween::step(WaitStep { remaining: seconds }))
+    }
+
+    /// Calls the given function once everything so far has finished.
+    ///
+    /// The callback is not traced by the GC, so it shouldn't hold the only
+    /// reference to a Gp.
+    pub fn call(self, callback: impl FnOnce(&mut Engine) + 'static) -> Self {
+        self.then(Tween::step(CallStep { callback: Some(Box::new(callback)) }))
+    }
+
+    fn property(&self) -> Option<usize> {
+        self.steps.front().and_then(|step| step.property())
+    }
+
+    /// Returns true once the whole sequence has finished.
+    fn advance(&mut self, engine: &mut Engine, mut dt: f32) -> bool {
+        while let Some(step) = self.steps.front_mut() {
+            match step.advance(engine, dt) {
+                Some(left_over) => {
+                    self.steps.pop_front();
+                    dt = left_over;
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Trace for Tween {
+    fn trace(&self, tracer: &mut Tracer) {
+        for step in &self.steps {
+            step.trace(tracer);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TweenId(u64);
+
+/// Every running Tween.
+#[derive(Default)]
+pub struct Tweens {
+    active: Vec<(TweenId, Tween)>,
+    /// Tweens that were cancelled while Tweens::advance() was running, and so
+    /// weren't in `active`.
+    cancelled: Vec<TweenId>,
+    next_id: u64,
+}
+
+impl Tweens {
+    /// Starts running the given Tween. If it starts by animating a property
+    /// that another Tween is currently animating, that other Tween is
+    /// cancelled, so that the latest one wins.
+    pub fn add(&mut self, tween: Tween) -> TweenId {
+        if let Some(property) = tween.property() {
+            self.active.retain(|(_, other)| other.property() != Some(property));
+        }
+
+        let id = TweenId(self.next_id);
+        self.next_id += 1;
+        self.active.push((id, tween));
+        id
+    }
+
+    /// Stops the given Tween where it is. Its remaining callbacks are not
+    /// called.
+    pub fn cancel(&mut self, id: TweenId) {
+        self.active.retain(|(other, _)| *other != id);
+        self.cancelled.push(id);
+    }
+
+    /// Stops every Tween that is currently animating the given Cell.
+    pub fn cancel_property<V>(&mut self, property: &Cell<V>) {
+        let property = property as *const Cell<V> as usize;
+        self.active.retain(|(_, tween)| tween.property() != Some(property));
+    }
+
+    pub fn is_running(&self, id: TweenId) -> bool {
+        self.active.iter().any(|(other, _)| *other == id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// Advances every Tween by `dt` seconds. The Tweens are taken out of the
+    /// Engine while this runs, so that callbacks get the whole Engine.
+    pub(crate) fn advance(engine: &mut Engine, dt: f32) {
+        let mut active = std::mem::take(&mut engine.tweens.active);
+        active.retain_mut(|(id, tween)| {
+            !engine.tweens.cancelled.contains(id) && !tween.advance(engine, dt)
+        });
+
+        // Anything added by a callback.
+        active.append(&mut engine.tweens.active);
+        active.retain(|(id, _)| !engine.tweens.cancelled.contains(id));
+
+        engine.tweens.active = active;
+        engine.tweens.cancelled.clear();
+    }
+}
+
+impl Trace for Tweens {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (_, tween) in &self.active {
+            tween.trace(tracer);
+        }
+    }
+}
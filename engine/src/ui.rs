@@ -1,31 +1,47 @@
 use egui_wgpu::RendererOptions;
 
-use crate::video::Video;
+use crate::video::{HeadlessTarget, RenderCtx};
 
 
 
+/// The egui state of a single window. Every window has its own, so that each
+/// one gets its own input and GUI.
 pub struct Egui {
     pub egui_ctx: egui::Context,
-    pub egui_state: egui_winit::State,
+    /// None when running headless, as there is no window to get input from.
+    pub egui_state: Option<egui_winit::State>,
     pub egui_renderer: egui_wgpu::Renderer,
 }
 
 impl Egui {
-    pub fn new(video: &Video) -> Self {
-        let window = video.id_map.iter().next().unwrap().1;
-
+    pub fn new(ctx: &RenderCtx, window: &winit::window::Window, format: wgpu::TextureFormat) -> Self {
         let egui_ctx = egui::Context::default();
         let egui_state = egui_winit::State::new(egui_ctx.clone(), egui::ViewportId::default(),
-            &window.sdl, None, None, None);
+            window, None, None, None);
+        let egui_renderer = egui_wgpu::Renderer::new(
+            &ctx.device,
+            format,
+            RendererOptions::PREDICTABLE
+        );
+
+        Self {
+            egui_ctx,
+            egui_state: Some(egui_state),
+            egui_renderer,
+        }
+    }
+
+    pub fn new_headless(ctx: &RenderCtx) -> Self {
+        let egui_ctx = egui::Context::default();
         let egui_renderer = egui_wgpu::Renderer::new(
-            &video.renderer.ctx.device,
-            window.renderer.config.format,
+            &ctx.device,
+            HeadlessTarget::OUTPUT_FORMAT,
             RendererOptions::PREDICTABLE
         );
 
         Self {
             egui_ctx,
-            egui_state,
+            egui_state: None,
             egui_renderer,
         }
     }
@@ -1,7 +1,10 @@
 pub mod texture;
 pub mod mesh_render_pipeline;
 pub mod sky_pipeline;
+pub mod shadow_pipeline;
+pub mod render_queue;
 pub mod asset_import;
+pub mod animation;
 pub mod hdr_tonemap;
 pub mod camera;
 pub mod world;
@@ -14,7 +17,7 @@ use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 use wgpu::util::DeviceExt;
 use winit::{dpi::PhysicalSize, event::{MouseButton, WindowEvent}, event_loop::{ActiveEventLoop, EventLoopProxy}, window::WindowId};
 
-use crate::{gc::{Gp, GpMaybe}, ui::Egui, video::{camera::Camera, hdr_tonemap::Tonemap, sky_pipeline::SkyPipeline, texture::Texture, world::{Viewport, World}}, Gameplay, Engine, EngineAppEvent};
+use crate::{audio::Audio, error::{EngineError, EngineResult}, gc::{Gp, GpMaybe, Trace, Tracer}, ui::Egui, video::{animation::Skin, camera::Camera, hdr_tonemap::Tonemap, shadow_pipeline::ShadowPipeline, sky_pipeline::SkyPipeline, texture::{ColorTarget, Texture}, world::{Viewport, ViewportRect, World}}, Gameplay, Engine, EngineAppEvent};
 
 // Bundles together all the global state that a given part of the renderer might
 // need, i.e. the device, queue, etc.
@@ -29,6 +32,10 @@ pub struct RenderCtx {
     
     pub samplers: Samplers,
 
+    /// The number of lights that fit in a Viewport's lights buffer. Comes from
+    /// Gameplay::MAX_LIGHTS.
+    pub max_lights: usize,
+
     // TODO: Maybe move this to Renderer, make most things take Renderer instead
     // of RenderCtx?
     pub shaders: MaybeUninit<Shaders>,
@@ -44,7 +51,7 @@ impl RenderCtx {
         unsafe { self.shaders.assume_init_ref() }
     }
 
-    pub async fn new(initial_window: &winit::window::Window) -> (Self, wgpu::Surface<'static>) {
+    pub async fn new(initial_window: &winit::window::Window, max_lights: usize) -> (Self, wgpu::Surface<'static>) {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
             // On WASM, we want to target both WebGPU and WebGL2, whatever is available.
             //
@@ -75,6 +82,46 @@ impl RenderCtx {
             .await
             .unwrap();
 
+        (Self::from_adapter(instance, adapter, max_lights).await.unwrap(), surface)
+    }
+
+    /// Creates a RenderCtx that isn't tied to any window. This prefers a
+    /// software adapter, so that rendering still produces real output on
+    /// machines without a GPU, and otherwise falls back to the noop backend,
+    /// which accepts all commands but doesn't draw anything. Fails if there
+    /// is no adapter at all.
+    pub async fn new_headless(max_lights: usize) -> EngineResult<Self> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::PRIMARY | wgpu::Backends::NOOP,
+            backend_options: wgpu::BackendOptions {
+                noop: wgpu::NoopBackendOptions { enable: true },
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let adapter = match instance
+            .request_adapter(&wgpu::RequestAdapterOptionsBase {
+                power_preference: wgpu::PowerPreference::LowPower,
+                force_fallback_adapter: true,
+                compatible_surface: None
+            })
+            .await {
+            Ok(adapter) => adapter,
+            Err(_) => instance
+                .request_adapter(&wgpu::RequestAdapterOptionsBase {
+                    power_preference: wgpu::PowerPreference::LowPower,
+                    force_fallback_adapter: false,
+                    compatible_surface: None
+                })
+                .await
+                .map_err(|err| EngineError::new(format!("no graphics adapter available: {}", err)))?
+        };
+
+        Self::from_adapter(instance, adapter, max_lights).await
+    }
+
+    async fn from_adapter(instance: wgpu::Instance, adapter: wgpu::Adapter, max_lights: usize) -> EngineResult<Self> {
         let info = adapter.get_info();
         log::info!("Using graphics adapter: {} - {}\n{}\n{}\nDevice Type: {:?}\nBackend: {}", info.vendor, info.name,
             info.driver, info.driver_info, info.device_type, info.backend);
@@ -100,7 +147,7 @@ impl RenderCtx {
                 experimental_features: wgpu::ExperimentalFeatures::disabled(),
             })
             .await
-            .unwrap();
+            .map_err(|err| EngineError::new(format!("failed to create graphics device: {}", err)))?;
 
         let layouts = Layouts::new(&device);
         let samplers = Samplers::new(&device);
@@ -114,12 +161,13 @@ impl RenderCtx {
 
             layouts,
             samplers,
+            max_lights,
             shaders: MaybeUninit::uninit(),
         };
 
         ctx.shaders = MaybeUninit::new(Shaders::new(&ctx));
 
-        (ctx, surface)
+        Ok(ctx)
     }
 
     pub fn create_uniform_buffer_init(&self, label: &str, data: &[u8]) -> UniformBuffer {
@@ -165,12 +213,104 @@ impl RenderCtx {
     pub fn create_index_buffer_init_from_u32(&self, label: &str, data: &[u32]) -> IndexBuffer {
         self.create_index_buffer_init(label, bytemuck::cast_slice(data))
     }
+
+    /// Copies the first mip level of the given texture back to the CPU,
+    /// blocking until the GPU is done. The texture must have been created
+    /// with COPY_SRC, and must be in an 8-bit RGBA or BGRA format.
+    pub fn read_texture_rgba8(&self, texture: &wgpu::Texture) -> EngineResult<image::RgbaImage> {
+        use wgpu::TextureFormat as F;
+        let swap_rb = match texture.format() {
+            F::Rgba8Unorm | F::Rgba8UnormSrgb => false,
+            F::Bgra8Unorm | F::Bgra8UnormSrgb => true,
+            other => return Err(EngineError::new(format!("read_texture_rgba8: unsupported format {:?}", other))),
+        };
+
+        let width = texture.width();
+        let height = texture.height();
+
+        // Rows in the copy must be aligned, so we strip the padding afterwards.
+        let unpadded_bytes_per_row = width * 4;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+            * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("RenderCtx::read_texture_rgba8"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("RenderCtx::read_texture_rgba8")
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::TexelCopyTextureInfo {
+                aspect: wgpu::TextureAspect::All,
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::PollType::wait_indefinitely())?;
+        receiver.recv()
+            .map_err(|_| EngineError::new("read_texture_rgba8: map_async callback was never called"))??;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        {
+            let mapped = slice.get_mapped_range();
+            for row in mapped.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+
+        if swap_rb {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        image::RgbaImage::from_raw(width, height, pixels)
+            .ok_or_else(|| EngineError::new("read_texture_rgba8: wrong buffer size"))
+    }
+
+    /// Reads back the last tonemapped frame of an offscreen Viewport. Call
+    /// Viewport::render_offscreen first.
+    pub fn read_viewport(&self, viewport: &Viewport) -> EngineResult<image::RgbaImage> {
+        let Some(target) = &viewport.color_target else {
+            return Err(EngineError::new("read_viewport: viewport is not offscreen"));
+        };
+        self.read_texture_rgba8(&target.texture)
+    }
 }
 
 pub struct Renderer {
     pub ctx: RenderCtx,
 
     pub sky: SkyPipeline,
+    pub shadow: ShadowPipeline,
 }
 
 pub struct PerWindowRenderer {
@@ -178,7 +318,11 @@ pub struct PerWindowRenderer {
     pub config: wgpu::SurfaceConfiguration,
     is_surface_configured: bool,
 
+    /// The window's main Viewport. It covers the whole window unless its
+    /// rect is changed, e.g. for split-screen.
     pub viewport: Viewport,
+    /// More Viewports drawn on top of the main one, in order, e.g. a minimap.
+    pub overlays: Vec<Viewport>,
 }
 
 pub struct Samplers {
@@ -186,7 +330,7 @@ pub struct Samplers {
     nearest_clamp: wgpu::Sampler,
 
     // The sampler used for sampling depth textures (specifically our DepthTexture).
-    #[expect(unused)]
+    // This is a comparison sampler, so it is what the shadow maps use.
     depth_texture_sampler: wgpu::Sampler,
 }
 
@@ -208,7 +352,8 @@ impl Samplers {
         // The compare function definitely seems relevant. I'm not sure exactly
         // what the Lod parameters do.
         //
-        // Note: This sampler is actually not used anywhere. *shrug*
+        // This is used for sampling the shadow maps. With Linear filtering,
+        // each comparison already gets us a bit of smoothing.
         let depth_texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
@@ -259,18 +404,15 @@ pub struct Layouts {
     // It might be better to bind them separately so they can be swapped out separately
     tex_sampler: wgpu::BindGroupLayout,
 
-    #[expect(unused)]
     single_uniform: wgpu::BindGroupLayout,
 
     pbr_material: wgpu::BindGroupLayout,
 
     world: wgpu::BindGroupLayout,
 
-    mesh_3d: wgpu::BindGroupLayout,
-
-    /// Layout for a pipeline that uses the World bind group and the PBR bind
-    /// group.
-    #[expect(unused)]
+    /// Layout for a pipeline that uses the World bind group, the PBR bind
+    /// group and a Skin bind group. Per-instance data is passed in an instance
+    /// buffer.
     pipeline_world_pbr: wgpu::PipelineLayout,
     /// Layout for a pipeline that uses the World bind group.
     pipeline_world: wgpu::PipelineLayout,
@@ -278,6 +420,9 @@ pub struct Layouts {
 
 pub struct Shaders {
     pbr_default: Gp<PBRShader>,
+
+    /// Bound for every MeshInstance that doesn't have its own Skin.
+    pub(crate) default_skin: Skin,
 }
 
 impl Shaders {
@@ -288,10 +433,17 @@ impl Shaders {
 
         Shaders {
             pbr_default,
+            default_skin: Skin::new(ctx),
         }
     }
 }
 
+impl Trace for Shaders {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.pbr_default.trace(tracer);
+    }
+}
+
 pub use mesh_render_pipeline::PBRShader;
 
 pub struct PBRMaterial {
@@ -313,6 +465,10 @@ pub struct PBRMaterial {
 
     pub cached_bind_group: GpMaybe<wgpu::BindGroup>,
     pub shader: Gp<PBRShader>,
+
+    /// Alpha-blended materials are drawn after all the opaque ones, sorted
+    /// back-to-front. They are not instanced.
+    pub alpha_blended: bool,
 }
 
 impl PBRMaterial {
@@ -331,6 +487,8 @@ impl PBRMaterial {
             cached_bind_group: GpMaybe::none(),
 
             shader: ctx.shaders().pbr_default.clone(),
+
+            alpha_blended: false,
         }
     }
 }
@@ -351,6 +509,8 @@ impl PerWindowRenderer {
         window: &winit::window::Window,
         surface: wgpu::Surface<'static>,
         ctx: &RenderCtx,
+        world: Gp<World>,
+        camera: Gp<Camera>,
         default_tonemap: Tonemap,
     ) -> Self {
         // TODO: DPI stuff...
@@ -374,22 +534,20 @@ impl PerWindowRenderer {
             desired_maximum_frame_latency: 2,
         };
 
-        let world = World::new(ctx);
-        let camera = Camera::demo();
-
-        let viewport = Viewport::new(ctx, Gp::new(world), Gp::new(camera),
-        (win_width, win_height), &config, default_tonemap);
+        let viewport = Viewport::new(ctx, world, camera,
+        (win_width, win_height), config.format, default_tonemap);
 
         Self {
             surface,
             config,
             is_surface_configured: false,
 
-            viewport
+            viewport,
+            overlays: Vec::new(),
         }
     }
 
-    pub fn new(window: &winit::window::Window, ctx: &RenderCtx, default_tonemap: Tonemap) -> Self {
+    pub fn new(window: &winit::window::Window, ctx: &RenderCtx, world: Gp<World>, camera: Gp<Camera>, default_tonemap: Tonemap) -> Self {
          let surface = unsafe {
             let raw_display_handle = window.display_handle().unwrap().as_raw();
             let raw_window_handle = window.window_handle().unwrap().as_raw();
@@ -397,7 +555,7 @@ impl PerWindowRenderer {
                 .unwrap()
         };
 
-        Self::new_from_surface_and_ctx(window, surface, ctx, default_tonemap)
+        Self::new_from_surface_and_ctx(window, surface, ctx, world, camera, default_tonemap)
     }
 
     fn resize(&mut self, renderer: &Renderer, width: u32, height: u32) {
@@ -409,13 +567,26 @@ impl PerWindowRenderer {
 
             // Recreate depth texture when window is resized
             self.viewport.resize(&renderer.ctx, width, height);
+            for overlay in &mut self.overlays {
+                overlay.resize(&renderer.ctx, width, height);
+            }
         }
     }
 
-     fn render_egui(&self, renderer: &Renderer, encoder: &mut wgpu::CommandEncoder, output_view: &wgpu::TextureView, egui: &mut Egui, window: &Window, full_output: FullOutput) {
+    /// Adds a Viewport on top of the others, covering the given rect of the
+    /// window.
+    pub fn add_overlay(&mut self, ctx: &RenderCtx, world: Gp<World>, camera: Gp<Camera>, rect: ViewportRect, tonemap: Tonemap) -> &mut Viewport {
+        let mut viewport = Viewport::new(ctx, world, camera,
+            (self.config.width, self.config.height), self.config.format, tonemap);
+        viewport.set_rect(ctx, rect);
+        self.overlays.push(viewport);
+        self.overlays.last_mut().unwrap()
+    }
+
+     fn render_egui(&self, renderer: &Renderer, encoder: &mut wgpu::CommandEncoder, output_view: &wgpu::TextureView, egui: &mut Egui, pixels_per_point: f32, full_output: FullOutput) {
         let screen_descriptor = egui_wgpu::ScreenDescriptor {
-            size_in_pixels: [window.renderer.config.width, window.renderer.config.height],
-            pixels_per_point: window.egui_pixels_per_point() as f32,
+            size_in_pixels: [self.config.width, self.config.height],
+            pixels_per_point,
         };
 
         for (id, image_delta) in &full_output.textures_delta.set {
@@ -425,7 +596,7 @@ impl PerWindowRenderer {
 
         let paint_jobs = egui.egui_ctx.tessellate(
             full_output.shapes, 
-            window.egui_pixels_per_point() as f32);
+            pixels_per_point);
 
         egui.egui_renderer.update_buffers(
             &renderer.ctx.device, 
@@ -464,7 +635,7 @@ impl PerWindowRenderer {
         }
     }
 
-    fn render(&self, renderer: &Renderer, egui: &mut Egui, window: &Window, full_output: FullOutput) -> Result<(), wgpu::SurfaceError> {
+    fn render(&self, renderer: &Renderer, sdl: &winit::window::Window, egui: &mut Egui, pixels_per_point: f32, full_output: FullOutput) -> Result<(), wgpu::SurfaceError> {
         if !self.is_surface_configured { return Ok(()) }
 
         let output = self.surface.get_current_texture()?;
@@ -476,13 +647,36 @@ impl PerWindowRenderer {
             label: Some("Render Encoder")
         });
 
+        // Viewports only draw over their own rect, so clear whatever they don't
+        // cover.
+        {
+            let _clear_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("surface_clear_pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &output_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                    depth_slice: None,
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None
+            });
+        }
+
         self.viewport.render(renderer, &mut encoder, &output_view);
+        for overlay in &self.overlays {
+            overlay.render(renderer, &mut encoder, &output_view);
+        }
 
-        self.render_egui(renderer, &mut encoder, &output_view, egui, window, full_output);
+        self.render_egui(renderer, &mut encoder, &output_view, egui, pixels_per_point, full_output);
 
         renderer.ctx.queue.submit(std::iter::once(encoder.finish()));
 
-        window.sdl.pre_present_notify();
+        sdl.pre_present_notify();
 
         output.present();
 
@@ -598,6 +792,25 @@ impl Layouts {
                 simple_uniform(1),
                 simple_texture(2),
                 simple_sampler(3),
+                // Shadow uniform (light matrix)
+                simple_uniform(4),
+                // Shadow map
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Depth
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
             ]
         });
 
@@ -637,7 +850,9 @@ impl Layouts {
             label: Some("Layouts::pipeline_world_pbr"),
             bind_group_layouts: &[
                 &world,
-                &pbr_material
+                &pbr_material,
+                // Joint matrices
+                &single_uniform,
             ],
             push_constant_ranges: &[]
         });
@@ -650,20 +865,11 @@ impl Layouts {
             push_constant_ranges: &[]
         });
 
-        let mesh_3d = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Layouts::mesh_3d"),
-            entries: &[
-                // Transform
-                simple_uniform(0)
-            ],
-        });
-
         Self {
             tex_sampler,
             single_uniform,
             pbr_material,
             world,
-            mesh_3d,
 
             pipeline_world_pbr,
             pipeline_world,
@@ -675,25 +881,47 @@ impl Layouts {
 
 impl Renderer {
     async fn new<G: Gameplay>(initial_window: &winit::window::Window) -> (Renderer, PerWindowRenderer) {
-        let (ctx, surface) = RenderCtx::new(initial_window).await;
+        let (ctx, surface) = RenderCtx::new(initial_window, G::MAX_LIGHTS).await;
 
+        let world = Gp::new(World::new(&ctx));
+        let camera = Gp::new(Camera::demo());
         let initial_per_window = PerWindowRenderer::new_from_surface_and_ctx(initial_window,
-            surface, &ctx, G::DEFAULT_TONEMAP);
+            surface, &ctx, world, camera, G::DEFAULT_TONEMAP);
 
         // For our pipelines, we will use the config from the initial_per_window.
         //
         // It should be the case that this pipeline is compatible with other
         // windows (?)
+        (Self::from_ctx(ctx), initial_per_window)
+    }
+
+    /// Renders any Viewport (e.g. the one belonging to a window) into a
+    /// temporary texture and reads it back. Useful for screenshots. The image
+    /// is the size of the Viewport's whole target, with the Viewport in its
+    /// rect.
+    pub fn capture_viewport(&self, viewport: &Viewport) -> EngineResult<image::RgbaImage> {
+        let target = ColorTarget::new(&self.ctx, viewport.target_size(), viewport.output_format);
+
+        let mut encoder = self.ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Renderer::capture_viewport")
+        });
+        viewport.render(self, &mut encoder, &target.view);
+        self.ctx.queue.submit(std::iter::once(encoder.finish()));
+
+        self.ctx.read_texture_rgba8(&target.texture)
+    }
+
+    fn from_ctx(ctx: RenderCtx) -> Renderer {
         //let mesh_renderer = MeshRenderPipeline::new(&ctx);
         let sky = SkyPipeline::new(&ctx);
+        let shadow = ShadowPipeline::new(&ctx);
 
-        let renderer = Renderer {
+        Renderer {
             ctx,
 
             sky,
-        };
-
-        (renderer, initial_per_window)
+            shadow,
+        }
     }
 }
 
@@ -705,13 +933,97 @@ pub struct Window {
 
     pub cursor_position: Vector2<f32>,
 
+    /// Each window runs its own egui context, so GUIs in different windows
+    /// don't share state.
+    pub egui: Egui,
     pub egui_scale_factor: f64,
 }
 
 impl Window {
+    fn new(sdl: winit::window::Window, renderer: PerWindowRenderer, ctx: &RenderCtx) -> Self {
+        let egui = Egui::new(ctx, &sdl, renderer.config.format);
+        Window {
+            sdl,
+            renderer,
+            cursor_position: Vector2::zero(),
+            egui,
+            egui_scale_factor: 2.0,
+        }
+    }
+
     pub fn egui_pixels_per_point(&self) -> f64 {
         self.sdl.scale_factor() * self.egui_scale_factor
     }
+
+    fn render(&mut self, renderer: &Renderer, full_output: FullOutput) -> Result<(), wgpu::SurfaceError> {
+        let pixels_per_point = self.egui_pixels_per_point() as f32;
+        self.renderer.render(renderer, &self.sdl, &mut self.egui, pixels_per_point, full_output)
+    }
+}
+
+/// Describes a window to open with Engine::open_window().
+pub struct WindowOptions {
+    pub title: String,
+    /// The inner size, in physical pixels.
+    pub size: (u32, u32),
+    /// The World the window shows. None shows the main world.
+    pub world: Option<Gp<World>>,
+    /// None creates a new Camera for the window.
+    pub camera: Option<Gp<Camera>>,
+}
+
+impl WindowOptions {
+    pub fn new(title: impl Into<String>, size: (u32, u32)) -> Self {
+        WindowOptions {
+            title: title.into(),
+            size,
+            world: None,
+            camera: None,
+        }
+    }
+
+    pub fn with_world(mut self, world: Gp<World>) -> Self {
+        self.world = Some(world);
+        self
+    }
+
+    pub fn with_camera(mut self, camera: Gp<Camera>) -> Self {
+        self.camera = Some(camera);
+        self
+    }
+}
+
+impl Trace for WindowOptions {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.world.trace(tracer);
+        self.camera.trace(tracer);
+    }
+}
+
+/// Returned by Engine::open_window(). Windows can only be created between
+/// events, so the window doesn't exist yet; once it does,
+/// Gameplay::window_opened() is called with this and the new WindowId.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WindowRequest(u64);
+
+/// Stands in for the main Window when the engine is running headless.
+pub struct HeadlessTarget {
+    /// Always an offscreen Viewport.
+    pub viewport: Viewport,
+
+    /// There is no real cursor, so this is only ever changed by whoever is
+    /// driving the engine (e.g. a test).
+    pub cursor_position: Vector2<f32>,
+
+    pub egui: Egui,
+}
+
+impl HeadlessTarget {
+    /// The format the headless target renders in.
+    pub const OUTPUT_FORMAT: wgpu::TextureFormat = Viewport::OFFSCREEN_FORMAT;
+
+    /// The WindowId that Gameplay::ui() gets for the headless target.
+    pub const WINDOW_ID: WindowId = WindowId::dummy();
 }
 
 pub struct Video {
@@ -720,6 +1032,32 @@ pub struct Video {
 
     // TODO: GC integration..?
     pub id_map: HashMap<WindowId, Window>,
+    /// The window the game was started in. Closing it exits the game.
+    pub main_window: Option<WindowId>,
+
+    /// Windows asked for with Engine::open_window(), that have not been
+    /// created yet.
+    pub(crate) window_requests: Vec<(WindowRequest, WindowOptions)>,
+    next_window_request: u64,
+
+    /// Only present when running headless, in which case id_map is empty.
+    pub headless: Option<HeadlessTarget>,
+}
+
+impl Trace for Video {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.renderer.ctx.shaders().trace(tracer);
+        for window in self.id_map.values() {
+            window.renderer.viewport.trace(tracer);
+            window.renderer.overlays.trace(tracer);
+        }
+        for (_, options) in &self.window_requests {
+            options.trace(tracer);
+        }
+        if let Some(headless) = &self.headless {
+            headless.viewport.trace(tracer);
+        }
+    }
 }
 
 impl Video {
@@ -736,33 +1074,19 @@ impl Video {
         let camera = per.viewport.camera.clone();
 
         let id = underlying_window.id();
-        let window = Window {
-            sdl: underlying_window,
-            renderer: per,
-            cursor_position: Vector2::zero(),
-            egui_scale_factor: 2.0,
-        };
+        let window = Window::new(underlying_window, per, &renderer.ctx);
         id_map.insert(id, window);
 
         let video = Video {
             id_map,
+            main_window: Some(id),
+            window_requests: Vec::new(),
+            next_window_request: 0,
             renderer,
+            headless: None,
         };
 
-        let egui = Egui::new(&video);
-
-        let engine = Engine {
-            video,
-            audio: crate::audio::Audio::initial(),
-            input: crate::input::Input::new(),
-            main_world: world,
-            main_camera: camera,
-
-            egui,
-
-            accumulator: web_time::Duration::from_micros(0),
-            last_tick: web_time::Instant::now(),
-        };
+        let engine = Engine::from_parts(video, Audio::initial(), world, camera);
 
         assert!(proxy.send_event(EngineAppEvent::Initialize(engine))
             .is_ok())
@@ -835,6 +1159,83 @@ impl Video {
         }
     }
 
+    /// Creates a Video without any windows, rendering into an offscreen
+    /// Viewport of the given dimensions instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new_headless<G: Gameplay>(dimensions: (u32, u32)) -> EngineResult<Self> {
+        let ctx = pollster::block_on(RenderCtx::new_headless(G::MAX_LIGHTS))?;
+
+        let world = World::new(&ctx);
+        let camera = Camera::demo();
+
+        let viewport = Viewport::new_offscreen(&ctx, Gp::new(world), Gp::new(camera),
+            dimensions, G::DEFAULT_TONEMAP);
+
+        let egui = Egui::new_headless(&ctx);
+
+        Ok(Video {
+            renderer: Renderer::from_ctx(ctx),
+            id_map: HashMap::new(),
+            main_window: None,
+            window_requests: Vec::new(),
+            next_window_request: 0,
+            headless: Some(HeadlessTarget {
+                viewport,
+                cursor_position: Vector2::zero(),
+                egui,
+            }),
+        })
+    }
+
+    /// Returns the Viewport of the main window, or of the headless target.
+    pub fn main_viewport(&self) -> &Viewport {
+        if let Some(window) = self.main_window.and_then(|id| self.id_map.get(&id)) {
+            return &window.renderer.viewport;
+        }
+        &self.headless.as_ref().expect("Video has neither a window nor a headless target").viewport
+    }
+
+    /// Queues up a window to be opened. See Engine::open_window().
+    pub(crate) fn request_window(&mut self, options: WindowOptions) -> WindowRequest {
+        let request = WindowRequest(self.next_window_request);
+        self.next_window_request += 1;
+        if self.headless.is_some() {
+            log::warn!("cannot open window '{}': running headless", options.title);
+            return request;
+        }
+        self.window_requests.push((request, options));
+        request
+    }
+
+    /// Creates a window showing the given world from the given camera.
+    pub(crate) fn create_window(
+        &mut self,
+        event_loop: &ActiveEventLoop,
+        title: &str,
+        size: (u32, u32),
+        world: Gp<World>,
+        camera: Gp<Camera>,
+        default_tonemap: Tonemap,
+    ) -> EngineResult<WindowId> {
+        let mut attributes = winit::window::Window::default_attributes();
+        attributes.title = title.to_string();
+        attributes.inner_size = Some(PhysicalSize::new(size.0, size.1).into());
+        attributes.resizable = true;
+
+        let sdl = event_loop.create_window(attributes)
+            .map_err(|err| EngineError::new(format!("failed to create window: {}", err)))?;
+
+        let mut per = PerWindowRenderer::new(&sdl, &self.renderer.ctx, world, camera, default_tonemap);
+        let PhysicalSize { width, height } = sdl.inner_size();
+        per.resize(&self.renderer, width, height);
+
+        let id = sdl.id();
+        let window = Window::new(sdl, per, &self.renderer.ctx);
+        window.sdl.request_redraw();
+        self.id_map.insert(id, window);
+        Ok(id)
+    }
+
     pub fn update_all_window_sizes(&mut self) {
         for window in self.id_map.values_mut() {
             let phys = window.sdl.inner_size();
@@ -848,10 +1249,11 @@ impl Video {
         {
             let Some(window) = engine.video.id_map.get_mut(&window_id) else { return false; };
 
-            // TODO: Probably each Window should get its own EGUI
-            let egui_res = engine.egui.egui_state.on_window_event(&window.sdl, &win_event);
-            if egui_res.repaint { window.sdl.request_redraw(); }
-            if egui_res.consumed { return false; }
+            if let Some(egui_state) = window.egui.egui_state.as_mut() {
+                let egui_res = egui_state.on_window_event(&window.sdl, &win_event);
+                if egui_res.repaint { window.sdl.request_redraw(); }
+                if egui_res.consumed { return false; }
+            }
         }
 
         // Let the gameplay provide custom logic as well.
@@ -869,24 +1271,26 @@ impl Video {
 
         match win_event {
             WindowEvent::RedrawRequested => {
-                //  TODO: How do we handle this per-window?
-                let raw_input = engine.egui.egui_state.take_egui_input(&window.sdl);
+                let Some(egui_state) = window.egui.egui_state.as_mut() else { return false; };
+                let raw_input = egui_state.take_egui_input(&window.sdl);
                 // Clone the context for borrow checker :)
-                let context = engine.egui.egui_ctx.clone();
+                let context = window.egui.egui_ctx.clone();
                 // Set this here, because we need to keep it consistent between
                 // what we actualyl tell EGUI and what the context gets.
                 let ctx_pixels_per_point = window.egui_scale_factor as f32;
                 let full_output = context.run(raw_input, |ctx| {
                     ctx.set_zoom_factor(ctx_pixels_per_point);
-                    gameplay.ui(engine, ctx);
+                    gameplay.ui(engine, window_id, ctx);
                 });
                 // TODO: Call this somehow...
                 // egui.egui_state.handle_platform_output(&window.sdl, full_output.platform_output);
 
-                // This is an ugly hack... We should really fix this...
-                engine.video.render(&mut engine.egui, full_output);
+                engine.video.render(window_id, full_output);
 
-                engine.video.id_map.get(&window_id).unwrap().sdl.request_redraw();
+                // The ui might have closed the window.
+                if let Some(window) = engine.video.id_map.get(&window_id) {
+                    window.sdl.request_redraw();
+                }
             }
             WindowEvent::Resized(phys) => {
                 window.renderer.resize(&engine.video.renderer, phys.width, phys.height);
@@ -896,7 +1300,7 @@ impl Video {
                 // This removes the inner 'sdl' object from existing, which results
                 // in a DestroyWindow operation.
                 engine.video.id_map.remove(&window_id);
-                return engine.video.id_map.is_empty();
+                return engine.video.main_window == Some(window_id) || engine.video.id_map.is_empty();
             },
             WindowEvent::CursorMoved { device_id: _, position } => {
                 window.cursor_position.x = position.x as f32;
@@ -908,15 +1312,11 @@ impl Video {
         return false;
     }
 
-    pub fn render(&mut self, egui: &mut Egui, full_output: FullOutput) {
-        for window in self.id_map.values_mut() {
-            if let Err(err) = window.renderer.render(&self.renderer, egui, &window, full_output) {
-                log::warn!("renderer: error: {}", err);
-            }
-
-            // Hack because we move full_output. Needs to be patched for multi-window
-            // support.
-            return;
+    /// Renders a window's Viewport, with the egui output on top.
+    pub fn render(&mut self, window_id: WindowId, full_output: FullOutput) {
+        let Some(window) = self.id_map.get_mut(&window_id) else { return; };
+        if let Err(err) = window.render(&self.renderer, full_output) {
+            log::warn!("renderer: error: {}", err);
         }
     }
 }
\ No newline at end of file
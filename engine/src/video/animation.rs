@@ -0,0 +1,354 @@
+// Skeletal animation.
+//
+// A Skin holds the joint matrices of a single skinned MeshInstance on the GPU.
+// An Animator owns the Skins for one instantiated Scene, samples an
+// AnimationClip into the poses of the Scene's nodes, and writes the resulting
+// joint matrices into the Skins. Call Animator::tick() once per Gameplay::tick.
+
+use std::{ops::{Add, Mul}, rc::Rc};
+
+use cgmath::{InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3};
+
+use crate::{gc::Gp, video::{asset_import::{NodeData, Scene, SkinData}, RenderCtx, UniformBuffer}, Engine};
+
+/// How the values between two keyframes are computed. Matches the glTF
+/// interpolation modes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Linear,
+    Step,
+    /// Each keyframe is stored as an (in-tangent, value, out-tangent) triple.
+    CubicSpline,
+}
+
+#[derive(Clone)]
+pub enum ChannelValues {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+/// Animates a single property of a single node.
+#[derive(Clone)]
+pub struct Channel {
+    /// Index into the Scene's nodes.
+    pub node: usize,
+    pub interpolation: Interpolation,
+    /// The time of each keyframe, in seconds, in increasing order.
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+}
+
+#[derive(Clone)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    /// The time of the last keyframe of any channel.
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+/// The local transform of a node, split up so that it can be animated.
+#[derive(Clone, Copy, Debug)]
+pub struct NodePose {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl NodePose {
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+fn sample_keys<T>(interpolation: Interpolation, times: &[f32], values: &[T], time: f32, lerp: impl Fn(T, T, f32) -> T) -> T
+    where T: Copy + Add<Output = T> + Mul<f32, Output = T>
+{
+    // For cubic splines, the actual value is the middle of each triple.
+    let value = |key: usize| match interpolation {
+        Interpolation::CubicSpline => values[key * 3 + 1],
+        _ => values[key],
+    };
+
+    let last = times.len() - 1;
+    if time <= times[0] {
+        return value(0);
+    }
+    if time >= times[last] {
+        return value(last);
+    }
+
+    // The keyframe right before the given time.
+    let key = times.partition_point(|t| *t <= time) - 1;
+    let dt = times[key + 1] - times[key];
+    let s = (time - times[key]) / dt;
+
+    match interpolation {
+        Interpolation::Step => value(key),
+        Interpolation::Linear => lerp(value(key), value(key + 1), s),
+        Interpolation::CubicSpline => {
+            let out_tangent = values[key * 3 + 2];
+            let in_tangent = values[(key + 1) * 3];
+
+            let s2 = s * s;
+            let s3 = s2 * s;
+            value(key) * (2.0 * s3 - 3.0 * s2 + 1.0)
+                + out_tangent * ((s3 - 2.0 * s2 + s) * dt)
+                + value(key + 1) * (-2.0 * s3 + 3.0 * s2)
+                + in_tangent * ((s3 - s2) * dt)
+        }
+    }
+}
+
+impl Channel {
+    pub fn sample(&self, time: f32, pose: &mut NodePose) {
+        if self.times.is_empty() {
+            return;
+        }
+
+        let lerp_vec = |a: Vector3<f32>, b: Vector3<f32>, s: f32| a + (b - a) * s;
+        match &self.values {
+            ChannelValues::Translation(values) => {
+                pose.translation = sample_keys(self.interpolation, &self.times, values, time, lerp_vec);
+            }
+            ChannelValues::Scale(values) => {
+                pose.scale = sample_keys(self.interpolation, &self.times, values, time, lerp_vec);
+            }
+            ChannelValues::Rotation(values) => {
+                let rotation = sample_keys(self.interpolation, &self.times, values, time,
+                    |a: Quaternion<f32>, b: Quaternion<f32>, s| a.slerp(b, s));
+                // Cubic splines don't keep the quaternion normalized.
+                pose.rotation = rotation.normalize();
+            }
+        }
+    }
+}
+
+impl AnimationClip {
+    /// Overwrites the poses of every node that this clip animates.
+    pub fn sample(&self, time: f32, poses: &mut [NodePose]) {
+        for channel in &self.channels {
+            if let Some(pose) = poses.get_mut(channel.node) {
+                channel.sample(time, pose);
+            }
+        }
+    }
+}
+
+/// The joint matrices for a skinned MeshInstance, in a uniform buffer. Each
+/// matrix takes a vertex from the mesh's bind pose to its animated position,
+/// in model space.
+pub struct Skin {
+    buffer: UniformBuffer,
+    pub(crate) bind_group: wgpu::BindGroup,
+}
+
+impl Skin {
+    /// The maximum number of joints per Skin. Must match MAX_JOINTS in
+    /// mesh.wgsl and shadow.wgsl.
+    pub const MAX_JOINTS: usize = 64;
+
+    /// Creates a Skin with every joint matrix set to the identity.
+    pub fn new(ctx: &RenderCtx) -> Self {
+        let identity: [[f32; 4]; 4] = Matrix4::<f32>::identity().into();
+        let buffer = ctx.create_uniform_buffer_init_from("Skin::buffer", &[identity; Self::MAX_JOINTS]);
+
+        let bind_group = ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Skin::bind_group"),
+            layout: &ctx.layouts.single_uniform,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.0.as_entire_binding(),
+                }
+            ],
+        });
+
+        Skin { buffer, bind_group }
+    }
+
+    /// Any joints past MAX_JOINTS are ignored.
+    pub fn write(&self, ctx: &RenderCtx, joints: &[Matrix4<f32>]) {
+        let data: Vec<[[f32; 4]; 4]> = joints.iter()
+            .take(Self::MAX_JOINTS)
+            .map(|joint| (*joint).into())
+            .collect();
+        ctx.queue.write_buffer(&self.buffer.0, 0, bytemuck::cast_slice(&data));
+    }
+}
+
+/// A skinned node of the Scene, and the Skin its instances are drawn with.
+struct SkinTarget {
+    node: usize,
+    /// Index into Animator::skin_data.
+    skin: usize,
+}
+
+/// Plays AnimationClips on one instance of a Scene.
+pub struct Animator {
+    nodes: Vec<NodeData>,
+    roots: Vec<usize>,
+    skin_data: Vec<SkinData>,
+    clips: Rc<[AnimationClip]>,
+
+    targets: Vec<SkinTarget>,
+    /// Parallel to targets.
+    pub(crate) skins: Vec<Gp<Skin>>,
+
+    poses: Vec<NodePose>,
+
+    current: Option<usize>,
+    time: f32,
+
+    /// Multiplies how fast time passes for the current clip.
+    pub speed: f32,
+    /// Whether the current clip starts over once it reaches its end.
+    /// Otherwise, it stays on the last frame.
+    pub looping: bool,
+}
+
+impl Animator {
+    /// Creates an Animator with a Skin for every skinned node in the Scene.
+    /// Usually this is done through Scene::instantiate_animated(), which also
+    /// hands the Skins to the MeshInstances.
+    pub fn new(ctx: &RenderCtx, scene: &Scene) -> Self {
+        let mut targets = Vec::new();
+        let mut skins = Vec::new();
+        for (node, data) in scene.nodes.iter().enumerate() {
+            let Some(skin) = data.skin else { continue; };
+            if scene.skins[skin].joints.len() > Skin::MAX_JOINTS {
+                log::warn!("animator: skin {} has more than {} joints", skin, Skin::MAX_JOINTS);
+            }
+            targets.push(SkinTarget { node, skin });
+            skins.push(Gp::new(Skin::new(ctx)));
+        }
+
+        let animator = Animator {
+            nodes: scene.nodes.clone(),
+            roots: scene.roots.clone(),
+            skin_data: scene.skins.clone(),
+            clips: scene.animations.clone(),
+
+            targets,
+            skins,
+
+            poses: scene.nodes.iter().map(|node| node.pose).collect(),
+
+            current: None,
+            time: 0.0,
+
+            speed: 1.0,
+            looping: true,
+        };
+        // Start out in the rest pose, rather than the identity matrices.
+        animator.update_skins(ctx);
+        animator
+    }
+
+    /// The Skin for the given node, if it is skinned.
+    pub fn skin_for_node(&self, node: usize) -> Option<Gp<Skin>> {
+        self.targets.iter().position(|target| target.node == node)
+            .map(|idx| self.skins[idx].clone())
+    }
+
+    pub fn clip_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.clips.iter().map(|clip| clip.name.as_deref())
+    }
+
+    /// Starts playing the clip with the given name from the beginning. Returns
+    /// false if there is no such clip.
+    pub fn play(&mut self, name: &str) -> bool {
+        match self.clips.iter().position(|clip| clip.name.as_deref() == Some(name)) {
+            Some(idx) => {
+                self.play_index(idx);
+                true
+            }
+            None => {
+                log::warn!("animator: no clip named {}", name);
+                false
+            }
+        }
+    }
+
+    pub fn play_index(&mut self, idx: usize) {
+        self.current = Some(idx);
+        self.time = 0.0;
+    }
+
+    /// Stops playing. The nodes stay in whatever pose they were in.
+    pub fn stop(&mut self) {
+        self.current = None;
+    }
+
+    /// The name of the clip that is playing, if any.
+    pub fn current_clip(&self) -> Option<&str> {
+        self.current.and_then(|idx| self.clips[idx].name.as_deref())
+    }
+
+    /// True if a clip is playing and hasn't reached its end. Looping clips
+    /// never finish.
+    pub fn is_playing(&self) -> bool {
+        match self.current {
+            Some(idx) => self.looping || self.time < self.clips[idx].duration,
+            None => false,
+        }
+    }
+
+    /// Advances by one fixed tick. Meant to be called from Gameplay::tick.
+    pub fn tick(&mut self, ctx: &RenderCtx) {
+        self.advance(ctx, 1.0 / Engine::TICKS_PER_SECOND as f32);
+    }
+
+    /// Advances the current clip by the given number of seconds, and updates
+    /// the Skins to match.
+    pub fn advance(&mut self, ctx: &RenderCtx, seconds: f32) {
+        let Some(idx) = self.current else { return; };
+        let clip = &self.clips[idx];
+
+        self.time += seconds * self.speed;
+        if self.looping && clip.duration > 0.0 {
+            self.time = self.time.rem_euclid(clip.duration);
+        }
+        else {
+            self.time = self.time.clamp(0.0, clip.duration);
+        }
+
+        clip.sample(self.time, &mut self.poses);
+        self.update_skins(ctx);
+    }
+
+    fn compute_globals(&self, idx: usize, parent: Matrix4<f32>, globals: &mut [Matrix4<f32>]) {
+        globals[idx] = parent * self.poses[idx].to_matrix();
+        for child in &self.nodes[idx].children {
+            self.compute_globals(*child, globals[idx], globals);
+        }
+    }
+
+    fn update_skins(&self, ctx: &RenderCtx) {
+        if self.targets.is_empty() {
+            return;
+        }
+
+        let mut globals = vec![Matrix4::identity(); self.nodes.len()];
+        for root in &self.roots {
+            self.compute_globals(*root, Matrix4::identity(), &mut globals);
+        }
+
+        for (target, skin) in self.targets.iter().zip(&self.skins) {
+            let data = &self.skin_data[target.skin];
+
+            // The MeshInstance already has the node's own transform, so the
+            // joints have to be relative to that.
+            let to_model = globals[target.node].invert().unwrap_or(Matrix4::identity());
+            let joints: Vec<Matrix4<f32>> = data.joints.iter().enumerate().map(|(i, joint)| {
+                let inverse_bind = data.inverse_bind_matrices.get(i).copied()
+                    .unwrap_or(Matrix4::identity());
+                to_model * globals[*joint] * inverse_bind
+            }).collect();
+
+            skin.write(ctx, &joints);
+        }
+    }
+}
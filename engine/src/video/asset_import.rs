@@ -1,10 +1,8 @@
-use std::{io::Cursor, path::Path};
+use std::{collections::HashMap, rc::Rc};
 
-use asset_importer_rs_gltf::Gltf2Importer;
-use asset_importer_rs_core::AiImporterExt;
-use asset_importer_rs_scene::AiMesh;
+use cgmath::{vec3, InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3};
 
-use crate::{gc::Gp, video::mesh_render_pipeline::{Mesh, Vertex}, Engine};
+use crate::{error::{EngineError, EngineResult}, gc::Gp, video::{animation::{AnimationClip, Animator, Channel, ChannelValues, Interpolation, NodePose}, mesh_render_pipeline::{Mesh, MeshInstance, Vertex}, texture::Texture, PBRMaterial, RenderCtx}, Engine};
 
 // How should meshs work?
 // 
@@ -28,116 +26,740 @@ impl MeshData {
     }
 }
 
-fn conv_vec3(vec3: asset_importer_rs_scene::AiVector3D) -> [f32; 3] {
-    [vec3.x, vec3.y, vec3.z]
+/// Appends a face to the index list, fan-triangulating it if it has more than
+/// three indices. This assumes the polygon is convex, which is what exporters
+/// give us in practice.
+fn triangulate_face(mesh_name: &str, face: &[u32], index_data: &mut Vec<u32>) -> EngineResult<()> {
+    if face.len() < 3 {
+        return Err(EngineError::NonTriangleFace {
+            mesh: mesh_name.to_string(),
+            index_count: face.len(),
+        });
+    }
+
+    for i in 1..face.len() - 1 {
+        index_data.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+    }
+    Ok(())
 }
 
-fn import_mesh(mesh: &AiMesh) -> MeshData {
-    let mut vertex_data = vec![];
-    let mut index_data: Vec<u32> = vec![];
+/// Fails if any index is past the end of the vertices, as a malformed file
+/// shouldn't be able to make us index out of bounds.
+fn check_indices(mesh_name: &str, index_data: &[u32], count: usize) -> EngineResult<()> {
+    match index_data.iter().find(|index| **index as usize >= count) {
+        Some(index) => Err(EngineError::AssetParse(format!("mesh {}: index {} is out of range for {} vertices",
+            mesh_name, index, count))),
+        None => Ok(()),
+    }
+}
 
-    // TODO: How to process missing texture coordinates?
-    let texcoords = mesh.texture_coords.get(0)
-        .as_ref().unwrap().as_ref().unwrap();
+/// Fails if a vertex attribute doesn't have exactly one element per vertex.
+fn check_attribute<T>(mesh_name: &str, attribute: &str, values: Option<&[T]>, count: usize) -> EngineResult<()> {
+    match values {
+        Some(values) if values.len() != count => Err(EngineError::AssetParse(format!(
+            "mesh {}: {} has {} elements, but there are {} vertices", mesh_name, attribute, values.len(), count))),
+        _ => Ok(()),
+    }
+}
 
-    // If there is a second texcoords channel, use it, otherwise default back
-    // to texcoords
-    let texcoords_2 = match mesh.texture_coords.get(1).as_ref() {
-        // Also fallback here
-        Some(coords) => coords.as_ref().unwrap_or(texcoords),
-        None => texcoords,
-    };
+/// Fills in smooth normals for a mesh that has none, by averaging the normals
+/// of all the faces that touch each vertex. The indices must have been
+/// checked with check_indices().
+fn generate_normals(vertex_data: &mut [Vertex], index_data: &[u32]) {
+    let mut normals = vec![Vector3::new(0.0f32, 0.0, 0.0); vertex_data.len()];
 
-    for i in 0..mesh.vertices.len() {
-        let vertex = Vertex {
-            position: conv_vec3(mesh.vertices[i]),
-            normal:   conv_vec3(mesh.normals[i]),
+    for tri in index_data.chunks_exact(3) {
+        let [a, b, c] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let pa = Vector3::from(vertex_data[a].position);
+        let pb = Vector3::from(vertex_data[b].position);
+        let pc = Vector3::from(vertex_data[c].position);
 
-            // For now, we have to manaully flip the UVs, as it doesn't necessarily
-            // seem (?) like asset-importer-rs does it for us?
-            uv:       [texcoords[i].x, 1.0 - texcoords[i].y],
-            uv2:      [texcoords_2[i].x, 1.0 - texcoords_2[i].y],
+        // Not normalized, so that bigger faces count for more.
+        let face_normal = (pb - pa).cross(pc - pa);
+        normals[a] += face_normal;
+        normals[b] += face_normal;
+        normals[c] += face_normal;
+    }
+
+    for (vertex, normal) in vertex_data.iter_mut().zip(normals) {
+        vertex.normal = if normal.magnitude2() > 0.0 {
+            normal.normalize().into()
+        }
+        else {
+            [0.0, 1.0, 0.0]
         };
+    }
+}
 
-        vertex_data.push(vertex);
+/// Fills in UVs for a mesh that has none, by projecting each vertex onto the
+/// two largest axes of the mesh's bounding box.
+fn generate_uvs(vertex_data: &mut [Vertex]) {
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for vertex in vertex_data.iter() {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(vertex.position[axis]);
+            max[axis] = max[axis].max(vertex.position[axis]);
+        }
     }
 
-    for face in &mesh.faces {
-        assert!(face.len() == 3);
-        for index in face {
-            index_data.push(*index as u32);
+    let extent = [max[0] - min[0], max[1] - min[1], max[2] - min[2]];
+    let mut axes = [0, 1, 2];
+    axes.sort_by(|a, b| extent[*b].total_cmp(&extent[*a]));
+    let (u, v) = (axes[0], axes[1]);
+
+    for vertex in vertex_data.iter_mut() {
+        let project = |axis: usize| if extent[axis] > 0.0 {
+            (vertex.position[axis] - min[axis]) / extent[axis]
         }
+        else {
+            0.0
+        };
+        vertex.uv = [project(u), project(v)];
+        vertex.uv2 = vertex.uv;
     }
+}
+
+/// Takes the geometry out of a mesh that has exactly one primitive, which is
+/// all that the mesh-only functions below can represent.
+fn single_primitive(mesh: SceneMeshData) -> EngineResult<MeshData> {
+    let name = mesh.name.unwrap_or_else(|| "<unnamed>".to_string());
+    let count = mesh.primitives.len();
+    let Ok([primitive]) = <[PrimitiveData; 1]>::try_from(mesh.primitives) else {
+        return Err(EngineError::AssetParse(format!("mesh {} has {} primitives, expected exactly one", name, count)));
+    };
+    Ok(primitive.mesh)
+}
 
-    MeshData { vertex_data, index_data }
+/// Imports the first mesh of a .glb file, ignoring everything else in it.
+pub fn import_binary_data(data: &[u8]) -> EngineResult<MeshData> {
+    let scene = import_scene_data(data)?;
+    let Some(mesh) = scene.meshes.into_iter().next() else {
+        return Err(EngineError::AssetParse("file contains no meshes".to_string()));
+    };
+    single_primitive(mesh)
 }
 
-pub fn import_binary_data(data: &[u8]) -> Option<MeshData> {
-    log::info!("begin asset import");
-    
-    let importer = Gltf2Importer::new();
-    let scene = importer.read_file(Path::new("builtin.gltf"), |_| {
-        Ok(Cursor::new(data))
-    }).unwrap();
+/// Imports the meshes with the given names from a .glb file, in the same
+/// order.
+pub fn import_mesh_set<const N: usize>(data: &[u8], mesh_names: &[&str; N]) -> EngineResult<[MeshData; N]> {
+    let mut imported: [Option<MeshData>; N] = core::array::from_fn(|_| None);
 
-    // When using Russimp, we were using these flags:
-    // vec![PostProcess::Triangulate, PostProcess::FlipUVs, PostProcess::GenerateUVCoords],
-    //
-    // Although, GenerateUVCoords may be unnecessary.
-    //
-    // It would be good to figure out the equivalent in the new library.
+    let scene = import_scene_data(data)?;
+    for mesh in scene.meshes {
+        let Some(idx) = mesh_names.iter().position(|name| mesh.name.as_deref() == Some(*name)) else { continue; };
+        imported[idx] = Some(single_primitive(mesh)?);
+    }
 
-    for mesh in &scene.meshes {
-        log::info!("import: mesh = {}", mesh.name);
-        let mesh = Some(import_mesh(mesh));
-        log::info!("finish asset import");
-        return mesh;
+    if let Some(idx) = imported.iter().position(Option::is_none) {
+        return Err(EngineError::MeshNotFound(mesh_names[idx].to_string()));
     }
+    Ok(imported.map(Option::unwrap))
+}
 
-    None
+pub fn import_mesh_set_as_gc<const N: usize>(engine: &Engine, data: &[u8], mesh_names: &[&str; N]) -> EngineResult<[Gp<Mesh>; N]> {
+    let meshes = import_mesh_set(data, mesh_names)?;
+    Ok(core::array::from_fn(|idx| {
+        Gp::new(Mesh::new(engine.render_ctx(), &meshes[idx]))
+    }))
 }
 
-pub fn import_mesh_set<const N: usize>(data: &[u8], mesh_names: &[&str; N]) -> Option<[MeshData; N]> {
-    let mut imported = core::array::from_fn::<_, N, _>(|_| MeshData::empty());
+// Full scene import.
+//
+// Everything goes through the gltf crate, which exposes the node hierarchy,
+// materials and textures without any conversion step. The functions above
+// only keep the geometry.
 
-    let importer = Gltf2Importer::new();
-    let scene = importer.read_file(Path::new("builtin.gltf"), |_| {
-        Ok(Cursor::new(data))
-    }).unwrap();
+/// A glTF material, with its parameters already converted to match
+/// PBRMaterial.
+pub struct MaterialData {
+    pub name: Option<String>,
+    /// Linear RGBA.
+    pub base_color: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub alpha_blended: bool,
+    /// Indices into SceneData::images.
+    pub base_color_texture: Option<usize>,
+    pub metallic_roughness_texture: Option<usize>,
+}
 
-    let mut missing = N;
+pub struct PrimitiveData {
+    pub mesh: MeshData,
+    /// Index into SceneData::materials. None means the default material.
+    pub material: Option<usize>,
+}
 
-    log::info!("import: mesh count = {}", scene.meshes.len());
+pub struct SceneMeshData {
+    pub name: Option<String>,
+    pub primitives: Vec<PrimitiveData>,
+}
 
-    for mesh in &scene.meshes {
-        log::info!("import: considering {}", mesh.name);
+#[derive(Clone)]
+pub struct NodeData {
+    pub name: Option<String>,
+    /// Relative to the parent node.
+    pub transform: Matrix4<f32>,
+    /// The same as transform, but split up for the Animator.
+    pub pose: NodePose,
+    /// Index into the scene's meshes.
+    pub mesh: Option<usize>,
+    /// Index into the scene's skins. Only set on nodes with a mesh.
+    pub skin: Option<usize>,
+    /// Indices into the scene's nodes.
+    pub children: Vec<usize>,
+}
 
-        let idx = mesh_names.iter().position(|x| **x == mesh.name);
-        let Some(idx) = idx else { continue; };
+#[derive(Clone)]
+pub struct SkinData {
+    pub name: Option<String>,
+    /// Indices into the scene's nodes. The vertex joint indices index into
+    /// this list.
+    pub joints: Vec<usize>,
+    /// Parallel to joints. Takes a vertex from model space into the joint's
+    /// local space in the bind pose.
+    pub inverse_bind_matrices: Vec<Matrix4<f32>>,
+}
+
+/// The CPU-side data of a glTF scene. Nodes are stored flat, with `roots`
+/// being the top-level nodes of the scene.
+pub struct SceneData {
+    pub nodes: Vec<NodeData>,
+    pub roots: Vec<usize>,
+    pub meshes: Vec<SceneMeshData>,
+    pub materials: Vec<MaterialData>,
+    pub images: Vec<image::DynamicImage>,
+    pub skins: Vec<SkinData>,
+    pub animations: Vec<AnimationClip>,
+}
 
-        log::info!("import: mesh = {} @ {}", mesh.name, idx);
-        let mesh = import_mesh(mesh);
-        
-        imported[idx] = mesh;
-        // TODO: This will be wrong if there is more than one mesh in the glb
-        // that has the same name.
-        missing -= 1;
+fn convert_gltf_image(data: gltf::image::Data) -> Option<image::DynamicImage> {
+    use gltf::image::Format;
+
+    let (width, height) = (data.width, data.height);
+    match data.format {
+        Format::R8G8B8A8 => image::RgbaImage::from_raw(width, height, data.pixels)
+            .map(image::DynamicImage::ImageRgba8),
+        Format::R8G8B8 => image::RgbImage::from_raw(width, height, data.pixels)
+            .map(image::DynamicImage::ImageRgb8),
+        Format::R8G8 => image::GrayAlphaImage::from_raw(width, height, data.pixels)
+            .map(image::DynamicImage::ImageLumaA8),
+        Format::R8 => image::GrayImage::from_raw(width, height, data.pixels)
+            .map(image::DynamicImage::ImageLuma8),
+        other => {
+            log::warn!("import: unsupported image format {:?}", other);
+            None
+        }
     }
+}
+
+fn import_gltf_primitive(mesh_name: &str, primitive: &gltf::Primitive, buffers: &[gltf::buffer::Data]) -> EngineResult<Option<MeshData>> {
+    use gltf::mesh::Mode;
 
-    if missing == 0 {
-        return Some(imported);
+    let mode = primitive.mode();
+    if !matches!(mode, Mode::Triangles | Mode::TriangleStrip | Mode::TriangleFan) {
+        log::warn!("import: skipping primitive with mode {:?}", mode);
+        return Ok(None);
     }
-    None
+
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data.0[..]));
+
+    let Some(positions) = reader.read_positions() else {
+        return Err(EngineError::MissingAttribute { mesh: mesh_name.to_string(), attribute: "positions" });
+    };
+    let positions: Vec<[f32; 3]> = positions.collect();
+    let count = positions.len();
+
+    let normals: Option<Vec<[f32; 3]>> = reader.read_normals()
+        .map(|normals| normals.collect());
+    let uvs: Option<Vec<[f32; 2]>> = reader.read_tex_coords(0)
+        .map(|uvs| uvs.into_f32().collect());
+    // Without a second set of texcoords, fall back to the first.
+    let uvs2: Option<Vec<[f32; 2]>> = reader.read_tex_coords(1)
+        .map(|uvs| uvs.into_f32().collect())
+        .or_else(|| uvs.clone());
+    // Only skinned meshes have these. Without any weights, the vertex isn't
+    // moved by the Skin.
+    let joints: Option<Vec<[u16; 4]>> = reader.read_joints(0)
+        .map(|joints| joints.into_u16().collect());
+    let weights: Option<Vec<[f32; 4]>> = reader.read_weights(0)
+        .map(|weights| weights.into_f32().collect());
+
+    check_attribute(mesh_name, "normals", normals.as_deref(), count)?;
+    check_attribute(mesh_name, "texcoords", uvs.as_deref(), count)?;
+    check_attribute(mesh_name, "second texcoords", uvs2.as_deref(), count)?;
+    check_attribute(mesh_name, "joints", joints.as_deref(), count)?;
+    check_attribute(mesh_name, "weights", weights.as_deref(), count)?;
+
+    // glTF texcoords already have their origin in the top left, same as wgpu,
+    // so there's no flipping here.
+    let mut vertex_data: Vec<Vertex> = (0..count).map(|i| Vertex {
+        position: positions[i],
+        normal: normals.as_ref().map_or([0.0, 1.0, 0.0], |normals| normals[i]),
+        uv: uvs.as_ref().map_or([0.0, 0.0], |uvs| uvs[i]),
+        uv2: uvs2.as_ref().map_or([0.0, 0.0], |uvs| uvs[i]),
+        joints: joints.as_ref().map_or([0; 4], |joints| joints[i].map(u32::from)),
+        weights: weights.as_ref().map_or([0.0; 4], |weights| weights[i]),
+    }).collect();
+
+    let indices: Vec<u32> = reader.read_indices()
+        .map(|indices| indices.into_u32().collect())
+        .unwrap_or_else(|| (0..count as u32).collect());
+
+    let index_data = match mode {
+        Mode::TriangleStrip => {
+            // Every other triangle in a strip has its winding flipped.
+            let mut index_data = Vec::new();
+            for (i, tri) in indices.windows(3).enumerate() {
+                if i % 2 == 0 {
+                    index_data.extend_from_slice(&[tri[0], tri[1], tri[2]]);
+                }
+                else {
+                    index_data.extend_from_slice(&[tri[1], tri[0], tri[2]]);
+                }
+            }
+            index_data
+        }
+        Mode::TriangleFan => {
+            let mut index_data = Vec::new();
+            triangulate_face(mesh_name, &indices, &mut index_data)?;
+            index_data
+        }
+        _ => indices,
+    };
+    check_indices(mesh_name, &index_data, count)?;
+
+    if normals.is_none() {
+        log::warn!("import: mesh {} has no normals, generating them", mesh_name);
+        generate_normals(&mut vertex_data, &index_data);
+    }
+    if uvs.is_none() {
+        log::warn!("import: mesh {} has no texcoords, generating them", mesh_name);
+        generate_uvs(&mut vertex_data);
+    }
+
+    Ok(Some(MeshData { vertex_data, index_data }))
 }
 
-pub fn import_mesh_set_as_gc<const N: usize>(engine: &Engine, data: &[u8], mesh_names: &[&str; N]) -> Option<[Gp<Mesh>; N]> {
-    if let Some(meshes) = import_mesh_set(data, mesh_names) {
-        let ready = core::array::from_fn(|idx| {
-            Gp::new(Mesh::new(engine.render_ctx(), &meshes[idx]))
+fn import_gltf_animation(animation: &gltf::Animation, buffers: &[gltf::buffer::Data], node_map: &HashMap<usize, usize>) -> AnimationClip {
+    use gltf::animation::util::ReadOutputs;
+
+    log::info!("import: animation = {:?}", animation.name());
+
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        // Channels for nodes that aren't part of the scene can't do anything.
+        let Some(node) = node_map.get(&channel.target().node().index()) else { continue; };
+
+        let reader = channel.reader(|buffer| buffers.get(buffer.index()).map(|data| &data.0[..]));
+        let (Some(times), Some(outputs)) = (reader.read_inputs(), reader.read_outputs()) else { continue; };
+
+        let values = match outputs {
+            ReadOutputs::Translations(values) => ChannelValues::Translation(values.map(Vector3::from).collect()),
+            ReadOutputs::Scales(values) => ChannelValues::Scale(values.map(Vector3::from).collect()),
+            ReadOutputs::Rotations(values) => ChannelValues::Rotation(values.into_f32()
+                .map(|[x, y, z, w]| Quaternion::new(w, x, y, z))
+                .collect()),
+            ReadOutputs::MorphTargetWeights(_) => {
+                log::warn!("import: skipping morph target animation");
+                continue;
+            }
+        };
+
+        channels.push(Channel {
+            node: *node,
+            interpolation: match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            },
+            times: times.collect(),
+            values,
+        });
+    }
+
+    let duration = channels.iter()
+        .filter_map(|channel| channel.times.last().copied())
+        .fold(0.0, f32::max);
+
+    AnimationClip {
+        name: animation.name().map(String::from),
+        duration,
+        channels,
+    }
+}
+
+/// `node_map` maps glTF node indices to indices into `nodes`, as the two
+/// aren't the same.
+fn import_gltf_node(node: gltf::Node, nodes: &mut Vec<NodeData>, node_map: &mut HashMap<usize, usize>) -> usize {
+    let idx = nodes.len();
+    node_map.insert(node.index(), idx);
+    let (translation, rotation, scale) = node.transform().decomposed();
+    nodes.push(NodeData {
+        name: node.name().map(String::from),
+        transform: Matrix4::from(node.transform().matrix()),
+        pose: NodePose {
+            translation: translation.into(),
+            rotation: Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]),
+            scale: scale.into(),
+        },
+        mesh: node.mesh().map(|mesh| mesh.index()),
+        skin: node.skin().map(|skin| skin.index()),
+        children: Vec::new(),
+    });
+
+    let children = node.children().map(|child| import_gltf_node(child, nodes, node_map)).collect();
+    nodes[idx].children = children;
+    idx
+}
+
+/// Imports the default scene (or otherwise the first scene) of a .glb file.
+pub fn import_scene_data(data: &[u8]) -> EngineResult<SceneData> {
+    log::info!("begin scene import");
+
+    let (document, buffers, images) = gltf::import_slice(data)?;
+
+    let Some(scene) = document.default_scene().or_else(|| document.scenes().next()) else {
+        return Err(EngineError::AssetParse("file contains no scenes".to_string()));
+    };
+
+    let mut nodes = Vec::new();
+    let mut node_map = HashMap::new();
+    let roots = scene.nodes().map(|node| import_gltf_node(node, &mut nodes, &mut node_map)).collect();
+
+    let mut meshes = Vec::new();
+    for mesh in document.meshes() {
+        log::info!("import: mesh = {:?}", mesh.name());
+        let mesh_name = mesh.name().unwrap_or("<unnamed>");
+
+        let mut primitives = Vec::new();
+        for primitive in mesh.primitives() {
+            if let Some(data) = import_gltf_primitive(mesh_name, &primitive, &buffers)? {
+                primitives.push(PrimitiveData {
+                    mesh: data,
+                    material: primitive.material().index(),
+                });
+            }
+        }
+
+        meshes.push(SceneMeshData {
+            name: mesh.name().map(String::from),
+            primitives,
         });
-        Some(ready)
     }
-    else {
-        None
+
+    let materials = document.materials().map(|material| {
+        let pbr = material.pbr_metallic_roughness();
+        MaterialData {
+            name: material.name().map(String::from),
+            base_color: pbr.base_color_factor(),
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            alpha_blended: material.alpha_mode() == gltf::material::AlphaMode::Blend,
+            base_color_texture: pbr.base_color_texture()
+                .map(|info| info.texture().source().index()),
+            metallic_roughness_texture: pbr.metallic_roughness_texture()
+                .map(|info| info.texture().source().index()),
+        }
+    }).collect();
+
+    let images = images.into_iter().map(|image| {
+        // Keep the indices lined up even if an image can't be converted.
+        convert_gltf_image(image).unwrap_or_else(|| {
+            let mut white = image::RgbaImage::new(1, 1);
+            *white.get_pixel_mut(0, 0) = image::Rgba([255, 255, 255, 255]);
+            image::DynamicImage::ImageRgba8(white)
+        })
+    }).collect();
+
+    let mut skins = Vec::new();
+    for skin in document.skins() {
+        let reader = skin.reader(|buffer| buffers.get(buffer.index()).map(|data| &data.0[..]));
+
+        let mut joints = Vec::new();
+        for joint in skin.joints() {
+            let Some(idx) = node_map.get(&joint.index()) else {
+                return Err(EngineError::AssetParse(format!("skin {:?} uses a joint outside of the scene", skin.name())));
+            };
+            joints.push(*idx);
+        }
+
+        // Without inverse bind matrices, the joints are already in model space.
+        let inverse_bind_matrices = match reader.read_inverse_bind_matrices() {
+            Some(matrices) => matrices.map(Matrix4::from).collect(),
+            None => vec![Matrix4::identity(); joints.len()],
+        };
+        if inverse_bind_matrices.len() != joints.len() {
+            return Err(EngineError::AssetParse(format!("skin {:?} has {} joints, but {} inverse bind matrices",
+                skin.name(), joints.len(), inverse_bind_matrices.len())));
+        }
+
+        skins.push(SkinData {
+            name: skin.name().map(String::from),
+            joints,
+            inverse_bind_matrices,
+        });
+    }
+
+    let animations = document.animations()
+        .map(|animation| import_gltf_animation(&animation, &buffers, &node_map))
+        .collect();
+
+    log::info!("finish scene import");
+
+    Ok(SceneData { nodes, roots, meshes, materials, images, skins, animations })
+}
+
+pub struct ScenePrimitive {
+    pub mesh: Gp<Mesh>,
+    pub material: Gp<PBRMaterial>,
+}
+
+pub struct SceneMesh {
+    pub name: Option<String>,
+    pub primitives: Vec<ScenePrimitive>,
+}
+
+/// A glTF scene with all of its meshes, materials and textures uploaded to
+/// the GPU. Use instantiate() to get MeshInstances to push into a World.
+pub struct Scene {
+    pub nodes: Vec<NodeData>,
+    pub roots: Vec<usize>,
+    pub meshes: Vec<SceneMesh>,
+    pub materials: Vec<Gp<PBRMaterial>>,
+    pub skins: Vec<SkinData>,
+    /// Shared with every Animator created from this Scene.
+    pub animations: Rc<[AnimationClip]>,
+}
+
+impl Scene {
+    pub fn new(ctx: &RenderCtx, data: &SceneData) -> Self {
+        // The same image could in theory be used as both an sRGB and a linear
+        // texture, so key the cache on both.
+        let mut textures: HashMap<(usize, bool), Texture> = HashMap::new();
+        let mut get_texture = |idx: usize, srgb: bool| -> Texture {
+            textures.entry((idx, srgb)).or_insert_with(|| {
+                let image = &data.images[idx];
+                if srgb {
+                    Texture::from_image_rgba8srgb(ctx, image, Some("Scene::texture"), true)
+                }
+                else {
+                    Texture::from_image_rgba8linear(ctx, image, Some("Scene::texture"), true)
+                }
+            }).clone()
+        };
+
+        let materials: Vec<_> = data.materials.iter().map(|material| {
+            let mut pbr = PBRMaterial::default(ctx);
+            pbr.albedo = vec3(material.base_color[0], material.base_color[1], material.base_color[2]);
+            pbr.metallic = material.metallic;
+            pbr.roughness = material.roughness;
+            pbr.alpha_blended = material.alpha_blended;
+            if let Some(idx) = material.base_color_texture {
+                pbr.albedo_texture = get_texture(idx, true);
+            }
+            if let Some(idx) = material.metallic_roughness_texture {
+                pbr.metallic_roughness_texture = get_texture(idx, false);
+            }
+            Gp::new(pbr)
+        }).collect();
+
+        // Only created if some primitive actually has no material.
+        let mut default_material: Option<Gp<PBRMaterial>> = None;
+
+        let meshes = data.meshes.iter().map(|mesh| SceneMesh {
+            name: mesh.name.clone(),
+            primitives: mesh.primitives.iter().map(|primitive| ScenePrimitive {
+                mesh: Gp::new(Mesh::new(ctx, &primitive.mesh)),
+                material: match primitive.material {
+                    Some(idx) => materials[idx].clone(),
+                    None => default_material
+                        .get_or_insert_with(|| Gp::new(PBRMaterial::default(ctx)))
+                        .clone(),
+                },
+            }).collect(),
+        }).collect();
+
+        Scene {
+            nodes: data.nodes.clone(),
+            roots: data.roots.clone(),
+            meshes,
+            materials,
+            skins: data.skins.clone(),
+            animations: Rc::from(data.animations.clone()),
+        }
     }
-}
\ No newline at end of file
+
+    pub fn find_node(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name.as_deref() == Some(name))
+    }
+
+    pub fn find_mesh(&self, name: &str) -> Option<&SceneMesh> {
+        self.meshes.iter().find(|mesh| mesh.name.as_deref() == Some(name))
+    }
+
+    /// Creates a MeshInstance for every primitive in the scene, placed
+    /// according to the node hierarchy and then the given transform.
+    ///
+    /// Skinned meshes are left in their bind pose; see instantiate_animated().
+    pub fn instantiate(&self, transform: Matrix4<f32>) -> Vec<Gp<MeshInstance>> {
+        let mut instances = Vec::new();
+        for root in &self.roots {
+            self.instantiate_recursive(*root, transform, None, &mut instances);
+        }
+        instances
+    }
+
+    /// Like instantiate(), but also creates an Animator, whose Skins are given
+    /// to the instances of skinned meshes.
+    pub fn instantiate_animated(&self, ctx: &RenderCtx, transform: Matrix4<f32>) -> (Vec<Gp<MeshInstance>>, Animator) {
+        let animator = Animator::new(ctx, self);
+        let mut instances = Vec::new();
+        for root in &self.roots {
+            self.instantiate_recursive(*root, transform, Some(&animator), &mut instances);
+        }
+        (instances, animator)
+    }
+
+    /// Like instantiate(), but only for the given node and its children. The
+    /// node's own transform is still applied.
+    pub fn instantiate_node(&self, node: usize, transform: Matrix4<f32>) -> Vec<Gp<MeshInstance>> {
+        let mut instances = Vec::new();
+        self.instantiate_recursive(node, transform, None, &mut instances);
+        instances
+    }
+
+    fn instantiate_recursive(&self, idx: usize, parent: Matrix4<f32>, animator: Option<&Animator>, instances: &mut Vec<Gp<MeshInstance>>) {
+        let node = &self.nodes[idx];
+        let transform = parent * node.transform;
+
+        if let Some(mesh) = node.mesh {
+            let skin = animator.and_then(|animator| animator.skin_for_node(idx));
+            for primitive in &self.meshes[mesh].primitives {
+                let instance = MeshInstance::new(
+                    primitive.mesh.clone(),
+                    primitive.material.clone(),
+                    transform);
+                instance.skin.set(skin.as_ref());
+                instances.push(Gp::new(instance));
+            }
+        }
+
+        for child in &node.children {
+            self.instantiate_recursive(*child, transform, animator, instances);
+        }
+    }
+}
+
+pub fn import_scene(ctx: &RenderCtx, data: &[u8]) -> EngineResult<Scene> {
+    import_scene_data(data).map(|data| Scene::new(ctx, &data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import_binary_data, import_mesh_set, import_scene_data};
+    use crate::error::EngineError;
+
+    /// Packs a glTF JSON document and its binary buffer into a .glb file.
+    fn glb(json: &str, bin: &[u8]) -> Vec<u8> {
+        let mut json = json.as_bytes().to_vec();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut bin = bin.to_vec();
+        bin.resize(bin.len().next_multiple_of(4), 0);
+
+        let total = 12 + 8 + json.len() + 8 + bin.len();
+        let mut out = Vec::with_capacity(total);
+        out.extend_from_slice(b"glTF");
+        out.extend_from_slice(&2u32.to_le_bytes());
+        out.extend_from_slice(&(total as u32).to_le_bytes());
+        out.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"JSON");
+        out.extend_from_slice(&json);
+        out.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"BIN\0");
+        out.extend_from_slice(&bin);
+        out
+    }
+
+    /// A single triangle, with the given indices and optionally as many
+    /// normals as given.
+    fn triangle(indices: [u32; 3], normal_count: Option<usize>) -> Vec<u8> {
+        let mut bin = Vec::new();
+        for value in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            bin.extend_from_slice(&value.to_le_bytes());
+        }
+        for index in indices {
+            bin.extend_from_slice(&index.to_le_bytes());
+        }
+        let normal_count = normal_count.unwrap_or(0);
+        for _ in 0..normal_count {
+            for value in [0.0f32, 0.0, 1.0] {
+                bin.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let (normal_attribute, normal_view, normal_accessor) = if normal_count > 0 {
+            (
+                r#","NORMAL":2"#.to_string(),
+                format!(r#",{{"buffer":0,"byteOffset":48,"byteLength":{}}}"#, normal_count * 12),
+                format!(r#",{{"bufferView":2,"componentType":5126,"count":{},"type":"VEC3"}}"#, normal_count),
+            )
+        }
+        else {
+            Default::default()
+        };
+
+        let json = format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scene": 0,
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [{{ "mesh": 0 }}],
+            "meshes": [{{ "name": "triangle", "primitives": [{{ "attributes": {{ "POSITION": 0{normal_attribute} }}, "indices": 1 }}] }}],
+            "buffers": [{{ "byteLength": {len} }}],
+            "bufferViews": [
+                {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                {{ "buffer": 0, "byteOffset": 36, "byteLength": 12 }}{normal_view}
+            ],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }},
+                {{ "bufferView": 1, "componentType": 5125, "count": 3, "type": "SCALAR" }}{normal_accessor}
+            ]
+        }}"#, len = bin.len());
+
+        glb(&json, &bin)
+    }
+
+    #[test]
+    fn imports_a_valid_triangle() {
+        let scene = import_scene_data(&triangle([0, 1, 2], Some(3))).expect("valid triangle failed to import");
+        let mesh = &scene.meshes[0].primitives[0].mesh;
+        assert_eq!(mesh.vertex_data.len(), 3);
+        assert_eq!(mesh.index_data, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_out_of_range_indices() {
+        let result = import_scene_data(&triangle([0, 1, 5], None));
+        assert!(matches!(result, Err(EngineError::AssetParse(_))));
+    }
+
+    #[test]
+    fn rejects_short_attributes() {
+        let result = import_scene_data(&triangle([0, 1, 2], Some(2)));
+        assert!(matches!(result, Err(EngineError::AssetParse(_))));
+    }
+
+    #[test]
+    fn mesh_functions_use_the_scene_importer() {
+        let data = triangle([0, 1, 2], Some(3));
+        assert_eq!(import_binary_data(&data).unwrap().index_data, vec![0, 1, 2]);
+
+        let [mesh] = import_mesh_set(&data, &["triangle"]).unwrap();
+        assert_eq!(mesh.vertex_data.len(), 3);
+        assert!(matches!(import_mesh_set(&data, &["square"]), Err(EngineError::MeshNotFound(_))));
+
+        // The same checks as for whole scenes.
+        let result = import_mesh_set(&triangle([0, 1, 5], None), &["triangle"]);
+        assert!(matches!(result, Err(EngineError::AssetParse(_))));
+    }
+}
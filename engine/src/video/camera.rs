@@ -72,10 +72,12 @@ impl Camera {
         Some(ray_point + ray_dir * t)
     }
 
+    /// The cursor position is relative to the Viewport's target (e.g. the
+    /// window), not to the Viewport itself.
     pub fn convert_screen_to_normalized_device(&self, viewport: &Viewport, cursor_pos: Vector2<f32>) -> Vector2<f32> {
         let normalized = vec2(
-            cursor_pos.x / (0.5 * viewport.width as f32),
-            cursor_pos.y / (-0.5 * viewport.height as f32),
+            (cursor_pos.x - viewport.x as f32) / (0.5 * viewport.width as f32),
+            (cursor_pos.y - viewport.y as f32) / (-0.5 * viewport.height as f32),
         ) + vec2(-1.0, 1.0);
         return normalized;
         // match self.projection.get() {
@@ -8,6 +8,7 @@ pub struct HdrTonemapPipeline {
     pub bind_group: wgpu::BindGroup,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Tonemap {
     None,
     Aces,
@@ -58,7 +59,7 @@ impl HdrTonemapPipeline {
         (texture, view, bind_group)
     }
 
-    pub fn new(width: u32, height: u32, ctx: &RenderCtx, config: &wgpu::SurfaceConfiguration, tonemap: Tonemap) -> Self {
+    pub fn new(width: u32, height: u32, ctx: &RenderCtx, output_format: wgpu::TextureFormat, tonemap: Tonemap) -> Self {
         let (texture, view, bind_group) =
             Self::create_texture(width, height, ctx);
 
@@ -73,9 +74,9 @@ impl HdrTonemapPipeline {
         });
 
         // Choose whether the shader needs to do an SRGB-related remapping or not
-        // based on the config format.
+        // based on the output format.
         
-        let tonemapper = if config.format.is_srgb() {
+        let tonemapper = if output_format.is_srgb() {
             match tonemap {
                 Tonemap::None => "tonemap_none_to_srgb",
                 Tonemap::Aces => "tonemap_aces_to_srgb",
@@ -108,7 +109,7 @@ impl HdrTonemapPipeline {
                     // TODO:
                     // If we re-use the same pipeline for multiple Surfaces,
                     // how do we make sure this format works correctly?
-                    format: config.format,
+                    format: output_format,
                     blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                     write_mask: wgpu::ColorWrites::all()
                 })],
@@ -17,29 +17,58 @@ struct PBR {
     reflectance: f32,
 }
 
-struct InstanceData {
-    @location(4) row0: vec3f,
-    @location(5) row1: vec3f,
-    @location(6) row2: vec3f,
-    @location(7) row3: vec3f,
+// Must match InstanceData in mesh_render_pipeline.rs.
+struct InstanceInput {
+    // The columns of the transform matrix.
+    @location(4) transform0: vec4f,
+    @location(5) transform1: vec4f,
+    @location(6) transform2: vec4f,
+    @location(7) transform3: vec4f,
+    @location(8) modulate: vec4f,
 }
 
+// Must match the LIGHT_KIND_* constants in world.rs.
+const LIGHT_DIRECTIONAL: u32 = 0u;
+const LIGHT_POINT: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
 struct Light {
-    // Eye-space directions.
+    // Eye-space position and direction.
+    position: vec3f,
+    kind: u32,
     direction: vec3f,
+    range: f32,
     color: vec3f,
+    cos_inner: f32,
+    cos_outer: f32,
+}
+
+// Must match Skin::MAX_JOINTS in animation.rs.
+const MAX_JOINTS: u32 = 64u;
+
+struct Skin {
+    joints: array<mat4x4f, MAX_JOINTS>,
 }
 
-struct ModelUniform {
-    transform: mat4x4f,
-    modulate: vec4f,
+struct Lights {
+    count: u32,
+    // The index of the light that the shadow map belongs to, or 0xFFFFFFFF.
+    shadow_light: u32,
+    // MAX_LIGHTS is prepended by PBRShader::new.
+    lights: array<Light, MAX_LIGHTS>,
+}
+
+struct ShadowUniform {
+    // Eye-space to shadow map clip space.
+    eye_to_shadow: mat4x4f,
+    texel_size: f32,
 }
 
 // Future group layout:
 // Group 0: World: includes viewport, environment map, lights
 // Group 1: Material: includes material properties, textures
-// Group 2: Model: (Maybe replaced by instance data one day):
-//          includes transform matrix, per-model tweaks (color?)
+// Group 2: Skin: joint matrices for skinned meshes
+// Per-model data (transform matrix, modulate) comes from the instance buffer.
 //
 // The fundamental render process looks something like:
 //
@@ -61,9 +90,12 @@ struct ModelUniform {
 // }
 
 @group(0) @binding(0) var<uniform> viewport: ViewportUniform;
-@group(0) @binding(1) var<uniform> lights: array<Light, 1>;
+@group(0) @binding(1) var<uniform> lights: Lights;
 @group(0) @binding(2) var envmap_t: texture_2d<f32>;
 @group(0) @binding(3) var envmap_s: sampler;
+@group(0) @binding(4) var<uniform> shadow: ShadowUniform;
+@group(0) @binding(5) var shadow_t: texture_depth_2d;
+@group(0) @binding(6) var shadow_s: sampler_comparison;
 
 @group(1) @binding(0) var<uniform> pbr: PBR;
 @group(1) @binding(1) var albedo_t: texture_2d<f32>;
@@ -72,13 +104,16 @@ struct ModelUniform {
 @group(1) @binding(4) var metallic_rough_decal_t: texture_2d<f32>;
 @group(1) @binding(5) var pbr_s: sampler;
 
-@group(2) @binding(0) var<uniform> model: ModelUniform;
+@group(2) @binding(0) var<uniform> skin: Skin;
+
 
 struct VertexInput {
     @location(0) position: vec3f,
     @location(1) normal  : vec3f,
     @location(2) uv      : vec2f,
     @location(3) uv2     : vec2f,
+    @location(9) joints  : vec4u,
+    @location(10) weights: vec4f,
 }
 
 struct VertexOutput {
@@ -89,6 +124,7 @@ struct VertexOutput {
     @location(1) f_normal: vec3f,
     @location(2) uv      : vec2f,
     @location(3) uv2     : vec2f,
+    @location(4) modulate: vec4f,
 }
 
 fn expand_transformation_matrix(in: mat4x3f) -> mat4x4f {
@@ -100,14 +136,35 @@ fn expand_transformation_matrix(in: mat4x3f) -> mat4x4f {
     );
 }
 
+// The weighted sum of the joint matrices for a vertex. Vertices of unskinned
+// meshes have no weights at all, and are left in place.
+fn skin_matrix(joints: vec4u, weights: vec4f) -> mat4x4f {
+    let total = weights.x + weights.y + weights.z + weights.w;
+    if (total <= 0.0) {
+        return mat4x4f(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+    }
+
+    return skin.joints[joints.x] * weights.x
+        + skin.joints[joints.y] * weights.y
+        + skin.joints[joints.z] * weights.z
+        + skin.joints[joints.w] * weights.w;
+}
+
 @vertex
 fn vs_main(
     @builtin(vertex_index) in_vertex_index: u32,
     vertex: VertexInput,
+    instance: InstanceInput,
 ) -> VertexOutput {
     var out: VertexOutput;
 
-    let m: mat4x4f = model.transform;
+    let m: mat4x4f = mat4x4f(instance.transform0, instance.transform1, instance.transform2, instance.transform3)
+        * skin_matrix(vertex.joints, vertex.weights);
     // let m: mat4x4f = mat4x4f(
     //     1.0, 0.0, 0.0, 0.0,
     //     0.0, 1.0, 0.0, 0.0,
@@ -127,6 +184,7 @@ fn vs_main(
     out.f_normal = (viewport.view * m * vec4(vertex.normal, 0.0)).xyz;
     out.uv = vertex.uv;
     out.uv2 = vertex.uv2;
+    out.modulate = instance.modulate;
 
     return out;
 }
@@ -276,6 +334,56 @@ struct PBROut {
     normal: vec3f,
 }
 
+/// Returns the (eye-space) direction from the fragment towards the light.
+fn light_direction(light: Light, f_pos: vec3f) -> vec3f {
+    if light.kind == LIGHT_DIRECTIONAL {
+        return -normalize(light.direction);
+    }
+    return normalize(light.position - f_pos);
+}
+
+/// Distance and cone falloff. Directional lights are never attenuated.
+fn light_attenuation(light: Light, f_pos: vec3f) -> f32 {
+    if light.kind == LIGHT_DIRECTIONAL {
+        return 1.0;
+    }
+
+    // Inverse square falloff, windowed so that it reaches zero at the range.
+    let dist = length(light.position - f_pos);
+    let window = clamp(1.0 - pow(dist / light.range, 4.0), 0.0, 1.0);
+    var atten = window * window / max(dist * dist, 0.0001);
+
+    if light.kind == LIGHT_SPOT {
+        let cos_angle = dot(normalize(f_pos - light.position), normalize(light.direction));
+        atten *= smoothstep(light.cos_outer, light.cos_inner, cos_angle);
+    }
+
+    return atten;
+}
+
+/// Returns how lit the fragment is by the shadowed light, from 0 (fully in
+/// shadow) to 1. Uses 3x3 PCF.
+fn shadow_factor(f_pos: vec3f, normal: vec3f) -> f32 {
+    // Offset along the normal a little to further reduce acne.
+    let pos = shadow.eye_to_shadow * vec4f(f_pos + normal * 0.02, 1.0);
+    let ndc = pos.xyz / pos.w;
+    let uv = ndc.xy * vec2f(0.5, -0.5) + vec2f(0.5);
+
+    // Anything outside of the shadow map is lit.
+    if any(uv < vec2f(0.0)) || any(uv > vec2f(1.0)) || ndc.z > 1.0 {
+        return 1.0;
+    }
+
+    var sum = 0.0;
+    for(var y = -1; y <= 1; y += 1) {
+        for(var x = -1; x <= 1; x += 1) {
+            let offset = vec2f(f32(x), f32(y)) * shadow.texel_size;
+            sum += textureSampleCompareLevel(shadow_t, shadow_s, uv + offset, ndc.z);
+        }
+    }
+    return sum / 9.0;
+}
+
 @fragment
 fn pbr_main(in: VertexOutput) -> @location(0) vec4f {
     let param = pbr_fn(in);
@@ -289,13 +397,15 @@ fn pbr_main(in: VertexOutput) -> @location(0) vec4f {
     bin.n = param.normal;
 
     var sum = vec3f(0.0);
-    for(var i = 0; i < 1; i += 1) {
-        // These are currently passed in eye-space.
-        var l_direction = lights[i].direction;
-
-        bin.l = -normalize(l_direction);
+    for(var i = 0u; i < lights.count; i += 1u) {
+        let light = lights.lights[i];
+        bin.l = light_direction(light, in.f_pos);
         let brdf = BRDF_attentuated(bin);
-        sum += brdf * lights[i].color;
+        var atten = light_attenuation(light, in.f_pos);
+        if i == lights.shadow_light {
+            atten *= shadow_factor(in.f_pos, bin.n);
+        }
+        sum += brdf * light.color * atten;
     }
 
     if true {
@@ -349,14 +459,14 @@ fn pbr_default(in: VertexOutput) -> PBROut {
 
     var reflectance = pbr.reflectance;
 
-    var base_color = vec3(1.0, 1.0, 1.0);
+    var base_color = pbr.albedo;
     if true {
         var albedo_tex = textureSample(albedo_t, pbr_s, in.uv).rgb;
         albedo_tex = mix(albedo_tex, albedo_decal.rgb, albedo_decal.a);
         base_color *= albedo_tex;
     }
 
-    out.albedo = base_color * model.modulate.rgb;
+    out.albedo = base_color * in.modulate.rgb;
     
     out.roughness = clamp(perceptual_roughness * perceptual_roughness, 0.01, 1.0);
 
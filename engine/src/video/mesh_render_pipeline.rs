@@ -1,9 +1,9 @@
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 
-use cgmath::{vec4, Vector4};
+use cgmath::{point3, vec4, Vector4};
 
-use crate::{gc::Gp, video::{asset_import::MeshData, hdr_tonemap::HdrTonemapPipeline, texture::{self}, IndexBuffer, PBRMaterial, RenderCtx, UniformBuffer, VertexBuffer}};
+use crate::{gc::{Gp, GpMaybe}, video::{animation::Skin, asset_import::MeshData, hdr_tonemap::HdrTonemapPipeline, texture::{self}, IndexBuffer, PBRMaterial, RenderCtx, VertexBuffer}};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -12,15 +12,22 @@ pub struct Vertex {
     pub normal  : [f32; 3],
     pub uv      : [f32; 2],
     pub uv2     : [f32; 2],
+    /// Indices into the joint matrices of the instance's Skin. Only used if
+    /// some of the weights are non-zero.
+    pub joints  : [u32; 4],
+    pub weights : [f32; 4],
 }
 
 impl Vertex {
-    fn desc() -> wgpu::VertexBufferLayout<'static> {
+    pub(crate) fn desc() -> wgpu::VertexBufferLayout<'static> {
         const ATTRIBS: &[wgpu::VertexAttribute] = &wgpu::vertex_attr_array![
             0 => Float32x3,
             1 => Float32x3,
             2 => Float32x2,
             3 => Float32x2,
+            // 4 through 8 are used by the instance buffer.
+            9 => Uint32x4,
+            10 => Float32x4,
          ];
 
         wgpu::VertexBufferLayout {
@@ -29,60 +36,72 @@ impl Vertex {
             attributes: ATTRIBS
         }
     }
+
+    /// The layout of the per-instance InstanceData, which is bound as the
+    /// second vertex buffer.
+    pub(crate) fn instance_desc() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBS: &[wgpu::VertexAttribute] = &wgpu::vertex_attr_array![
+            4 => Float32x4,
+            5 => Float32x4,
+            6 => Float32x4,
+            7 => Float32x4,
+            8 => Float32x4,
+         ];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<InstanceData>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: ATTRIBS
+        }
+    }
 }
 
 pub struct Mesh {
-    vertex_buffer: VertexBuffer,
-    index_buffer: IndexBuffer,
+    pub(crate) vertex_buffer: VertexBuffer,
+    pub(crate) index_buffer: IndexBuffer,
 
     #[expect(unused)]
     vertex_count: u32,
-    index_count: u32,
+    pub(crate) index_count: u32,
+
+    /// The (min, max) corners of the model-space bounding box. Used to fit
+    /// the shadow map around the World.
+    pub(crate) bounds: (cgmath::Point3<f32>, cgmath::Point3<f32>),
 }
 
+/// Per-instance data, stored in a Viewport's instance buffer. Must match
+/// InstanceInput in mesh.wgsl.
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct MeshInstanceUniform {
+pub struct InstanceData {
     transform: [[f32; 4]; 4],
     modulate: [f32; 4],
 }
 
 pub struct MeshInstance {
-    mesh: Gp<Mesh>,
+    pub(crate) mesh: Gp<Mesh>,
     pub material: Gp<PBRMaterial>,
 
     pub modulate: Cell<cgmath::Vector4<f32>>,
 
     pub transform: Cell<cgmath::Matrix4<f32>>,
 
-    /// Uniform buffer for our instance.
-    uniform_buffer: UniformBuffer,
+    /// Whether the instance is rendered into shadow maps. Things like
+    /// emissive effects usually shouldn't be.
+    pub casts_shadow: Cell<bool>,
 
-    /// Bind group for the instance. Contains per-instance uniform (and texture)
-    /// data.
-    instance_bind_group: wgpu::BindGroup,
+    /// The joint matrices for a skinned mesh, usually owned by an Animator.
+    /// Instances without a Skin are drawn in their rest pose.
+    pub skin: GpMaybe<Skin>,
 }
 
 impl MeshInstance {
-    pub fn new(ctx: &RenderCtx, mesh: Gp<Mesh>, material: Gp<PBRMaterial>, transform: cgmath::Matrix4<f32>) -> Self {
-        Self::new_modulate(ctx, mesh, material, transform, vec4(1.0, 1.0, 1.0, 1.0))
-    }
-
-    pub fn new_modulate(ctx: &RenderCtx, mesh: Gp<Mesh>, material: Gp<PBRMaterial>, transform: cgmath::Matrix4<f32>, modulate: Vector4<f32>) -> Self {
-        let uniform_buffer = ctx.create_uniform_buffer_init_zero::<MeshInstanceUniform>("MeshInstance::uniform_buffer");
-        
-        let instance_bind_group = ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("MeshInstance::instance_bind_group"),
-            layout: &ctx.layouts.mesh_3d,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: uniform_buffer.0.as_entire_binding()
-                }
-            ],
-        });
+    pub fn new(mesh: Gp<Mesh>, material: Gp<PBRMaterial>, transform: cgmath::Matrix4<f32>) -> Self {
+        Self::new_modulate(mesh, material, transform, vec4(1.0, 1.0, 1.0, 1.0))
+    }
 
-        let instance = Self {
+    pub fn new_modulate(mesh: Gp<Mesh>, material: Gp<PBRMaterial>, transform: cgmath::Matrix4<f32>, modulate: Vector4<f32>) -> Self {
+        Self {
             mesh,
             material,
 
@@ -90,20 +109,99 @@ impl MeshInstance {
 
             transform: Cell::new(transform),
 
-            uniform_buffer,
-            instance_bind_group,
-        };
+            casts_shadow: Cell::new(true),
 
-        instance.update(ctx);
-        instance
+            skin: GpMaybe::none(),
+        }
     }
 
-    pub fn update(&self, ctx: &RenderCtx) {
-        let uniform = MeshInstanceUniform {
+    pub fn to_instance_data(&self) -> InstanceData {
+        InstanceData {
             transform: self.transform.get().into(),
             modulate: self.modulate.get().into(),
-        };
-        ctx.queue.write_buffer(&self.uniform_buffer.0, 0, bytemuck::cast_slice(&[uniform]));
+        }
+    }
+}
+
+/// A group of instances that share a Mesh, PBRMaterial and Skin, and so can be drawn
+/// with a single instanced draw call. The instances themselves are the range
+/// `first..first + count` of the instance buffer.
+pub struct InstanceBatch {
+    pub(crate) mesh: Gp<Mesh>,
+    pub(crate) material: Gp<PBRMaterial>,
+    pub(crate) skin: Option<Gp<Skin>>,
+    pub(crate) first: u32,
+    pub(crate) count: u32,
+}
+
+impl InstanceBatch {
+    pub fn mesh(&self) -> &Gp<Mesh> {
+        &self.mesh
+    }
+
+    pub fn material(&self) -> &Gp<PBRMaterial> {
+        &self.material
+    }
+
+    /// How many instances the batch draws.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// The bind group with the joint matrices for this batch. Unskinned
+    /// batches use a shared Skin of identity matrices.
+    pub(crate) fn skin_bind_group<'a>(&'a self, ctx: &'a RenderCtx) -> &'a wgpu::BindGroup {
+        match &self.skin {
+            Some(skin) => &skin.bind_group,
+            None => &ctx.shaders().default_skin.bind_group,
+        }
+    }
+}
+
+/// A vertex buffer of InstanceData that is re-written every frame, and grows
+/// as needed.
+pub struct InstanceBuffer {
+    buffer: RefCell<wgpu::Buffer>,
+    capacity: Cell<usize>,
+}
+
+impl InstanceBuffer {
+    const INITIAL_CAPACITY: usize = 64;
+
+    fn create(ctx: &RenderCtx, capacity: usize) -> wgpu::Buffer {
+        ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("InstanceBuffer"),
+            size: (capacity * std::mem::size_of::<InstanceData>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn new(ctx: &RenderCtx) -> Self {
+        InstanceBuffer {
+            buffer: RefCell::new(Self::create(ctx, Self::INITIAL_CAPACITY)),
+            capacity: Cell::new(Self::INITIAL_CAPACITY),
+        }
+    }
+
+    pub fn write(&self, ctx: &RenderCtx, data: &[InstanceData]) {
+        if data.len() > self.capacity.get() {
+            let capacity = data.len().next_power_of_two();
+            *self.buffer.borrow_mut() = Self::create(ctx, capacity);
+            self.capacity.set(capacity);
+        }
+
+        if !data.is_empty() {
+            ctx.queue.write_buffer(&self.buffer.borrow(), 0, bytemuck::cast_slice(data));
+        }
+    }
+
+    /// Binds the instances of the given batch to vertex buffer slot 1.
+    pub fn bind_batch(&self, pass: &mut wgpu::RenderPass, batch: &InstanceBatch) {
+        let stride = std::mem::size_of::<InstanceData>() as wgpu::BufferAddress;
+        let start = batch.first as wgpu::BufferAddress * stride;
+        let end = start + batch.count as wgpu::BufferAddress * stride;
+        pass.set_vertex_buffer(1, self.buffer.borrow().slice(start..end));
     }
 }
 
@@ -116,19 +214,36 @@ impl Mesh {
         let vertex_buffer = ctx.create_vertex_buffer_init_from("Mesh::vertex_buffer", &data.vertex_data);
         let index_buffer = ctx.create_index_buffer_init_from_u32("Mesh::index_buffer", &data.index_data);
 
+        let mut min = point3(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = point3(f32::MIN, f32::MIN, f32::MIN);
+        for vertex in &data.vertex_data {
+            let [x, y, z] = vertex.position;
+            min = point3(min.x.min(x), min.y.min(y), min.z.min(z));
+            max = point3(max.x.max(x), max.y.max(y), max.z.max(z));
+        }
+        if data.vertex_data.is_empty() {
+            min = point3(0.0, 0.0, 0.0);
+            max = point3(0.0, 0.0, 0.0);
+        }
+
         Mesh {
             vertex_buffer,
             index_buffer,
 
             vertex_count: data.vertex_data.len() as u32,
             index_count: data.index_data.len() as u32,
+
+            bounds: (min, max),
         }
     }
 }
 
 impl PBRShader {
     pub fn new(ctx: &RenderCtx, label: &str, pbr_fn: &str) -> Self {
-        let mut whole_shader = include_str!("mesh.wgsl").to_string();
+        // The size of the lights array has to be known when the shader is
+        // compiled, so prepend it as a constant.
+        let mut whole_shader = format!("const MAX_LIGHTS: u32 = {}u;\n", ctx.max_lights);
+        whole_shader.push_str(include_str!("mesh.wgsl"));
         whole_shader.push_str(pbr_fn);
 
         let shader = ctx.device.create_shader_module(wgpu::ShaderModuleDescriptor {
@@ -136,24 +251,15 @@ impl PBRShader {
             source: wgpu::ShaderSource::Wgsl(whole_shader.into()),
         });
 
-        let layout = ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: Some("PBRShader::layout"),
-            bind_group_layouts: &[
-                &ctx.layouts.world,
-                &ctx.layouts.pbr_material,
-                &ctx.layouts.mesh_3d,
-            ],
-            push_constant_ranges: &[],
-        });
-
         let pipeline = ctx.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("PBRShader::pipeline"),
-            layout: Some(&layout),
+            layout: Some(&ctx.layouts.pipeline_world_pbr),
             vertex: wgpu::VertexState {
                 module: &shader,
                 entry_point: Some("vs_main"),
                 buffers: &[
                     Vertex::desc(),
+                    Vertex::instance_desc(),
                 ],
                 compilation_options: wgpu::PipelineCompilationOptions::default()
             },
@@ -204,17 +310,4 @@ impl PBRShader {
         pass.set_pipeline(&self.pipeline);
     }
 
-    pub fn render(&self, ctx: &RenderCtx, pass: &mut wgpu::RenderPass, mesh: &MeshInstance) {
-        pass.set_vertex_buffer(0, mesh.mesh.vertex_buffer.0.slice(..));
-        // No instance buffer for now.
-
-        pass.set_index_buffer(mesh.mesh.index_buffer.0.slice(..), wgpu::IndexFormat::Uint32);
-    
-        // TODO: Sort by material, so we only have to set this bind group once.
-        pass.set_bind_group(1, Some(mesh.material.get_bind_group(ctx).as_ref()), &[]);
-
-        pass.set_bind_group(2, Some(&mesh.instance_bind_group), &[]);
-
-        pass.draw_indexed(0..mesh.mesh.index_count, 0, 0..1);
-    }
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+// Decides the order that a World's meshes are drawn in, and draws them while
+// skipping any state changes that would be redundant.
+
+use cgmath::{Matrix4, Transform};
+
+use crate::video::{mesh_render_pipeline::{InstanceBatch, InstanceBuffer, InstanceData}, world::World, RenderCtx};
+
+/// Counters for a single rendered frame of a Viewport. Mostly useful for tests
+/// and for debugging performance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    pub draw_calls: u32,
+    pub instances: u32,
+    pub pipeline_changes: u32,
+    pub bind_group_changes: u32,
+    pub vertex_buffer_changes: u32,
+    pub shadow_draw_calls: u32,
+}
+
+/// The batches to draw for a single frame. Opaque batches are sorted by shader,
+/// then material, then mesh, then skin. Alpha-blended instances are not batched, and are
+/// instead sorted back-to-front.
+#[derive(Default)]
+pub struct RenderQueue {
+    pub(crate) opaque: Vec<InstanceBatch>,
+    pub(crate) transparent: Vec<InstanceBatch>,
+}
+
+fn addr<T>(value: &T) -> usize {
+    value as *const T as usize
+}
+
+impl RenderQueue {
+    /// Builds the queue for the given World, appending all instance data to
+    /// `data`. `view` is the Camera's view matrix, used for depth sorting.
+    pub fn build(world: &World, view: &Matrix4<f32>, data: &mut Vec<InstanceData>) -> Self {
+        let mut opaque = world.batch_instances(data, |instance| !instance.material.alpha_blended);
+        opaque.sort_by_key(|batch| (
+            addr(batch.material.shader.get_gc_value_ptr()),
+            addr(batch.material.get_gc_value_ptr()),
+            addr(batch.mesh.get_gc_value_ptr()),
+            batch.skin.as_ref().map_or(0, |skin| addr(skin.get_gc_value_ptr()))));
+
+        // Each transparent instance gets its own batch, so that it can be sorted
+        // independently.
+        let mut transparent: Vec<(f32, InstanceBatch)> = world.meshes.borrow().iter()
+            .filter(|instance| instance.material.alpha_blended)
+            .map(|instance| {
+                let origin = instance.transform.get().transform_point(cgmath::point3(0.0, 0.0, 0.0));
+                // The camera looks down -Z, so the furthest instances have the
+                // most negative Z.
+                let depth = view.transform_point(origin).z;
+
+                let batch = InstanceBatch {
+                    mesh: instance.mesh.clone(),
+                    material: instance.material.clone(),
+                    skin: instance.skin.get(),
+                    first: data.len() as u32,
+                    count: 1,
+                };
+                data.push(instance.to_instance_data());
+                (depth, batch)
+            })
+            .collect();
+        transparent.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        RenderQueue {
+            opaque,
+            transparent: transparent.into_iter().map(|(_, batch)| batch).collect(),
+        }
+    }
+
+    /// Every batch in the queue, in the order they are drawn.
+    pub fn batches(&self) -> impl Iterator<Item = &InstanceBatch> {
+        self.opaque.iter().chain(self.transparent.iter())
+    }
+
+    /// Draws every batch in the queue. Bind group 0 must already be set to the
+    /// World bind group.
+    pub fn draw(&self, ctx: &RenderCtx, pass: &mut wgpu::RenderPass, instances: &InstanceBuffer, stats: &mut RenderStats) {
+        let mut state = PassState::default();
+        for batch in self.batches() {
+            state.draw(ctx, pass, instances, batch, stats);
+        }
+    }
+}
+
+/// What is currently bound in a render pass, identified by address.
+#[derive(Default)]
+struct PassState {
+    shader: Option<usize>,
+    material: Option<usize>,
+    mesh: Option<usize>,
+    /// 0 for the default skin.
+    skin: Option<usize>,
+}
+
+impl PassState {
+    fn draw(&mut self, ctx: &RenderCtx, pass: &mut wgpu::RenderPass, instances: &InstanceBuffer, batch: &InstanceBatch, stats: &mut RenderStats) {
+        let shader = &batch.material.shader;
+        let shader_id = addr(shader.get_gc_value_ptr());
+        if self.shader != Some(shader_id) {
+            shader.bind(pass);
+            self.shader = Some(shader_id);
+            stats.pipeline_changes += 1;
+        }
+
+        let material_id = addr(batch.material.get_gc_value_ptr());
+        if self.material != Some(material_id) {
+            pass.set_bind_group(1, Some(batch.material.get_bind_group(ctx).as_ref()), &[]);
+            self.material = Some(material_id);
+            stats.bind_group_changes += 1;
+        }
+
+        let mesh_id = addr(batch.mesh.get_gc_value_ptr());
+        if self.mesh != Some(mesh_id) {
+            pass.set_vertex_buffer(0, batch.mesh.vertex_buffer.0.slice(..));
+            pass.set_index_buffer(batch.mesh.index_buffer.0.slice(..), wgpu::IndexFormat::Uint32);
+            self.mesh = Some(mesh_id);
+            stats.vertex_buffer_changes += 1;
+        }
+
+        let skin_id = batch.skin.as_ref().map_or(0, |skin| addr(skin.get_gc_value_ptr()));
+        if self.skin != Some(skin_id) {
+            pass.set_bind_group(2, Some(batch.skin_bind_group(ctx)), &[]);
+            self.skin = Some(skin_id);
+            stats.bind_group_changes += 1;
+        }
+
+        instances.bind_batch(pass, batch);
+        pass.draw_indexed(0..batch.mesh.index_count, 0, 0..batch.count);
+        stats.draw_calls += 1;
+        stats.instances += batch.count;
+    }
+}
@@ -0,0 +1,44 @@
+// Depth-only pass used to render shadow maps. Only the vertex positions, joints
+// and instance transforms are needed.
+
+// Must match Skin::MAX_JOINTS in animation.rs.
+const MAX_JOINTS: u32 = 64u;
+
+struct Skin {
+    joints: array<mat4x4f, MAX_JOINTS>,
+}
+
+@group(0) @binding(0) var<uniform> light_view_proj: mat4x4f;
+@group(1) @binding(0) var<uniform> skin: Skin;
+
+// Same as skin_matrix in mesh.wgsl.
+fn skin_matrix(joints: vec4u, weights: vec4f) -> mat4x4f {
+    let total = weights.x + weights.y + weights.z + weights.w;
+    if (total <= 0.0) {
+        return mat4x4f(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+    }
+
+    return skin.joints[joints.x] * weights.x
+        + skin.joints[joints.y] * weights.y
+        + skin.joints[joints.z] * weights.z
+        + skin.joints[joints.w] * weights.w;
+}
+
+@vertex
+fn vs_main(
+    @location(0) position: vec3f,
+    @location(4) transform0: vec4f,
+    @location(5) transform1: vec4f,
+    @location(6) transform2: vec4f,
+    @location(7) transform3: vec4f,
+    @location(9) joints: vec4u,
+    @location(10) weights: vec4f,
+) -> @builtin(position) vec4f {
+    let m = mat4x4f(transform0, transform1, transform2, transform3) * skin_matrix(joints, weights);
+    return light_view_proj * m * vec4f(position, 1.0);
+}
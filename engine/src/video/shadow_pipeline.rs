@@ -0,0 +1,90 @@
+use crate::video::{mesh_render_pipeline::{InstanceBatch, InstanceBuffer, Vertex}, texture::DepthTexture, RenderCtx};
+
+/// Renders the depth of every shadow-casting MeshInstance from the point of
+/// view of a light. There is no fragment shader; only the depth is written.
+pub struct ShadowPipeline {
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl ShadowPipeline {
+    /// The width and height of each shadow map.
+    pub const SHADOW_MAP_SIZE: u32 = 2048;
+
+    pub fn new(ctx: &RenderCtx) -> Self {
+        let shader = ctx.device.create_shader_module(wgpu::include_wgsl!("shadow.wgsl"));
+
+        let layout = ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("ShadowPipeline::layout"),
+            bind_group_layouts: &[
+                &ctx.layouts.single_uniform,
+                // Joint matrices
+                &ctx.layouts.single_uniform,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = ctx.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("ShadowPipeline::pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                buffers: &[
+                    Vertex::desc(),
+                    Vertex::instance_desc(),
+                ],
+                compilation_options: wgpu::PipelineCompilationOptions::default()
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                // A lot of our meshes (e.g. the floor tiles) are not closed,
+                // so render both sides.
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: DepthTexture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                // Pushes the depth back a bit to avoid shadow acne.
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false
+            },
+            multiview: None,
+            cache: None
+        });
+
+        ShadowPipeline {
+            pipeline,
+        }
+    }
+
+    pub fn bind(&self, pass: &mut wgpu::RenderPass) {
+        pass.set_pipeline(&self.pipeline);
+    }
+
+    /// ASSUMPTION: The light's view-projection bind group is bound to bind
+    /// group 0.
+    pub fn render(&self, ctx: &RenderCtx, pass: &mut wgpu::RenderPass, instances: &InstanceBuffer, batch: &InstanceBatch) {
+        pass.set_bind_group(1, Some(batch.skin_bind_group(ctx)), &[]);
+        pass.set_vertex_buffer(0, batch.mesh.vertex_buffer.0.slice(..));
+        instances.bind_batch(pass, batch);
+        pass.set_index_buffer(batch.mesh.index_buffer.0.slice(..), wgpu::IndexFormat::Uint32);
+
+        pass.draw_indexed(0..batch.mesh.index_count, 0, 0..batch.count);
+    }
+}
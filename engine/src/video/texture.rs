@@ -38,6 +38,39 @@ impl DepthTexture {
     }
 }
 
+/// A color texture that can be rendered into and then copied back to the CPU.
+/// Used by Viewports that don't render to a window.
+pub struct ColorTarget {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+}
+
+impl ColorTarget {
+    pub fn new(ctx: &RenderCtx, dimensions: (u32, u32), format: wgpu::TextureFormat) -> Self {
+        let size = wgpu::Extent3d {
+            width: dimensions.0.max(1),
+            height: dimensions.1.max(1),
+            depth_or_array_layers: 1
+        };
+
+        let texture = ctx.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("ColorTarget"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[]
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self { texture, view }
+    }
+}
+
 #[derive(Clone)]
 pub struct Texture {
     pub texture: wgpu::Texture,
@@ -1,67 +1,154 @@
-use std::cell::{Cell, RefCell};
+use std::{cell::{Cell, Ref, RefCell}, collections::HashMap};
 
 use bytemuck::Zeroable;
-
-use crate::{gc::Gp, video::{camera::Camera, hdr_tonemap::{HdrTonemapPipeline, Tonemap}, mesh_render_pipeline::MeshInstance, texture::{DepthTexture, Texture}, RenderCtx, Renderer, UniformBuffer}};
+use cgmath::{point3, vec3, EuclideanSpace, InnerSpace, SquareMatrix, Transform, Vector2};
+
+use crate::{gc::Gp, video::{camera::{Camera, OPENGL_TO_WGPU_MATRIX}, hdr_tonemap::{HdrTonemapPipeline, Tonemap}, mesh_render_pipeline::{InstanceBatch, InstanceBuffer, InstanceData, MeshInstance}, render_queue::{RenderQueue, RenderStats}, shadow_pipeline::ShadowPipeline, texture::{ColorTarget, DepthTexture, Texture}, RenderCtx, Renderer, UniformBuffer}};
+
+#[derive(Clone, Copy, Debug)]
+pub enum LightKind {
+    /// Lights everything from the same direction, e.g. the sun. Ignores the
+    /// position of the light.
+    Directional,
+    /// Radiates in all directions from the position of the light, falling off
+    /// to nothing at the given range.
+    Point {
+        range: f32,
+    },
+    /// Like a Point light, but only lights a cone around its direction. The
+    /// light fades out between the inner and outer angles.
+    Spot {
+        range: f32,
+        inner_angle: cgmath::Rad<f32>,
+        outer_angle: cgmath::Rad<f32>,
+    },
+}
 
 pub struct Light3D {
+    pub kind: Cell<LightKind>,
+    /// World-space position. Unused by directional lights.
+    pub position: Cell<cgmath::Point3<f32>>,
+    /// World-space direction. Unused by point lights.
     pub direction: Cell<cgmath::Vector3<f32>>,
     pub color: Cell<cgmath::Vector3<f32>>,
 }
 
+// Must match the LIGHT_* constants in mesh.wgsl.
+const LIGHT_KIND_DIRECTIONAL: u32 = 0;
+const LIGHT_KIND_POINT: u32 = 1;
+const LIGHT_KIND_SPOT: u32 = 2;
+
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Light3DUniform {
+    position: [f32; 3],
+    kind: u32,
     direction: [f32; 3],
-    _pad: u32,
+    range: f32,
     color: [f32; 3],
-    _pad2: u32
+    cos_inner: f32,
+    cos_outer: f32,
+    _pad: [u32; 3],
+}
+
+/// Comes right before the array of Light3DUniforms in the lights buffer.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightsHeaderUniform {
+    count: u32,
+    /// Index of the light that uses the shadow map, or u32::MAX if none does.
+    shadow_light: u32,
+    _pad: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ShadowUniform {
+    /// Goes from eye-space to the shadow map's clip space.
+    eye_to_shadow: [[f32; 4]; 4],
+    texel_size: f32,
+    _pad: [u32; 3],
 }
 
 impl Light3D {
-    pub fn to_uniform(&self) -> Light3DUniform {
-        Light3DUniform {
-            direction: self.direction.get().into(),
-            _pad: 0,
-            color: self.color.get().into(),
-            _pad2: 0,
+    pub fn directional(direction: cgmath::Vector3<f32>, color: cgmath::Vector3<f32>) -> Self {
+        Light3D {
+            kind: Cell::new(LightKind::Directional),
+            position: Cell::new(cgmath::point3(0.0, 0.0, 0.0)),
+            direction: Cell::new(direction),
+            color: Cell::new(color),
+        }
+    }
+
+    pub fn point(position: cgmath::Point3<f32>, color: cgmath::Vector3<f32>, range: f32) -> Self {
+        Light3D {
+            kind: Cell::new(LightKind::Point { range }),
+            position: Cell::new(position),
+            direction: Cell::new(cgmath::vec3(0.0, -1.0, 0.0)),
+            color: Cell::new(color),
         }
     }
-    // pub fn to_uniform(&self) -> [u8; 8] {
-        
 
-    //     let uni = AsUniform {
-    //         direction: self.direction.into(),
-    //         _pad: 0,
-    //         color: self.color.into(),
-    //         _pad2: 0,
-    //     };
+    pub fn spot(position: cgmath::Point3<f32>, direction: cgmath::Vector3<f32>, color: cgmath::Vector3<f32>,
+        range: f32, inner_angle: cgmath::Rad<f32>, outer_angle: cgmath::Rad<f32>) -> Self {
+        Light3D {
+            kind: Cell::new(LightKind::Spot { range, inner_angle, outer_angle }),
+            position: Cell::new(position),
+            direction: Cell::new(direction),
+            color: Cell::new(color),
+        }
+    }
+
+    /// Converts the light to eye-space based on the given view matrix.
+    pub fn to_uniform(&self, view_mat: &cgmath::Matrix4<f32>) -> Light3DUniform {
+        let position = view_mat * self.position.get().to_homogeneous();
+        let direction = view_mat * self.direction.get().extend(0.0);
+
+        let (kind, range, cos_inner, cos_outer) = match self.kind.get() {
+            LightKind::Directional => (LIGHT_KIND_DIRECTIONAL, 0.0, 0.0, 0.0),
+            LightKind::Point { range } => (LIGHT_KIND_POINT, range, 0.0, 0.0),
+            LightKind::Spot { range, inner_angle, outer_angle } =>
+                (LIGHT_KIND_SPOT, range, inner_angle.0.cos(), outer_angle.0.cos()),
+        };
 
-    //     bytemuck::cast(uni)
-    // }
+        Light3DUniform {
+            position: position.truncate().into(),
+            kind,
+            direction: direction.truncate().into(),
+            range,
+            color: self.color.get().into(),
+            cos_inner,
+            cos_outer,
+            _pad: [0; 3],
+        }
+    }
 }
 
 /// A renderable world. Contains some number of objects that can be rendered.
 pub struct World {
-    envmap: Gp<Texture>,
+    pub(crate) envmap: Gp<Texture>,
 
-    pub lights: [Light3D; 1],
+    /// The default directional light. It is part of the light list like any
+    /// other light, so it can be removed with remove_light.
+    pub sun: Gp<Light3D>,
 
-    meshes: RefCell<Vec<Gp<MeshInstance>>>,
+    pub(crate) lights: RefCell<Vec<Gp<Light3D>>>,
+
+    pub(crate) meshes: RefCell<Vec<Gp<MeshInstance>>>,
 }
 
 impl World {
     pub fn new(ctx: &RenderCtx) -> Self {
         let envmap = Gp::new(Texture::dummy(ctx, Some("World::envmap (null)")));
 
-        let lights = [Light3D {
-            color: Cell::new(cgmath::vec3(0.0, 0.0, 0.0)),
-            direction: Cell::new(cgmath::vec3(2.0, -10.0, -10.0)),
-        }];
+        let sun = Gp::new(Light3D::directional(
+            cgmath::vec3(2.0, -10.0, -10.0),
+            cgmath::vec3(0.0, 0.0, 0.0)));
 
         Self {
             envmap,
-            lights,
+            sun: sun.clone(),
+            lights: RefCell::new(vec![sun]),
 
             meshes: RefCell::new(Vec::new()),
         }
@@ -77,27 +164,138 @@ impl World {
         meshes.clear();
     }
 
+    pub fn add_light(&self, light: Gp<Light3D>) {
+        let mut lights = self.lights.borrow_mut();
+        lights.push(light);
+    }
+
+    /// Removes the given light, if it is in the World.
+    pub fn remove_light(&self, light: &Gp<Light3D>) {
+        let mut lights = self.lights.borrow_mut();
+        lights.retain(|other| !other.has_same_id(light));
+    }
+
+    /// Removes all lights other than the sun.
+    pub fn clear_lights(&self) {
+        let mut lights = self.lights.borrow_mut();
+        lights.retain(|light| light.has_same_id(&self.sun));
+    }
+
+    pub fn light_count(&self) -> usize {
+        self.lights.borrow().len()
+    }
+
     pub fn set_envmap(&self, texture: &Gp<Texture>) {
         self.envmap.set(texture);
     }
 
-    /// Computes light uniform data based on the given Camera.
-    pub fn lights_to_uniform(&self, camera: &Camera) -> [Light3DUniform; 1] {
-        let mut data = core::array::from_fn::<_, 1, _>(|_| Light3DUniform::zeroed());
-
+    /// Computes light uniform data based on the given Camera. Lights past the
+    /// first max_lights are not rendered.
+    pub fn lights_to_uniform(&self, camera: &Camera, max_lights: usize) -> (LightsHeaderUniform, Vec<Light3DUniform>) {
         let view_mat = camera.get_view_matrix();
 
-        for (idx, light) in self.lights.iter().enumerate() {
-            let direction = view_mat * light.direction.get().extend(0.0);
-            data[idx] = Light3DUniform {
-                direction: direction.truncate().into(),
-                _pad: 0,
-                color: light.color.get().into(),
-                _pad2: 0
+        let lights = self.lights.borrow();
+        let data: Vec<_> = lights.iter()
+            .take(max_lights)
+            .map(|light| light.to_uniform(&view_mat))
+            .collect();
+
+        // Only the sun casts shadows for now.
+        let shadow_light = lights.iter()
+            .take(max_lights)
+            .position(|light| light.has_same_id(&self.sun))
+            .map(|idx| idx as u32)
+            .unwrap_or(u32::MAX);
+
+        let header = LightsHeaderUniform {
+            count: data.len() as u32,
+            shadow_light,
+            _pad: [0; 2],
+        };
+
+        (header, data)
+    }
+
+    /// Groups the World's meshes by their Mesh and PBRMaterial, so that each
+    /// group can be drawn with a single instanced draw call. The instance data
+    /// for every batch is appended to `data`, in batch order.
+    pub fn batch_instances(&self, data: &mut Vec<InstanceData>, filter: impl Fn(&MeshInstance) -> bool) -> Vec<InstanceBatch> {
+        let meshes = self.meshes.borrow();
+
+        // Batches are kept in the order their first instance appears.
+        let mut groups: Vec<(&Gp<MeshInstance>, Vec<InstanceData>)> = Vec::new();
+        let mut group_idx: HashMap<(usize, usize, usize), usize> = HashMap::new();
+
+        for instance in meshes.iter().filter(|instance| filter(instance)) {
+            let key = (
+                instance.mesh.get_gc_value_ptr() as *const _ as usize,
+                instance.material.get_gc_value_ptr() as *const _ as usize,
+                instance.skin.get().map_or(0, |skin| skin.get_gc_value_ptr() as *const _ as usize));
+            let idx = *group_idx.entry(key).or_insert_with(|| {
+                groups.push((instance, Vec::new()));
+                groups.len() - 1
+            });
+            groups[idx].1.push(instance.to_instance_data());
+        }
+
+        groups.into_iter().map(|(first_instance, instances)| {
+            let batch = InstanceBatch {
+                mesh: first_instance.mesh.clone(),
+                material: first_instance.material.clone(),
+                skin: first_instance.skin.get(),
+                first: data.len() as u32,
+                count: instances.len() as u32,
             };
+            data.extend_from_slice(&instances);
+            batch
+        }).collect()
+    }
+
+    /// Computes the view-projection matrix for the sun's shadow map, fit
+    /// around the bounds of every mesh that casts a shadow. Returns None if
+    /// there is nothing to cast a shadow, or if the sun isn't directional.
+    pub fn shadow_view_proj(&self) -> Option<cgmath::Matrix4<f32>> {
+        if !matches!(self.sun.kind.get(), LightKind::Directional) {
+            return None;
+        }
+
+        let mut min = point3(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = point3(f32::MIN, f32::MIN, f32::MIN);
+        let mut any_casters = false;
+
+        for instance in self.meshes.borrow().iter() {
+            if !instance.casts_shadow.get() { continue; }
+            any_casters = true;
+
+            let transform = instance.transform.get();
+            let (lo, hi) = instance.mesh.bounds;
+            for corner in 0..8 {
+                let p = point3(
+                    if corner & 1 == 0 { lo.x } else { hi.x },
+                    if corner & 2 == 0 { lo.y } else { hi.y },
+                    if corner & 4 == 0 { lo.z } else { hi.z });
+                let p = transform.transform_point(p);
+                min = point3(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+                max = point3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+            }
         }
 
-        data
+        if !any_casters {
+            return None;
+        }
+
+        let center = min.midpoint(max);
+        let radius = ((max - min).magnitude() * 0.5).max(0.01);
+
+        let direction = self.sun.direction.get().normalize();
+        let up = if direction.y.abs() > 0.99 { vec3(0.0, 0.0, 1.0) } else { vec3(0.0, 1.0, 0.0) };
+
+        // Put the light outside of the bounding sphere, looking at its center.
+        let eye = center - direction * (radius * 2.0);
+        let view = cgmath::Matrix4::look_at_rh(eye, center, up);
+        let proj = cgmath::ortho(-radius, radius, -radius, radius, radius, radius * 3.0);
+
+        Some(OPENGL_TO_WGPU_MATRIX * proj * view)
     }
 }
 
@@ -122,6 +320,38 @@ impl ViewportUniform {
     }
 }
 
+/// The part of its target that a Viewport covers, as fractions of the
+/// target's size, with (0, 0) at the top left. Used for split-screen and
+/// picture-in-picture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewportRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ViewportRect {
+    /// Covers the whole target.
+    pub const FULL: ViewportRect = ViewportRect { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        ViewportRect { x, y, width, height }
+    }
+
+    /// Returns the rect in pixels, as (x, y, width, height), for a target of
+    /// the given size. Always at least one pixel, and always inside the
+    /// target.
+    pub fn to_pixels(self, target: (u32, u32)) -> (u32, u32, u32, u32) {
+        let (target_width, target_height) = (target.0.max(1), target.1.max(1));
+        let x = ((self.x.clamp(0.0, 1.0) * target_width as f32).round() as u32).min(target_width - 1);
+        let y = ((self.y.clamp(0.0, 1.0) * target_height as f32).round() as u32).min(target_height - 1);
+        let width = ((self.width * target_width as f32).round() as u32).clamp(1, target_width - x);
+        let height = ((self.height * target_height as f32).round() as u32).clamp(1, target_height - y);
+        (x, y, width, height)
+    }
+}
+
 /// A Viewport is a single, actual surface to be rendered to. It contains
 /// its own depth buffer, output buffer, and dimensions.
 /// 
@@ -146,6 +376,33 @@ pub struct Viewport {
     // be in eye-space.
     pub lights_buffer: UniformBuffer,
 
+    /// The sun's shadow map. It is per-viewport as the shadow uniform goes
+    /// from eye-space to the shadow map.
+    pub shadow_map: DepthTexture,
+    pub shadow_buffer: UniformBuffer,
+    /// The light's view-projection matrix, used while rendering the shadow map.
+    pub shadow_pass_buffer: UniformBuffer,
+    pub shadow_pass_bind_group: wgpu::BindGroup,
+    /// Whether the shadow map should be rendered this frame. Set by update().
+    has_shadow: Cell<bool>,
+
+    /// Per-instance data for this frame, for both the main pass and the shadow
+    /// pass. Re-built by update().
+    pub instance_buffer: InstanceBuffer,
+    pub(crate) queue: RefCell<RenderQueue>,
+    pub(crate) shadow_batches: RefCell<Vec<InstanceBatch>>,
+
+    /// Counters from the last call to render().
+    stats: Cell<RenderStats>,
+
+    /// Where the Viewport is drawn within its target.
+    rect: ViewportRect,
+    /// The size of the whole target, i.e. the window's surface or the color
+    /// target.
+    target_size: (u32, u32),
+    /// The top left corner of the Viewport within its target, in pixels.
+    pub x: u32,
+    pub y: u32,
     pub width: u32,
     pub height: u32,
 
@@ -154,10 +411,17 @@ pub struct Viewport {
     /// probably we will want the Viewport to simply own its own texture, and
     /// then have the tonemap pipeline stored some other way?
     pub hdr: HdrTonemapPipeline,
+
+    /// The format that the tonemapped output is written in.
+    pub output_format: wgpu::TextureFormat,
+    /// Offscreen Viewports own the texture that they render to. Viewports
+    /// belonging to a window render to the window's surface instead.
+    pub color_target: Option<ColorTarget>,
 }
 
 impl Viewport {
-    fn build_bind_group(ctx: &RenderCtx, viewport_buffer: &UniformBuffer, lights_buffer: &UniformBuffer, envmap: &Texture) -> wgpu::BindGroup {
+    fn build_bind_group(ctx: &RenderCtx, viewport_buffer: &UniformBuffer, lights_buffer: &UniformBuffer, envmap: &Texture,
+        shadow_buffer: &UniformBuffer, shadow_map: &DepthTexture) -> wgpu::BindGroup {
         ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Viewport::bind_group"),
             layout: &ctx.layouts.world,
@@ -177,31 +441,60 @@ impl Viewport {
                 wgpu::BindGroupEntry {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&ctx.samplers.linear_clamp)
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: shadow_buffer.0.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&shadow_map.view)
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Sampler(&ctx.samplers.depth_texture_sampler)
                 }
             ],
         })
     }
 
-    pub fn new(ctx: &RenderCtx, world: Gp<World>, camera: Gp<Camera>, dimensions: (u32, u32), config: &wgpu::SurfaceConfiguration, default_tonemap: Tonemap) -> Self {
+    pub fn new(ctx: &RenderCtx, world: Gp<World>, camera: Gp<Camera>, dimensions: (u32, u32), output_format: wgpu::TextureFormat, default_tonemap: Tonemap) -> Self {
         let viewport_init = ViewportUniform::identity();
 
         let last_envmap = world.envmap.clone();
-        //let lights_init = Light3DUniform::zeroed();
 
-        let lights_init = [Light3DUniform {
-            color: [8.0, 8.0, 8.0], _pad: 0,
-            direction: [2.0, -10.0, -10.0], _pad2: 0,
-        }];
+        // The lights buffer is the header followed by room for max_lights
+        // lights. It starts out with a count of zero.
+        let mut lights_init = bytemuck::bytes_of(&LightsHeaderUniform::zeroed()).to_vec();
+        for _ in 0..ctx.max_lights {
+            lights_init.extend_from_slice(bytemuck::bytes_of(&Light3DUniform::zeroed()));
+        }
 
         let viewport_buffer = ctx.create_uniform_buffer_init_from("Viewport::viewport_buffer",
             &[viewport_init]);
-        let lights_buffer = ctx.create_uniform_buffer_init_from("Viewport::lights_buffer",
-            &[lights_init]);
+        let lights_buffer = ctx.create_uniform_buffer_init("Viewport::lights_buffer",
+            &lights_init);
+
+        let shadow_map = DepthTexture::new(ctx, (ShadowPipeline::SHADOW_MAP_SIZE, ShadowPipeline::SHADOW_MAP_SIZE));
+        let shadow_buffer = ctx.create_uniform_buffer_init_zero::<ShadowUniform>("Viewport::shadow_buffer");
+        let identity: [[f32; 4]; 4] = cgmath::Matrix4::identity().into();
+        let shadow_pass_buffer = ctx.create_uniform_buffer_init_from("Viewport::shadow_pass_buffer", &[identity]);
+        let shadow_pass_bind_group = ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Viewport::shadow_pass_bind_group"),
+            layout: &ctx.layouts.single_uniform,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: shadow_pass_buffer.0.as_entire_binding(),
+                }
+            ],
+        });
 
-        let bind_group = Self::build_bind_group(ctx, &viewport_buffer, &lights_buffer, &last_envmap);
+        let bind_group = Self::build_bind_group(ctx, &viewport_buffer, &lights_buffer, &last_envmap,
+            &shadow_buffer, &shadow_map);
 
         let depth_texture = DepthTexture::new(ctx, dimensions);
-        let hdr = HdrTonemapPipeline::new(dimensions.0, dimensions.1, ctx, config, default_tonemap);
+        let hdr = HdrTonemapPipeline::new(dimensions.0, dimensions.1, ctx, output_format, default_tonemap);
 
         Viewport {
             world,
@@ -214,14 +507,44 @@ impl Viewport {
             viewport_buffer,
             lights_buffer,
 
+            shadow_map,
+            shadow_buffer,
+            shadow_pass_buffer,
+            shadow_pass_bind_group,
+            has_shadow: Cell::new(false),
+
+            instance_buffer: InstanceBuffer::new(ctx),
+            queue: RefCell::new(RenderQueue::default()),
+            shadow_batches: RefCell::new(Vec::new()),
+
+            stats: Cell::new(RenderStats::default()),
+
+            rect: ViewportRect::FULL,
+            target_size: dimensions,
+            x: 0,
+            y: 0,
             width: dimensions.0,
             height: dimensions.1,
 
             depth_texture,
-            hdr
+            hdr,
+
+            output_format,
+            color_target: None,
         }
     }
 
+    /// The format used for the color target of offscreen Viewports.
+    pub const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    /// Creates a Viewport that renders into its own color target rather than
+    /// a window. The result can be read back with RenderCtx::read_viewport.
+    pub fn new_offscreen(ctx: &RenderCtx, world: Gp<World>, camera: Gp<Camera>, dimensions: (u32, u32), default_tonemap: Tonemap) -> Self {
+        let mut viewport = Self::new(ctx, world, camera, dimensions, Self::OFFSCREEN_FORMAT, default_tonemap);
+        viewport.color_target = Some(ColorTarget::new(ctx, dimensions, Self::OFFSCREEN_FORMAT));
+        viewport
+    }
+
     pub fn update(&self, ctx: &RenderCtx) {
         // The viewport must write to:
         // - The ViewportUniform
@@ -232,29 +555,173 @@ impl Viewport {
         let data = self.camera.to_viewport_uniform(self);
         ctx.queue.write_buffer(&self.viewport_buffer.0, 0, bytemuck::cast_slice(&[data]));
 
-        let lights = self.world.lights_to_uniform(&self.camera);
-        ctx.queue.write_buffer(&self.lights_buffer.0, 0, bytemuck::cast_slice(&lights));
+        let (mut header, lights) = self.world.lights_to_uniform(&self.camera, ctx.max_lights);
+
+        let shadow_view_proj = self.world.shadow_view_proj();
+        self.has_shadow.set(shadow_view_proj.is_some());
+        match shadow_view_proj {
+            Some(view_proj) => {
+                let inv_view = self.camera.get_view_matrix().invert().unwrap_or(cgmath::Matrix4::identity());
+                let shadow = ShadowUniform {
+                    eye_to_shadow: (view_proj * inv_view).into(),
+                    texel_size: 1.0 / ShadowPipeline::SHADOW_MAP_SIZE as f32,
+                    _pad: [0; 3],
+                };
+                ctx.queue.write_buffer(&self.shadow_buffer.0, 0, bytemuck::bytes_of(&shadow));
+
+                let view_proj: [[f32; 4]; 4] = view_proj.into();
+                ctx.queue.write_buffer(&self.shadow_pass_buffer.0, 0, bytemuck::bytes_of(&view_proj));
+            },
+            None => header.shadow_light = u32::MAX,
+        }
+
+        ctx.queue.write_buffer(&self.lights_buffer.0, 0, bytemuck::bytes_of(&header));
+        if !lights.is_empty() {
+            ctx.queue.write_buffer(&self.lights_buffer.0,
+                std::mem::size_of::<LightsHeaderUniform>() as wgpu::BufferAddress,
+                bytemuck::cast_slice(&lights));
+        }
     
+        let mut instances = Vec::new();
+        *self.queue.borrow_mut() = RenderQueue::build(&self.world, &self.camera.get_view_matrix(), &mut instances);
+        *self.shadow_batches.borrow_mut() = if self.has_shadow.get() {
+            self.world.batch_instances(&mut instances, |instance| instance.casts_shadow.get())
+        }
+        else {
+            Vec::new()
+        };
+        self.instance_buffer.write(ctx, &instances);
+
         if !self.world.envmap.has_same_id(&self.last_envmap) {
             self.last_envmap.set(&self.world.envmap);
             let mut bind_group = self.bind_group.borrow_mut();
-            *bind_group = Self::build_bind_group(ctx, &self.viewport_buffer, &self.lights_buffer, &self.last_envmap);
+            *bind_group = Self::build_bind_group(ctx, &self.viewport_buffer, &self.lights_buffer, &self.last_envmap,
+                &self.shadow_buffer, &self.shadow_map);
         }
     }
 
+    /// Resizes the target that the Viewport is drawn into, e.g. when the
+    /// window is resized. The Viewport itself covers its rect of the target.
     pub fn resize(&mut self, ctx: &RenderCtx, width: u32, height: u32) {
+        self.target_size = (width, height);
+        let (x, y, width, height) = self.rect.to_pixels(self.target_size);
+
         self.depth_texture = DepthTexture::new(ctx, (width, height));
 
         self.hdr.resize(width, height, ctx);
 
+        if self.color_target.is_some() {
+            self.color_target = Some(ColorTarget::new(ctx, self.target_size, self.output_format));
+        }
+
+        self.x = x;
+        self.y = y;
         self.width  = width;
         self.height = height;
     }
 
+    pub fn rect(&self) -> ViewportRect {
+        self.rect
+    }
+
+    /// Moves the Viewport to a different part of its target.
+    pub fn set_rect(&mut self, ctx: &RenderCtx, rect: ViewportRect) {
+        self.rect = rect;
+        let (width, height) = self.target_size;
+        self.resize(ctx, width, height);
+    }
+
+    pub fn target_size(&self) -> (u32, u32) {
+        self.target_size
+    }
+
+    /// Whether a position in the target, e.g. the cursor, is inside the
+    /// Viewport.
+    pub fn contains(&self, position: Vector2<f32>) -> bool {
+        position.x >= self.x as f32 && position.x < (self.x + self.width) as f32
+            && position.y >= self.y as f32 && position.y < (self.y + self.height) as f32
+    }
+
+    /// Changes how the Viewport's HDR output is mapped to the screen.
+    pub fn set_tonemap(&mut self, ctx: &RenderCtx, tonemap: Tonemap) {
+        self.hdr = HdrTonemapPipeline::new(self.width, self.height, ctx, self.output_format, tonemap);
+    }
+
+    /// Renders an offscreen Viewport into its own color target, and submits
+    /// the work immediately.
+    pub fn render_offscreen(&self, renderer: &Renderer) {
+        let Some(target) = &self.color_target else {
+            log::warn!("Viewport::render_offscreen: viewport has no color target");
+            return;
+        };
+
+        let mut encoder = renderer.ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Viewport::render_offscreen")
+        });
+
+        // The tonemap pass loads rather than clears, so clear the target first.
+        {
+            let _clear_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("offscreen_clear_pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                    depth_slice: None,
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None
+            });
+        }
+
+        self.render(renderer, &mut encoder, &target.view);
+
+        renderer.ctx.queue.submit(std::iter::once(encoder.finish()));
+    }
+
     // TODO: Probably the Viewport itself should contain either a Surface
     // or a TextureView, or maybe an option of either, depending on its usage.
 
+    /// Renders the sun's shadow map. Must come after update(), which computes
+    /// the light matrix.
+    fn render_shadow_map(&self, renderer: &Renderer, encoder: &mut wgpu::CommandEncoder, stats: &mut RenderStats) {
+        if !self.has_shadow.get() {
+            return;
+        }
+
+        let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("shadow_pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.shadow_map.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+
+        shadow_pass.set_bind_group(0, Some(&self.shadow_pass_bind_group), &[]);
+        renderer.shadow.bind(&mut shadow_pass);
+        for batch in self.shadow_batches.borrow().iter() {
+            renderer.shadow.render(&renderer.ctx, &mut shadow_pass, &self.instance_buffer, batch);
+            stats.shadow_draw_calls += 1;
+        }
+    }
+
     pub fn render(&self, renderer: &Renderer, encoder: &mut wgpu::CommandEncoder, output_view: &wgpu::TextureView) {
+        let mut stats = RenderStats::default();
+
+        self.update(&renderer.ctx);
+        self.render_shadow_map(renderer, encoder, &mut stats);
+
         {
             let mut world_render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("world_render_pass"),
@@ -285,20 +752,13 @@ impl Viewport {
             });
 
             // For the viewport, we must set the bind group 0 to our own bind group.
-            self.update(&renderer.ctx);
             let group = self.bind_group.borrow();
             world_render_pass.set_bind_group(0, Some(&*group), &[]);
             
             renderer.sky.render(&mut world_render_pass);
 
            // renderer.mesh_renderer.bind(&mut world_render_pass);
-            for mesh in self.world.meshes.borrow().iter() {
-                let shader = &mesh.material.shader;
-                // TODO: Reduce number of calls to bind(), either by sorting, or
-                // maybe by an extra check for which shader is already bound?
-                shader.bind(&mut world_render_pass);
-                shader.render(&renderer.ctx, &mut world_render_pass, &mesh);
-            }
+            self.queue.borrow().draw(&renderer.ctx, &mut world_render_pass, &self.instance_buffer, &mut stats);
             //renderer.mesh_renderer.render(&mut world_render_pass, &renderer.ctx.queue);
         }
 
@@ -319,7 +779,24 @@ impl Viewport {
                 timestamp_writes: None
             });
 
+            // Only draw over our own part of the target.
+            hdr_tonemap_pass.set_viewport(self.x as f32, self.y as f32,
+                self.width as f32, self.height as f32, 0.0, 1.0);
             self.hdr.render(&mut hdr_tonemap_pass);
         }
+
+        self.stats.set(stats);
+    }
+
+    /// Returns the draw call and state change counters from the last time this
+    /// Viewport was rendered.
+    pub fn last_frame_stats(&self) -> RenderStats {
+        self.stats.get()
+    }
+
+    /// The batches that were drawn the last time this Viewport was rendered,
+    /// in the order they were drawn.
+    pub fn last_frame_queue(&self) -> Ref<'_, RenderQueue> {
+        self.queue.borrow()
     }
 }
\ No newline at end of file
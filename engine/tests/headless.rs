@@ -0,0 +1,134 @@
+// Runs a minimal Gameplay without any window, the way CI does.
+
+use engine::{
+    cgmath::{point3, vec3, Matrix4},
+    gc::Gp,
+    headless::Headless,
+    input::replay::InputLog,
+    video::{
+        asset_import::MeshData,
+        hdr_tonemap::Tonemap,
+        mesh_render_pipeline::{Mesh, MeshInstance, Vertex},
+        PBRMaterial,
+    },
+    Engine, Gameplay,
+};
+
+struct Counter {
+    ticks: usize,
+    replay_start: Option<String>,
+}
+
+impl Gameplay for Counter {
+    const GAME_TITLE: &'static str = "headless test";
+    const DEFAULT_TONEMAP: Tonemap = Tonemap::None;
+
+    fn new(_engine: &mut Engine) -> Self {
+        Counter { ticks: 0, replay_start: None }
+    }
+
+    fn tick(&mut self, _engine: &mut Engine) {
+        self.ticks += 1;
+    }
+
+    fn replay_start(&mut self, _engine: &mut Engine, start: &str) {
+        self.replay_start = Some(start.to_string());
+    }
+}
+
+/// The headless engine falls back to a software or no-op adapter, so this
+/// should always succeed.
+fn headless(dimensions: (u32, u32)) -> Headless<Counter> {
+    Headless::new(dimensions).expect("headless engine")
+}
+
+#[test]
+fn steps_tick_the_gameplay() {
+    let mut headless = headless((64, 48));
+
+    headless.step_n(10);
+    assert_eq!(headless.gameplay.ticks, 10);
+
+    headless.step_for(web_time::Duration::from_millis(500));
+    assert_eq!(headless.gameplay.ticks, 10 + Engine::TICKS_PER_SECOND as usize / 2);
+}
+
+#[test]
+fn renders_the_main_viewport() {
+    let mut headless = headless((64, 48));
+
+    headless.step_n(3);
+    let image = headless.render().expect("headless render failed");
+    assert_eq!(image.dimensions(), (64, 48));
+}
+
+#[test]
+fn replays_from_the_recorded_start() {
+    let mut headless = headless((64, 48));
+
+    let log = InputLog::from_text("ticks 5\nstart level 3\n2 key:CapsLock down\n").unwrap();
+    headless.replay(log);
+    assert_eq!(headless.gameplay.replay_start.as_deref(), Some("level 3"));
+    assert_eq!(headless.gameplay.ticks, 5);
+}
+
+#[test]
+fn batches_shared_meshes_and_sorts_transparent_instances() {
+    let headless = headless((64, 48));
+    let engine = &headless.engine;
+    let ctx = engine.render_ctx();
+
+    let vertex = |x, y| Vertex {
+        position: [x, y, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        uv: [0.0, 0.0],
+        uv2: [0.0, 0.0],
+        joints: [0; 4],
+        weights: [0.0; 4],
+    };
+    let mesh = Gp::new(Mesh::new(ctx, &MeshData {
+        vertex_data: vec![vertex(-0.5, -0.5), vertex(0.5, -0.5), vertex(0.0, 0.5)],
+        index_data: vec![0, 1, 2],
+    }));
+
+    let opaque = Gp::new(PBRMaterial::default(ctx));
+    for x in [-1.5, -0.5, 0.5, 1.5] {
+        let transform = Matrix4::from_translation(vec3(x, 0.0, 0.0));
+        engine.main_world.push_mesh(Gp::new(MeshInstance::new(mesh.clone(), opaque.clone(), transform)));
+    }
+
+    // Pushed out of order, the camera looks down -z so the instance at z = -2
+    // is the furthest away.
+    let transparent: Vec<Gp<PBRMaterial>> = [0.0, 2.0, -2.0].into_iter().map(|z| {
+        let mut material = PBRMaterial::default(ctx);
+        material.alpha_blended = true;
+        let material = Gp::new(material);
+        let transform = Matrix4::from_translation(vec3(0.0, 0.0, z));
+        engine.main_world.push_mesh(Gp::new(MeshInstance::new(mesh.clone(), material.clone(), transform)));
+        material
+    }).collect();
+
+    engine.main_camera.position.set(point3(0.0, 0.0, 5.0));
+    engine.main_camera.target.set(point3(0.0, 0.0, 0.0));
+
+    headless.render().expect("headless render failed");
+
+    let viewport = headless.engine.get_viewport();
+    let stats = viewport.last_frame_stats();
+    // One instanced draw for the opaque instances, one each for the transparent ones.
+    assert_eq!(stats.draw_calls, 4);
+    assert_eq!(stats.instances, 7);
+    // Everything shares a shader, a mesh and the default skin, so those are
+    // only bound once. Each material is bound once, plus the skin.
+    assert_eq!(stats.pipeline_changes, 1);
+    assert_eq!(stats.vertex_buffer_changes, 1);
+    assert_eq!(stats.bind_group_changes, 5);
+
+    let queue = viewport.last_frame_queue();
+    let transparent_batches: Vec<_> = queue.batches().filter(|batch| batch.material().alpha_blended).collect();
+    assert_eq!(transparent_batches.len(), 3);
+    for (batch, expected) in transparent_batches.iter().zip([2, 0, 1]) {
+        assert_eq!(batch.count(), 1);
+        assert!(batch.material().has_same_id(&transparent[expected]));
+    }
+}
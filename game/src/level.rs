@@ -2,11 +2,13 @@ use std::cell::Cell;
 
 use grid::Grid;
 use engine::gc;
+use engine::gc::{Trace, Tracer};
 // /
 use engine::cgmath::{point3, vec3, AbsDiffEq, InnerSpace, Matrix4, Vector3};
 use engine::cgmath;
 use engine::log;
 
+use engine::tween::{Easing, Tween};
 use engine::video::camera::CameraProjection;
 use engine::{gc::Gp, video::{mesh_render_pipeline::{Mesh, MeshInstance}, PBRMaterial}, Engine};
 use tiled::{Loader, PropertyValue};
@@ -63,7 +65,7 @@ impl DeviceTy {
         }
     }
 
-    pub fn mk_mesh_instance(&self, engine: &Engine, assets: &Assets, locked: bool, transform: cgmath::Matrix4<f32>) -> Gp<MeshInstance> {
+    pub fn mk_mesh_instance(&self, assets: &Assets, locked: bool, transform: cgmath::Matrix4<f32>) -> Gp<MeshInstance> {
         let (mesh, mat) = match self {
             DeviceTy::Mix => (&assets.node_mix, &assets.node_mix_mat),
             DeviceTy::Emitter(_) => (&assets.emitter, &assets.emitter_mat),
@@ -78,7 +80,7 @@ impl DeviceTy {
             DeviceTy::Split => (&assets.node_split, &assets.node_split_mat),
         };
 
-        assets.pool.get_at(engine,
+        assets.pool.get_at(
             mesh,
             if locked { &mat.locked_mat } else { &mat.unlocked_mat },
             transform
@@ -189,6 +191,14 @@ impl std::fmt::Debug for GridCell {
     }
 }
 
+impl Trace for GridCell {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let GridCell::DeviceRoot(dev) = self {
+            dev.trace(tracer);
+        }
+    }
+}
+
 impl Default for GridCell {
     fn default() -> Self {
         GridCell::Void
@@ -252,12 +262,23 @@ pub struct Level {
 
     pub nr_goals: usize,
     pub nr_goals_fulfilled: usize,
+
+    /// Where setup_camera() last sent the camera (target, zoom).
+    camera_goal: Cell<Option<(cgmath::Point3<f32>, f32)>>,
+}
+
+impl Trace for Level {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.floor_meshes.trace(tracer);
+        for cell in self.grid.iter().chain(self.floor_grid.iter()) {
+            cell.trace(tracer);
+        }
+    }
 }
 
 impl Level {
-    pub fn spawn_static_tile(&mut self, engine: &Engine, assets: &Assets, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>, x: i32, y: i32) {
+    pub fn spawn_static_tile(&mut self, assets: &Assets, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>, x: i32, y: i32) {
         let instance = assets.pool_static.get_at(
-            engine,
             mesh,
             mat,
             Matrix4::from_translation(vec3(x as f32, 0.0, y as f32))
@@ -266,7 +287,7 @@ impl Level {
         self.floor_meshes.push(instance);
     }
 
-    pub fn new_from_map(map_path: &str, engine: &Engine, assets: &Assets) -> Level {
+    pub fn new_from_map(map_path: &str, assets: &Assets) -> Level {
 
         const WALL_TL: u32 = 0;
         const WALL_T : u32 = 1;
@@ -308,6 +329,7 @@ impl Level {
             bounds: (0, 0, 0, 0),
             nr_goals: 0,
             nr_goals_fulfilled: 0,
+            camera_goal: Cell::new(None),
         };
 
         let floors = map.get_layer(0).unwrap().as_tile_layer().unwrap();
@@ -361,7 +383,7 @@ impl Level {
                     };
 
                     if let Some((mesh, mat)) = mesh_mat {
-                        level.spawn_static_tile(engine, assets, mesh, mat, x as i32, y as i32);
+                        level.spawn_static_tile(assets, mesh, mat, x as i32, y as i32);
                     }
 
                     // Objects are placeable on the floor
@@ -424,6 +446,8 @@ impl Level {
     }
 
     pub fn setup_camera(&self, engine: &mut Engine) {
+        const CAMERA_MOVE_TIME: f32 = 0.4;
+
         let x_pos = (self.bounds.0 + self.bounds.2 + 1) as f32 / 2.0;
         let y_pos = (self.bounds.1 + self.bounds.3 + 1) as f32 / 2.0;
 
@@ -432,12 +456,33 @@ impl Level {
         // the height/width conversion factor.
         let desired_zoom_h = ((self.bounds.2 - self.bounds.0) + 2) as f32 * viewport.height as f32 / viewport.width as f32;
         let desired_zoom_w = (self.bounds.3 - self.bounds.1) as f32 + 2.0 * 0.66;
+        let zoom = if desired_zoom_h > desired_zoom_w { desired_zoom_h } else { desired_zoom_w };
 
-        engine.main_camera.position.set(point3(x_pos, 15.0, y_pos + 3.0));
-        engine.main_camera.target.set(point3(x_pos, 0.0, y_pos + 0.0));
-        engine.main_camera.projection.set(CameraProjection::Orthographic {
-            zoom: if desired_zoom_h > desired_zoom_w { desired_zoom_h } else { desired_zoom_w },
-        });
+        let target = point3(x_pos, 0.0, y_pos + 0.0);
+
+        // Only glide over when the goal changes, e.g. for a new level or when
+        // the window is resized.
+        if self.camera_goal.get() == Some((target, zoom)) {
+            return;
+        }
+        self.camera_goal.set(Some((target, zoom)));
+
+        let camera = engine.main_camera.clone();
+        engine.tweens.add(Tween::new(&camera, |c| &c.position, point3(x_pos, 15.0, y_pos + 3.0), CAMERA_MOVE_TIME)
+            .ease(Easing::CubicInOut));
+        engine.tweens.add(Tween::new(&camera, |c| &c.target, target, CAMERA_MOVE_TIME)
+            .ease(Easing::CubicInOut));
+        engine.tweens.add(Tween::new(&camera, |c| &c.projection, CameraProjection::Orthographic { zoom }, CAMERA_MOVE_TIME)
+            .ease(Easing::CubicInOut));
+    }
+
+    /// Stops any camera movement from setup_camera(), so that something else
+    /// can take over the camera. The next setup_camera() starts over.
+    pub fn release_camera(&self, engine: &mut Engine) {
+        self.camera_goal.set(None);
+        engine.tweens.cancel_property(&engine.main_camera.position);
+        engine.tweens.cancel_property(&engine.main_camera.target);
+        engine.tweens.cancel_property(&engine.main_camera.projection);
     }
 
     pub fn is_in_bounds_and_empty(&self, x: i32, y: i32) -> bool {
@@ -582,24 +627,31 @@ impl Level {
 
                     if let DeviceTy::Goal(value) = &device.ty {
                         //log::info!("pushing orb with color {:?}", value.color);
-                        engine.main_world.push_mesh(assets.goal_light(engine, mat.clone(),
-                            value.color))
+                        engine.main_world.push_mesh(assets.goal_light(mat.clone(),
+                            value.color));
+                        engine.main_world.add_light(assets.goal_light_source(
+                            point3(x as f32, 0.5, y as f32), value.color));
                     }
 
-                    engine.main_world.push_mesh(device.ty.mk_mesh_instance(engine, assets, device.locked, mat))
+                    engine.main_world.push_mesh(device.ty.mk_mesh_instance(assets, device.locked, mat))
                 },
                 _ => {}
             }
         }
 
         for laser in &self.lasers {
-            engine.main_world.push_mesh(assets.laser(engine,
+            engine.main_world.push_mesh(assets.laser(
                 // Add a horizontal offset of 0.5 so that the laser is good. 
                 Matrix4::from_translation(vec3(laser.x as f32 + 0.5, 0.0, laser.y as f32))
                 * Matrix4::from_nonuniform_scale(laser.length as f32, 1.0, 1.0),
 
                 laser.value.color
-            ))
+            ));
+
+            let length = laser.length as f32;
+            engine.main_world.add_light(assets.laser_light_source(
+                point3(laser.x as f32 + 0.5 + length * 0.5, 0.5, laser.y as f32),
+                laser.value.color, length));
         }
 
         for mesh in &self.floor_meshes {
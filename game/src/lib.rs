@@ -5,19 +5,23 @@ use std::ops::Range;
 mod level;
 
 use egui::{Align2};
-use engine::input::MouseButton;
-use engine::video::asset_import::import_mesh_set_as_gc;
+use engine::input::{actions::ActionMap, touch::TouchPhase, CursorEmulation, Stick};
+use engine::tween::{Easing, Tween};
+use engine::video::animation::Animator;
+use engine::video::asset_import::{import_mesh_set_as_gc, import_scene, Scene};
 use engine::video::hdr_tonemap::Tonemap;
-use engine::winit::event::{TouchPhase, WindowEvent};
-use engine::{game, gc};
+use engine::{game, gc, gc_trace};
+use engine::error::{EngineError, EngineResult};
+use engine::winit::window::WindowId;
 // /
 use engine::cgmath::{point3, vec2, vec3, vec4, Matrix4, SquareMatrix, Vector2, Vector3, Zero};
 use engine::cgmath;
 use engine::log;
 
 use engine::video::camera::CameraProjection;
-use engine::video::{PBRShader, RenderCtx};
-use engine::{audio::Sound, gc::{Gp, GpMaybe}, video::{asset_import::import_binary_data, mesh_render_pipeline::{Mesh, MeshInstance}, texture::Texture, PBRMaterial}, Engine};
+use engine::video::world::{Light3D, LightKind};
+use engine::video::PBRShader;
+use engine::{audio::{effects::Effect, music::Track, spatial::Emitter, synth::{Envelope, Synth, Waveform}, voice::{PlayParams, Voice}, Audio, Sound}, gc::{Gp, GpMaybe}, video::{asset_import::import_binary_data, mesh_render_pipeline::{Mesh, MeshInstance}, texture::Texture, PBRMaterial}, Engine};
 
 use level::*;
 use smallrand::SmallRng;
@@ -44,7 +48,7 @@ impl InstancePool {
         }
     }
 
-    pub fn get(&self, engine: &Engine, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>) -> Gp<MeshInstance> {
+    pub fn get(&self, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>) -> Gp<MeshInstance> {
         let key = (
             mesh.get_gc_value_ptr() as *const _ as usize,
             mat.get_gc_value_ptr() as *const _ as usize);
@@ -53,7 +57,7 @@ impl InstancePool {
             .entry(key).or_insert(Vec::new());
 
         if the_pool.is_empty() {
-            the_pool.push(Gp::new(MeshInstance::new(engine.render_ctx(),
+            the_pool.push(Gp::new(MeshInstance::new(
                 mesh.clone(),
                 mat.clone(),
                 Matrix4::identity())));
@@ -67,18 +71,16 @@ impl InstancePool {
         retval
     }
 
-    pub fn get_at(&self, engine: &Engine, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>, transform: Matrix4<f32>) -> Gp<MeshInstance> {
-        let mesh = self.get(engine, mesh, mat);
+    pub fn get_at(&self, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>, transform: Matrix4<f32>) -> Gp<MeshInstance> {
+        let mesh = self.get(mesh, mat);
         mesh.transform.set(transform);
-        mesh.update(engine.render_ctx());
         mesh
     }
 
-    pub fn get_at2(&self, engine: &Engine, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>, transform: Matrix4<f32>, modulate: cgmath::Vector4<f32>) -> Gp<MeshInstance> {
-        let mesh = self.get(engine, mesh, mat);
+    pub fn get_at2(&self, mesh: &Gp<Mesh>, mat: &Gp<PBRMaterial>, transform: Matrix4<f32>, modulate: cgmath::Vector4<f32>) -> Gp<MeshInstance> {
+        let mesh = self.get(mesh, mat);
         mesh.transform.set(transform);
         mesh.modulate.set(modulate);
-        mesh.update(engine.render_ctx());
         mesh
     }
 
@@ -97,6 +99,43 @@ impl InstancePool {
     }
 }
 
+gc_trace!(InstancePool, [pools, outstanding]);
+
+/// Same idea as InstancePool, but for the point lights that goals and lasers
+/// emit.
+struct LightPool {
+    free: RefCell<Vec<Gp<Light3D>>>,
+    outstanding: RefCell<Vec<Gp<Light3D>>>,
+}
+
+impl LightPool {
+    pub fn new() -> Self {
+        Self {
+            free: RefCell::new(Vec::new()),
+            outstanding: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn get_point(&self, position: cgmath::Point3<f32>, color: Vector3<f32>, range: f32) -> Gp<Light3D> {
+        let light = self.free.borrow_mut().pop()
+            .unwrap_or_else(|| Gp::new(Light3D::point(position, color, range)));
+
+        light.kind.set(LightKind::Point { range });
+        light.position.set(position);
+        light.color.set(color);
+
+        self.outstanding.borrow_mut().push(light.clone());
+        light
+    }
+
+    pub fn recycle(&self) {
+        let mut outstanding = self.outstanding.borrow_mut();
+        self.free.borrow_mut().append(&mut outstanding);
+    }
+}
+
+gc_trace!(LightPool, [free, outstanding]);
+
 struct Rng {
     inner: RefCell<SmallRng>,
 }
@@ -122,11 +161,12 @@ impl Rng {
 }
 
 struct Assets {
-    horse_mesh: Gp<Mesh>,
+    horse: Scene,
     horse_material: Gp<PBRMaterial>,
 
     pool: InstancePool,
     pool_static: InstancePool,
+    light_pool: LightPool,
 
     rng: Rng,
 
@@ -143,6 +183,9 @@ struct Assets {
     win: Sound,
     click: Sound,
 
+    music: Track,
+    laser_hum: Synth,
+
     node_ingot: Gp<Mesh>,
     node_mix2: Gp<Mesh>,
     node_nut: Gp<Mesh>,
@@ -204,6 +247,24 @@ struct Assets {
     wall_mat: Gp<PBRMaterial>,
 }
 
+gc_trace!(LockUnlockMat, [locked_mat, unlocked_mat]);
+
+gc_trace!(Assets, [
+    horse, horse_material,
+    pool, pool_static, light_pool,
+    node_mix, node_mix_mat, node_hook, node_hook_mat,
+    node_ingot, node_mix2, node_nut, node_bolt, node_prism, node_split, node_swap, node_collect,
+    node_ingot_mat, node_mix2_mat, node_nut_mat, node_bolt_mat, node_prism_mat, node_split_mat, node_swap_mat, node_collect_mat,
+    laser, laser_mat,
+    emitter, emitter_mat,
+    select_vert_1, select_vert_2, select_swap, select_o_o, select_o_o_o, select_v3, select_mat,
+    floor_tile, floor_tile_mat,
+    goal, goal_mat, goal_light, goal_light_mat,
+    wall_tl, wall_t, wall_tr, wall_l, wall_r, wall_bl, wall_b, wall_br,
+    wall_tl_i, wall_tr_i, wall_bl_i, wall_br_i,
+    wall_mat,
+]);
+
 enum SelectorState {
     None,
     Vert1,
@@ -242,27 +303,32 @@ struct Selector {
 
     moving: SelectorMoveState,
     
-    /// Contains the last touch start event that happened at the given point.
-    touch_started: Option<u64>,
+    /// The last known position of the touch we are moving with.
     touch_pos: Vector2<f32>,
+
+    /// The cell the selector mesh was last sent to.
+    shown_x: i32,
+    shown_y: i32,
 }
 
+gc_trace!(Selector, [mesh_vert_1, mesh_vert_2, mesh_swap, mesh_o_o, mesh_o_o_o, mesh_v3, object]);
+
 impl Selector {
-    fn mk_mesh_instance(ctx: &RenderCtx, assets: &Assets, mesh: &Gp<Mesh>) -> Gp<MeshInstance> {
-        Gp::new(MeshInstance::new(ctx,
+    fn mk_mesh_instance(assets: &Assets, mesh: &Gp<Mesh>) -> Gp<MeshInstance> {
+        Gp::new(MeshInstance::new(
             mesh.clone(),
             assets.select_mat.clone(),
             Matrix4::identity()))
     }
 
-    pub fn new(ctx: &RenderCtx, assets: &Assets) -> Self {
+    pub fn new(assets: &Assets) -> Self {
         Selector {
-            mesh_vert_1: Self::mk_mesh_instance(ctx, assets, &assets.select_vert_1),
-            mesh_vert_2: Self::mk_mesh_instance(ctx, assets, &assets.select_vert_2),
-            mesh_swap: Self::mk_mesh_instance(ctx, assets, &assets.select_swap),
-            mesh_o_o: Self::mk_mesh_instance(ctx, assets, &assets.select_o_o),
-            mesh_o_o_o: Self::mk_mesh_instance(ctx, assets, &assets.select_o_o_o),
-            mesh_v3: Self::mk_mesh_instance(ctx, assets, &assets.select_v3),
+            mesh_vert_1: Self::mk_mesh_instance(assets, &assets.select_vert_1),
+            mesh_vert_2: Self::mk_mesh_instance(assets, &assets.select_vert_2),
+            mesh_swap: Self::mk_mesh_instance(assets, &assets.select_swap),
+            mesh_o_o: Self::mk_mesh_instance(assets, &assets.select_o_o),
+            mesh_o_o_o: Self::mk_mesh_instance(assets, &assets.select_o_o_o),
+            mesh_v3: Self::mk_mesh_instance(assets, &assets.select_v3),
             state: SelectorState::None,
             object: GpMaybe::none(),
             x: 0,
@@ -275,8 +341,10 @@ impl Selector {
             offset_y: 0.0,
 
             moving: SelectorMoveState::NotMoving,
-            touch_started: None,
             touch_pos: vec2(0.0, 0.0),
+
+            shown_x: 0,
+            shown_y: 0,
         }
     }
 
@@ -293,15 +361,23 @@ impl Selector {
     }
 
     pub fn push_mesh(&mut self, engine: &mut Engine) {
+        const SNAP_TIME: f32 = 0.12;
+
         let transform = Matrix4::from_translation(vec3(self.x as f32, 0.0, self.y as f32));
-        let mesh_instance = self.get_current_mesh();
+        let Some(mesh_instance) = self.get_current_mesh().cloned() else { return; };
 
-        if let Some(mesh_instance) = mesh_instance {
+        if matches!(self.moving, SelectorMoveState::NotMoving) {
             mesh_instance.transform.set(transform);
-            mesh_instance.update(engine.render_ctx());
-
-            engine.main_world.push_mesh(mesh_instance.clone());
         }
+        else if (self.shown_x, self.shown_y) != (self.x, self.y) {
+            // While dragging, snap over to the new cell instead of jumping.
+            engine.tweens.add(Tween::new(&mesh_instance, |m| &m.transform, transform, SNAP_TIME)
+                .ease(Easing::CubicOut));
+        }
+        self.shown_x = self.x;
+        self.shown_y = self.y;
+
+        engine.main_world.push_mesh(mesh_instance);
     }
 
     pub fn do_move(&mut self, engine: &mut Engine, assets: &Assets, level: &mut Level, finish_now: bool) {
@@ -314,7 +390,10 @@ impl Selector {
         let pos = match self.moving {
             SelectorMoveState::MovingWithMouse => engine.get_cursor_position(),
             SelectorMoveState::MovingWithTouch(id) => {
-                // Updated in the engine
+                // Once the finger is gone, stay where it was last seen.
+                if let Some(touch) = engine.input.touch(id) {
+                    self.touch_pos = touch.position;
+                }
                 self.touch_pos
             },
             SelectorMoveState::NotMoving => unreachable!(),
@@ -333,24 +412,27 @@ impl Selector {
         let valid = level.move_from(self.start_x, self.start_y, &dev, self.x, self.y);
         if let Some(mesh) = self.get_current_mesh() {
             mesh.modulate.set(if valid { vec4(1.0, 1.0, 1.0, 1.0) } else { vec4(1.0, 0.0, 0.0, 1.0) });
-            mesh.update(engine.render_ctx());
         }
 
         if last_x != self.x || last_y != self.y {
             if valid {
-                engine.audio.play_speed(assets.rng.choose(&assets.metal_sfx), assets.rng.range(0.95..1.05));
+                play_at_tile(engine, assets.rng.choose(&assets.metal_sfx), self.x, self.y);
             }
             else {
-                engine.audio.play_speed(&assets.move_err, assets.rng.range(0.95..1.05));
+                play_at_tile(engine, &assets.move_err, self.x, self.y);
             }
         }
 
-        let finish_now_mouse = matches!(self.moving, SelectorMoveState::MovingWithMouse) && !engine.input.is_mouse_pressed(MouseButton::Left);
+        let finish_now_mouse = matches!(self.moving, SelectorMoveState::MovingWithMouse) && !engine.input.is_action_pressed("pick_up");
+        let finish_now_touch = match self.moving {
+            SelectorMoveState::MovingWithTouch(id) => !engine.input.touch(id).is_some_and(|touch| touch.is_down()),
+            _ => false,
+        };
 
-        if finish_now || finish_now_mouse {
+        if finish_now || finish_now_mouse || finish_now_touch {
             self.moving = SelectorMoveState::NotMoving;
             level.finish_move_from(self.start_x, self.start_y, &dev, self.x, self.y);
-            engine.audio.play_speed(&assets.metal_putdown, assets.rng.range(0.95..1.05));
+            play_at_tile(engine, &assets.metal_putdown, self.x, self.y);
         }
     }
 
@@ -414,7 +496,6 @@ impl Selector {
                     // Update the mesh to not be red
                     if let Some(mesh) = self.get_current_mesh() {
                         mesh.modulate.set(vec4(1.0, 1.0, 1.0, 1.0));
-                        mesh.update(engine.render_ctx());
                     }
                     return true;
                 }
@@ -430,20 +511,22 @@ impl Selector {
         }        
 
         self.state = SelectorState::None;
-        // Take whatever touch event we might have from the last event().
-        let touch = self.touch_started.take();
+        // A finger that went down since the last tick.
+        let touch = engine.input.touches()
+            .find(|touch| touch.phase == TouchPhase::Started)
+            .map(|touch| (touch.id, touch.position));
         self.object.set(None);
 
         if self.check_screen_pos(engine, level, engine.get_cursor_position())
-            && engine.input.is_mouse_just_pressed(MouseButton::Left) {
+            && engine.input.is_action_just_pressed("pick_up") {
             self.moving = SelectorMoveState::MovingWithMouse;
-            engine.audio.play_speed(&assets.metal_pickup, assets.rng.range(0.95..1.05));
+            play_at_tile(engine, &assets.metal_pickup, self.x, self.y);
 
         }
-        else if self.check_screen_pos(engine, level, self.touch_pos) && let Some(touch) = touch {
-            log::info!("Start moving with touch!!!");
-            self.moving = SelectorMoveState::MovingWithTouch(touch);
-            engine.audio.play_speed(&assets.metal_pickup, assets.rng.range(0.95..1.05));
+        else if let Some((id, pos)) = touch && self.check_screen_pos(engine, level, pos) {
+            self.touch_pos = pos;
+            self.moving = SelectorMoveState::MovingWithTouch(id);
+            play_at_tile(engine, &assets.metal_pickup, self.x, self.y);
         }
     }
 }
@@ -501,6 +584,23 @@ macro_rules! lock_unlock {
     }
 }
 
+/// Plays a sound from the middle of a grid cell.
+fn play_at_tile(engine: &Engine, sound: &Sound, x: i32, y: i32) {
+    engine.audio.play_at(sound, &Emitter::new(point3(x as f32 + 0.5, 0.0, y as f32 + 0.5)));
+}
+
+/// Returns the named mesh in the scene. The game pools and places its own
+/// instances, so the mesh must be made of a single primitive.
+fn scene_mesh(scene: &Scene, name: &str) -> EngineResult<Gp<Mesh>> {
+    let mesh = scene.find_mesh(name)
+        .ok_or_else(|| EngineError::MeshNotFound(name.to_string()))?;
+    match mesh.primitives.as_slice() {
+        [primitive] => Ok(primitive.mesh.clone()),
+        primitives => Err(EngineError::AssetParse(format!("mesh {} has {} primitives, expected 1",
+            name, primitives.len()))),
+    }
+}
+
 impl Assets {
     pub fn new(engine: &mut Engine) -> Self {
         let ctx = engine.render_ctx();
@@ -545,42 +645,36 @@ impl Assets {
             "wall-tl-i", "wall-tr-i", "wall-bl-i", "wall-br-i",
         ]).unwrap();
 
-        let [
-            node_ingot,
-            node_mix2,
-            node_nut,
-            node_bolt,
-            node_prism,
-            node_split,
-            node_swap,
-            node_collect,
-        ] = import_mesh_set_as_gc(engine, include_bytes!("./assets/nodes.glb"), &[
-            "ingot",
-            "mix2",
-            "nut",
-            "bolt",
-            "prism",
-            "split",
-            "swap",
-            "collect",
-        ]).unwrap();
-
-        let [
-            select_swap,
-            select_o_o,
-            select_o_o_o,
-            select_v3,
-        ] = import_mesh_set_as_gc(engine, include_bytes!("./assets/selectors.glb"), &[
-            "select_swap",
-            "select_o_o",
-            "select_o_o_o",
-            "select_v3",
-        ]).unwrap();
+        // These are authored as full scenes, but we only take the meshes out
+        // of them:
+        // - The nodes are laid out side by side in the file for the artist,
+        //   while the game puts every piece on its own tile, so the node
+        //   transforms don't apply.
+        // - Every piece swaps between a locked and an unlocked material, each
+        //   with its own label decal, which the single "Parts" material in
+        //   nodes.glb can't express.
+        // - The selectors are drawn with select.wgsl, which is purely
+        //   emissive and ignores the "highlight" material's parameters.
+        let nodes = import_scene(ctx, include_bytes!("./assets/nodes.glb")).unwrap();
+        let node_ingot = scene_mesh(&nodes, "ingot").unwrap();
+        let node_mix2 = scene_mesh(&nodes, "mix2").unwrap();
+        let node_nut = scene_mesh(&nodes, "nut").unwrap();
+        let node_bolt = scene_mesh(&nodes, "bolt").unwrap();
+        let node_prism = scene_mesh(&nodes, "prism").unwrap();
+        let node_split = scene_mesh(&nodes, "split").unwrap();
+        let node_swap = scene_mesh(&nodes, "swap").unwrap();
+        let node_collect = scene_mesh(&nodes, "collect").unwrap();
+
+        let selectors = import_scene(ctx, include_bytes!("./assets/selectors.glb")).unwrap();
+        let select_swap = scene_mesh(&selectors, "select_swap").unwrap();
+        let select_o_o = scene_mesh(&selectors, "select_o_o").unwrap();
+        let select_o_o_o = scene_mesh(&selectors, "select_o_o_o").unwrap();
+        let select_v3 = scene_mesh(&selectors, "select_v3").unwrap();
 
         let laser_shader = Gp::new(PBRShader::new(ctx, "laser.wgsl", include_str!("./shaders/laser.wgsl")));
 
         Assets {
-            horse_mesh: mesh!(ctx, "../test/horse.glb"),
+            horse: import_scene(ctx, include_bytes!("../test/horse.glb")).unwrap(),
             horse_material: Gp::new(PBRMaterial {
                 albedo: vec3(1.0, 1.0, 1.0),
                 metallic: 0.03,
@@ -592,22 +686,32 @@ impl Assets {
 
             pool: InstancePool::new(),
             pool_static: InstancePool::new(),
+            light_pool: LightPool::new(),
 
             rng: Rng::new(),
 
+            // Dragging quickly plays these every tick, so don't let them pile
+            // up.
             metal_sfx: [
-                sfx!("./assets/metal_1.flac"),
-                sfx!("./assets/metal_2.flac"),
-                sfx!("./assets/metal_3.flac"),
-                sfx!("./assets/metal_4.flac"),
-                sfx!("./assets/metal_5.flac"),
+                sfx!("./assets/metal_1.flac").with_max_voices(2).with_pitch_range(0.95..1.05),
+                sfx!("./assets/metal_2.flac").with_max_voices(2).with_pitch_range(0.95..1.05),
+                sfx!("./assets/metal_3.flac").with_max_voices(2).with_pitch_range(0.95..1.05),
+                sfx!("./assets/metal_4.flac").with_max_voices(2).with_pitch_range(0.95..1.05),
+                sfx!("./assets/metal_5.flac").with_max_voices(2).with_pitch_range(0.95..1.05),
             ],
-            metal_pickup: sfx!("./assets/metal_pickup.flac"),
-            metal_putdown: sfx!("./assets/metal_putdown.flac"),
-            move_err: sfx!("./assets/move_err.flac"),
+            metal_pickup: sfx!("./assets/metal_pickup.flac").with_pitch_range(0.95..1.05),
+            metal_putdown: sfx!("./assets/metal_putdown.flac").with_pitch_range(0.95..1.05),
+            move_err: sfx!("./assets/move_err.flac").with_pitch_range(0.95..1.05),
 
             win: sfx!("./assets/win.flac"),
-            click: sfx!("./assets/click.flac"),
+            click: sfx!("./assets/click.flac").with_pitch_range(0.95..1.05),
+
+            // Streamed, as the whole song decoded would take up a lot of memory on
+            // the web.
+            music: Track::streaming_from_data(include_bytes!("./assets/music.ogg")).unwrap().with_volume(1.6),
+            laser_hum: Synth::new(Waveform::Square, 55.0)
+                .with_envelope(Envelope { attack: 0.3, ..Default::default() })
+                .with_volume(0.04),
 
             node_mix: mesh!(ctx, "./assets/mix_node.glb"),
             node_mix_mat: lock_unlock!(ctx, lock_data, "./assets/label_mix.png"),
@@ -701,23 +805,37 @@ impl Assets {
         vec3(f32::powf(v.x, pow), f32::powf(v.y, pow), f32::powf(v.z, pow))
     }
 
-    fn laser(&self, engine: &Engine, transform: cgmath::Matrix4<f32>, color: Vector3<f32>) -> Gp<MeshInstance> {
+    fn laser(&self, transform: cgmath::Matrix4<f32>, color: Vector3<f32>) -> Gp<MeshInstance> {
         let color = Self::the_pow(color, 2.2);
 
-        self.pool.get_at2(engine,
+        let instance = self.pool.get_at2(
             &self.laser,
             &self.laser_mat,
-            transform, color.extend(1.0))
+            transform, color.extend(1.0));
+        // The beams are emissive, they shouldn't block the sun.
+        instance.casts_shadow.set(false);
+        instance
     }
 
-    fn goal_light(&self, engine: &Engine, transform: cgmath::Matrix4<f32>, color: Vector3<f32>) -> Gp<MeshInstance> {
+    fn goal_light(&self, transform: cgmath::Matrix4<f32>, color: Vector3<f32>) -> Gp<MeshInstance> {
         let color = Self::the_pow(color, 2.2);
 
-        self.pool.get_at2(engine,
+        self.pool.get_at2(
             &self.goal_light,
             &self.goal_light_mat,
             transform, color.extend(1.0))
     }
+
+    // The light actually cast by the goal orb at the given position.
+    fn goal_light_source(&self, position: cgmath::Point3<f32>, color: Vector3<f32>) -> Gp<Light3D> {
+        let color = Self::the_pow(color, 2.2);
+        self.light_pool.get_point(position, color * 0.4, 2.5)
+    }
+
+    fn laser_light_source(&self, position: cgmath::Point3<f32>, color: Vector3<f32>, length: f32) -> Gp<Light3D> {
+        let color = Self::the_pow(color, 2.2);
+        self.light_pool.get_point(position, color * 0.25, length * 0.5 + 1.0)
+    }
 }
 
 enum GameplayState {
@@ -741,8 +859,14 @@ pub struct GameplayLogic {
     state: GameplayState,
 
     the_horse: Gp<MeshInstance>,
+    horse_animator: Animator,
+
+    /// A hum for each laser, in the same order as Level::lasers.
+    laser_hums: Vec<(Voice, Emitter)>,
 }
 
+gc_trace!(GameplayLogic, [assets, level, selector, the_horse, horse_animator]);
+
 // meow
 
 static LEVELS: [&str; 7] = [
@@ -755,31 +879,101 @@ static LEVELS: [&str; 7] = [
     "intro_mix_constrained",
 ];
 
+/// More lasers than this only hum with the first few.
+const MAX_LASER_HUMS: usize = 8;
+
+/// The speed to play a laser's hum at: the hue of its color, spread over an
+/// octave.
+fn laser_pitch(color: Vector3<f32>) -> f32 {
+    let max = color.x.max(color.y).max(color.z);
+    let min = color.x.min(color.y).min(color.z);
+    let chroma = max - min;
+    let hue = if chroma <= 0.0 {
+        0.0
+    }
+    else if max == color.x {
+        ((color.y - color.z) / chroma).rem_euclid(6.0)
+    }
+    else if max == color.y {
+        (color.z - color.x) / chroma + 2.0
+    }
+    else {
+        (color.x - color.y) / chroma + 4.0
+    };
+    f32::powf(2.0, hue / 6.0)
+}
+
+/// Where the volume settings are saved. On the web, this is the localStorage
+/// key instead.
+const AUDIO_SETTINGS: &str = "audio.cfg";
+
 impl GameplayLogic {
     #[inline_tweak::tweak_fn]
     pub fn tweak_scene(&mut self, engine: &mut Engine) {
-        engine.main_world.lights[0].color.set(vec3(5.0, 5.0, 5.0));
+        engine.main_world.sun.color.set(vec3(5.0, 5.0, 5.0));
     }
 
     fn open_level(&mut self, engine: &mut Engine, idx: usize) {
         self.assets.pool_static.recycle();
         if let Some(name) = LEVELS.get(idx) {
-            self.level = Level::new_from_map(&format!("./levels/{}.tmx", name), engine, &self.assets); 
+            self.level = Level::new_from_map(&format!("./levels/{}.tmx", name), &self.assets); 
             self.state = GameplayState::Level;
             self.cur_level_idx = idx;
 
             // Reset selector
-            self.selector.touch_started = None;
             self.selector.moving = SelectorMoveState::NotMoving;
             self.selector.state = SelectorState::None;
+            self.selector.object.set(None);
+
+            // Free the devices and such from the previous level.
+            engine.collect_garbage(&*self);
         }
         else {
             self.state = GameplayState::LevelSelect;
         }
     }
 
+    /// Keeps a hum going for every laser, pitched by its color and panned to
+    /// its middle.
+    fn update_laser_hums(&mut self, engine: &mut Engine) {
+        let lasers = match self.state {
+            GameplayState::Level => &self.level.lasers[..self.level.lasers.len().min(MAX_LASER_HUMS)],
+            _ => &[],
+        };
+
+        while self.laser_hums.len() > lasers.len() {
+            let (voice, _) = self.laser_hums.pop().unwrap();
+            voice.fade_out(0.3);
+        }
+
+        for (idx, laser) in lasers.iter().enumerate() {
+            let position = point3(laser.x as f32 + 0.5 + laser.length as f32 * 0.5, 0.0, laser.y as f32);
+            // Also restart hums that never got to play, e.g. because the
+            // audio was waiting for a gesture.
+            if self.laser_hums.get(idx).is_none_or(|(voice, _)| voice.is_finished()) {
+                let emitter = Emitter::new(position);
+                let voice = engine.audio.play_synth(&self.assets.laser_hum, PlayParams {
+                    fade_in: 0.3,
+                    emitter: Some(&emitter),
+                    effects: &[Effect::LowPass(400.0)],
+                    ..Default::default()
+                });
+                if idx < self.laser_hums.len() {
+                    self.laser_hums[idx] = (voice, emitter);
+                }
+                else {
+                    self.laser_hums.push((voice, emitter));
+                }
+            }
+
+            let (voice, emitter) = &self.laser_hums[idx];
+            emitter.set_position(position);
+            voice.set_speed(laser_pitch(laser.value.color));
+        }
+    }
+
     fn click(&mut self, engine: &mut Engine) {
-        engine.audio.play_speed(&self.assets.click, self.assets.rng.range(0.95..1.05));
+        engine.audio.play_on(Audio::UI, &self.assets.click);
     }
 }
 
@@ -787,15 +981,26 @@ impl GameplayLogic {
 impl engine::Gameplay for GameplayLogic {
     const GAME_TITLE: &'static str = "ben's beams";
     const DEFAULT_TONEMAP: engine::video::hdr_tonemap::Tonemap = Tonemap::None;
+    // Every goal and laser gets a light, so leave a good amount of room.
+    const MAX_LIGHTS: usize = 32;
 
     fn new(engine: &mut Engine) -> Self {
+       engine.input.actions = ActionMap::from_config(include_str!("./controls.cfg")).unwrap();
+       // The Selector works off the cursor, so let a gamepad drive it.
+       engine.input.cursor_emulation = Some(CursorEmulation { stick: Stick::Left, speed: 900.0 });
+
        let assets = Assets::new(engine);
        let ctx = engine.render_ctx();
 
-       let the_horse = Gp::new(MeshInstance::new(ctx,
-            assets.horse_mesh.clone(),
+       let mut horse_animator = Animator::new(ctx, &assets.horse);
+       horse_animator.play("idle");
+
+       let horse_node = assets.horse.find_node("A_Horse").unwrap();
+       let the_horse = Gp::new(MeshInstance::new(
+            scene_mesh(&assets.horse, "Mesh").unwrap(),
             assets.horse_material.clone(),
             Matrix4::identity()));
+       the_horse.skin.set(horse_animator.skin_for_node(horse_node).as_ref());
 
         // let transform0 = cgmath::Matrix4::from_translation(vec3(-0.5, 0.0, 0.0));
         // let transform1 = cgmath::Matrix4::from_translation(vec3( 0.5, 0.0, 0.0));
@@ -811,18 +1016,29 @@ impl engine::Gameplay for GameplayLogic {
         engine.main_camera.projection.set(CameraProjection::Orthographic {
             zoom: 10.0,
         });
+        // The camera looks down from high up, so only pan the sounds, rather
+        // than making them quieter.
+        engine.audio.attenuation.rolloff = 0.0;
 
-        let level = Level::new_from_map("./levels/hook_something.tmx", engine, &assets);
+        let level = Level::new_from_map("./levels/hook_something.tmx", &assets);
        // for i in 0..5 {
         //level.try_place(2, 2, DeviceTy::Mix);
         
         //}//
 
-        let selector = Selector::new(ctx, &assets);
-
-        level.setup_camera(engine);
+        let selector = Selector::new(&assets);
 
-        engine.audio.play_music(include_bytes!("./assets/music.ogg"), 1.6);
+        // The player's volume settings, if they changed any.
+        #[cfg(not(target_arch = "wasm32"))]
+        if std::path::Path::new(AUDIO_SETTINGS).exists()
+            && let Err(err) = engine.audio.load_settings_file(AUDIO_SETTINGS) {
+            log::warn!("failed to load {}: {}", AUDIO_SETTINGS, err);
+        }
+        #[cfg(target_arch = "wasm32")]
+        if let Err(err) = engine.audio.load_settings_local_storage(AUDIO_SETTINGS) {
+            log::warn!("failed to load {}: {}", AUDIO_SETTINGS, err);
+        }
+        engine.audio.play_music(&assets.music);
 
         GameplayLogic {
             assets,
@@ -833,7 +1049,10 @@ impl engine::Gameplay for GameplayLogic {
             has_won: false,
             state: GameplayState::MainMenu,
 
-            the_horse
+            the_horse,
+            horse_animator,
+
+            laser_hums: Vec::new(),
         }
     }
 
@@ -844,7 +1063,6 @@ impl engine::Gameplay for GameplayLogic {
         self.tweak_scene(engine);
 
         self.level.build_lasers();
-        self.level.setup_camera(engine);
 
         self.selector.update(engine, &self.assets, &mut self.level);
 
@@ -858,20 +1076,46 @@ impl engine::Gameplay for GameplayLogic {
             engine.audio.play(&self.assets.win);
         }
 
+        // Record the input for bug reports. A recording started in a level
+        // opens that level again when it is replayed, see replay_start().
+        #[cfg(not(target_arch = "wasm32"))]
+        if engine.input.is_action_just_pressed("record") {
+            if let Some(log) = engine.input.stop_recording() {
+                match log.save_file("replay.log") {
+                    Ok(()) => log::info!("saved {} ticks of input to replay.log", log.ticks()),
+                    Err(err) => log::error!("failed to save replay: {}", err),
+                }
+            }
+            else {
+                let start = matches!(self.state, GameplayState::Level)
+                    .then(|| format!("level {}", self.cur_level_idx));
+                engine.input.start_recording(start.as_deref());
+            }
+        }
+
+        if matches!(self.state, GameplayState::Level) && engine.input.is_action_just_pressed("menu") {
+            self.state = GameplayState::LevelSelect;
+        }
+        self.update_laser_hums(engine);
+
         engine.main_world.clear_meshes();
+        engine.main_world.clear_lights();
         self.assets.pool.recycle();
+        self.assets.light_pool.recycle();
         match self.state {
             GameplayState::Level => {
+                self.level.setup_camera(engine);
                 self.level.build_meshes(engine, &self.assets);
                 self.selector.push_mesh(engine);
             },
             _ => {
+                self.level.release_camera(engine);
                 engine.main_camera.position.set(point3(0.0, 2.0, -2.0));
                 engine.main_camera.target.set(point3(0.0, 0.0, 0.0));
                 engine.main_camera.projection.set(CameraProjection::Perspective { fovy: 45.0, znear: 0.01, zfar: 20.0 });
                 //aengine.main_camera
                 self.the_horse.transform.set(Matrix4::from_angle_y(cgmath::Rad(self.theta)));
-                self.the_horse.update(engine.render_ctx());
+                self.horse_animator.tick(engine.render_ctx());
 
                 engine.main_world.push_mesh(self.the_horse.clone());
             }
@@ -884,33 +1128,18 @@ impl engine::Gameplay for GameplayLogic {
         //game.main_camera.position.set(point3(15.0 * f32::cos(self.theta), 15.0 * f32::sin(self.theta), 0.0));
     }
 
-    fn event(&mut self, engine: &mut Engine, event: &WindowEvent) {
-        match event {
-            WindowEvent::Touch(touch) => {
-                log::info!("touch event: {:?}", touch);
-                // If we are already moving with touch, handle those updates.
-                if let SelectorMoveState::MovingWithTouch(id) = self.selector.moving {
-                    if touch.id == id {
-                        self.selector.touch_pos = vec2(touch.location.x as f32, touch.location.y as f32);
-                        if touch.phase == TouchPhase::Cancelled || touch.phase == TouchPhase::Ended {
-                            // Finish the touch right now.
-                            self.selector.do_move(engine, &self.assets, &mut self.level, true);
-                        }
-                    }
-                }
-                else if let SelectorMoveState::NotMoving = self.selector.moving {
-                    if touch.phase == TouchPhase::Started {
-                        // Let the selector start moving from this touch.
-                        self.selector.touch_started = Some(touch.id);
-                        self.selector.touch_pos = vec2(touch.location.x as f32, touch.location.y as f32);
-                    }
-                }
-            },
-            _ => {}
+    fn replay_start(&mut self, engine: &mut Engine, start: &str) {
+        match start.strip_prefix("level ").and_then(|idx| idx.parse().ok()) {
+            Some(idx) => self.open_level(engine, idx),
+            None => log::warn!("replay starts from unknown state '{}'", start),
         }
     }
 
-    fn ui(&mut self, engine: &mut Engine, ctx: &egui::Context) {
+    fn ui(&mut self, engine: &mut Engine, window: WindowId, ctx: &egui::Context) {
+        if window != engine.main_window_id() {
+            return;
+        }
+
         // We have to set this on the engine's Window object
         // ctx.set_zoom_factor(4.0);
 
@@ -989,12 +1218,31 @@ impl engine::Gameplay for GameplayLogic {
                         if ui.button("play!").clicked() {
                             self.state = GameplayState::LevelSelect;
                         }
+
+                        if let Some(music) = engine.audio.bus_mut(Audio::MUSIC) {
+                            let mut volume = music.volume();
+                            let response = ui.add(egui::Slider::new(&mut volume, 0.0..=1.0).text("music"));
+                            music.set_volume(volume);
+
+                            // Save once the player lets go of the slider.
+                            let finished = response.drag_stopped() || (response.changed() && !response.dragged());
+                            #[cfg(not(target_arch = "wasm32"))]
+                            if finished && let Err(err) = engine.audio.save_settings_file(AUDIO_SETTINGS) {
+                                log::warn!("failed to save {}: {}", AUDIO_SETTINGS, err);
+                            }
+                            #[cfg(target_arch = "wasm32")]
+                            if finished && let Err(err) = engine.audio.save_settings_local_storage(AUDIO_SETTINGS) {
+                                log::warn!("failed to save {}: {}", AUDIO_SETTINGS, err);
+                            }
+                        }
                     });
                 });
             }
         }
         
-        engine.get_main_window_mut().egui_scale_factor = desired_scale;
+        if let Some(window) = engine.get_main_window_mut() {
+            window.egui_scale_factor = desired_scale;
+        }
         ctx.set_zoom_factor(desired_scale as f32);
     }
 }
@@ -7,7 +7,7 @@ fn pbr_fn(in: VertexOutput) -> PBROut {
     t = t * t;
     t = 1.0 - t;
 
-    let laser_color = model.modulate.rgb; //vec3f(1.0, 0.0, 0.0);
+    let laser_color = in.modulate.rgb; //vec3f(1.0, 0.0, 0.0);
     let w = vec3f(1.0, 1.0, 1.0);
 
     let a = mix(laser_color, w, clamp(t, 0.0, 0.5) * 2.0);
@@ -1,7 +1,7 @@
 fn pbr_fn(in: VertexOutput) -> PBROut {
     var out: PBROut = pbr_basic(in);
 
-    out.emission = vec3f(1.0) * model.modulate.xyz;
+    out.emission = vec3f(1.0) * in.modulate.xyz;
     out.albedo = out.emission;
 
     return out;